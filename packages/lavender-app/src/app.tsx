@@ -2,7 +2,21 @@ import ReactDOM from "react-dom";
 
 import { Overlay } from "./overlay";
 
-export type Emulator = typeof import("@lavender/core");
+export type Emulator = import("@lavender/core").Lavender;
+
+// Each key sets the matching bit of KEYINPUT, from A at bit 0 up to L at bit 9
+const BUTTONS: { [code: string]: number } = {
+	KeyX: 1 << 0,
+	KeyZ: 1 << 1,
+	Backspace: 1 << 2,
+	Enter: 1 << 3,
+	ArrowRight: 1 << 4,
+	ArrowLeft: 1 << 5,
+	ArrowUp: 1 << 6,
+	ArrowDown: 1 << 7,
+	KeyS: 1 << 8,
+	KeyA: 1 << 9,
+};
 
 type Memory = {
 	io: Uint8Array;
@@ -20,6 +34,7 @@ export class Controller {
 	context: CanvasRenderingContext2D;
 	frame: number;
 	shouldEmulate: boolean;
+	buttons: number;
 
 	showOverlay: boolean;
 	emulationTime: number;
@@ -55,6 +70,7 @@ export class Controller {
 		this.context = this.canvas.getContext("2d")!;
 		this.frame = 0;
 		this.shouldEmulate = false;
+		this.buttons = 0;
 
 		// Hide the overlay by default in production, show it by default in dev
 		this.showOverlay = webpack_mode !== "production";
@@ -102,13 +118,20 @@ export class Controller {
 			} else if (event.code === "Backquote") {
 				this.showOverlay = !this.showOverlay;
 				this.updateOverlay();
+			} else if (event.code in BUTTONS) {
+				this.buttons |= BUTTONS[event.code];
+				this.emulator.set_buttons(this.buttons);
+			}
+		});
+
+		window.addEventListener("keyup", (event) => {
+			if (event.code in BUTTONS) {
+				this.buttons &= ~BUTTONS[event.code];
+				this.emulator.set_buttons(this.buttons);
 			}
 		});
 
-		// Render the frame once, and then pause until manually resumed. The wrap is
-		// necessary so that the call is put on the event loop rather than executing
-		// immediately. If it executes immediately it will attempt to call step_frame
-		// while the mutex is still locked from enable_drawing.
+		// Render the frame once, and then pause until manually resumed.
 		requestAnimationFrame(() => this.render());
 
 		return this;
@@ -199,24 +222,40 @@ export class Controller {
 }
 
 // Promise.all doesn't really maintain typing properly
-type CoreImports = [Emulator, { memory: WebAssembly.Memory }];
+type CoreImports = [
+	typeof import("@lavender/core"),
+	{ memory: WebAssembly.Memory },
+];
 
 // We have to import both of these because index doesn't export memory,
 // but index_bg does. index exports the functions, but index_bg doesn't.
 // The any annotation is a sad way of avoiding type errors while also importing
 // both of these concurrently.
 const main = async () => {
-	const [emulator, { memory }]: CoreImports = await Promise.all([
+	const [core, { memory }]: CoreImports = await Promise.all([
 		import("@lavender/core"),
 		// @ts-ignore
 		import("@lavender/core/target/index_bg.wasm"),
 	]);
 
+	const emulator = new core.Lavender();
+
 	// Load the rom into the emulator
 	// fetch("/game/pokemon_emerald.gba")
 	const response = await fetch("/rom_tests/bin/first.gba");
 	const buffer = await response.arrayBuffer();
-	emulator.init_emulation(new Uint8Array(buffer));
+	try {
+		emulator.init_emulation(new Uint8Array(buffer));
+	} catch (error) {
+		console.error("Unable to load the ROM:", error);
+		return;
+	}
+
+	const cartridge = emulator.get_cartridge_info();
+	if (cartridge) {
+		console.log(`Loaded ${cartridge.title} (${cartridge.game_code})`);
+		cartridge.free();
+	}
 
 	// Create a controller to interact with the emulation
 	new Controller(emulator, memory).enableDrawing();
@@ -1,21 +1,55 @@
+use super::cycles;
 use crate::emulator::{cpu::*, Emulator};
 use instructions::*;
+use lazy_static::lazy_static;
 use std::convert::TryFrom;
 
+pub type Handler = fn(&mut Emulator, u32) -> u32;
+
+lazy_static! {
+    /// The implementation for every combination of the decode bits, so that
+    /// instructions don't have to go through `decode_instruction` every time
+    /// they run.
+    pub static ref DECODE_TABLE: Vec<Handler> = (0..DECODE_TABLE_SIZE)
+        .map(|key| decode_instruction(instruction_for_key(key)))
+        .collect();
+}
+
+/// There are 12 decode bits, so 4096 entries.
+pub const DECODE_TABLE_SIZE: usize = 1 << 12;
+
+/// Packs the decode bits, [27:20] and [7:4], into an index into
+/// `DECODE_TABLE`.
+pub fn decode_key(instruction: u32) -> usize {
+    (instruction >> 16 & 0xff0 | instruction >> 4 & 0xf) as usize
+}
+
+/// An instruction with the given decode bits, and every other bit cleared.
+pub fn instruction_for_key(key: usize) -> u32 {
+    let key = key as u32;
+    (key & 0xff0) << 16 | (key & 0xf) << 4
+}
+
 /// Decodes and runs the instruction using the given emulator, and returns the
 /// number of cycles used.
 pub fn process_instruction(emulator: &mut Emulator, instruction: u32) -> u32 {
-    // Check if the condition is met before executing the instruction.
+    run(emulator, instruction, DECODE_TABLE[decode_key(instruction)])
+}
+
+/// Runs an instruction that has already been decoded, if its condition is
+/// met.
+pub fn run(emulator: &mut Emulator, instruction: u32, handler: Handler) -> u32 {
     let condition = ConditionCodes::try_from(instruction >> 28 & 15).unwrap();
     if !emulator.cpu.check_condition(condition) {
-        return 1;
+        return cycles::SKIPPED;
     }
 
-    decode_instruction(instruction)(emulator, instruction)
+    handler(emulator, instruction)
 }
 
-/// Decodes the instruction and returns the appropriate implementation.
-pub fn decode_instruction(instruction: u32) -> fn(&mut Emulator, u32) -> u32 {
+/// Decodes the instruction and returns the appropriate implementation. This
+/// only looks at the decode bits, which is what makes `DECODE_TABLE` possible.
+pub fn decode_instruction(instruction: u32) -> Handler {
     // [27:20] and [7:4] are the CPU's decode bits
     // The first onces we want to look at are the three bits [27:25]
     let category = instruction >> 25 & 7;
@@ -129,42 +163,51 @@ pub fn decode_instruction(instruction: u32) -> fn(&mut Emulator, u32) -> u32 {
     }
 }
 
-mod internal {
-    use crate::emulator::{armv4t::utils::*, cpu::RegisterNames::*, cpu::*, Emulator};
+pub(crate) mod internal {
+    use crate::emulator::{
+        armv4t::utils::*, cpu::RegisterNames::*, cpu::*, timing::Width, Emulator,
+    };
     use std::convert::TryFrom;
 
     // Internal functions for reading and writing from/to memory "securely". There is nothing
     // secure about these because there is no permission checking for memory accesses on the GBA.
     // It's useful to have separate functions so that it's easier to add debug_assert's or extend
-    // it them for a system with a memory protection unit.
-    pub fn read_byte(emulator: &Emulator, address: u32) -> u8 {
+    // it them for a system with a memory protection unit. They also keep track of how long each
+    // access takes.
+    pub fn read_byte(emulator: &mut Emulator, address: u32) -> u8 {
+        emulator.memory.timing.data_access(address, Width::Byte);
         emulator.memory.read_byte(address)
     }
 
-    pub fn read_half_word(emulator: &Emulator, address: u32) -> u16 {
+    pub fn read_half_word(emulator: &mut Emulator, address: u32) -> u16 {
+        emulator.memory.timing.data_access(address, Width::HalfWord);
         emulator.memory.read_half_word(address)
     }
 
-    pub fn read_word(emulator: &Emulator, address: u32) -> u32 {
+    pub fn read_word(emulator: &mut Emulator, address: u32) -> u32 {
+        emulator.memory.timing.data_access(address, Width::Word);
         emulator.memory.read_word(address)
     }
 
     pub fn write_byte(emulator: &mut Emulator, address: u32, value: u8) {
+        emulator.memory.timing.data_access(address, Width::Byte);
         emulator.memory.write_byte(address, value);
     }
 
     pub fn write_half_word(emulator: &mut Emulator, address: u32, value: u16) {
+        emulator.memory.timing.data_access(address, Width::HalfWord);
         emulator.memory.write_half_word(address, value);
     }
 
     pub fn write_word(emulator: &mut Emulator, address: u32, value: u32) {
+        emulator.memory.timing.data_access(address, Width::Word);
         emulator.memory.write_word(address, value);
     }
 
     // Main bits of the load/store instructions, these are used in both the normal instructions and
     // in the with translation instructions.
     pub fn store_register(emulator: &mut Emulator, source_register: RegisterNames, address: u32) {
-        let source_register_value = emulator.cpu.get_register_value(source_register);
+        let source_register_value = stored_register_value(emulator, source_register);
 
         write_word(emulator, address & 0xFFFF_FFFC, source_register_value);
     }
@@ -174,11 +217,27 @@ mod internal {
         source_register: RegisterNames,
         address: u32,
     ) {
-        let source_register_value = emulator.cpu.get_register_value(source_register);
+        let source_register_value = stored_register_value(emulator, source_register);
 
         write_byte(emulator, address, (source_register_value & 0xff) as u8);
     }
 
+    /// Stores read r15 one cycle later than other instructions, so they see
+    /// the address of the instruction plus 12 instead of plus 8.
+    pub fn stored_register_value(emulator: &Emulator, register: RegisterNames) -> u32 {
+        stored_value(register, emulator.cpu.get_register_value(register))
+    }
+
+    /// Adjusts a register's value the way a store sees it, for stores that
+    /// read the register from somewhere other than the current bank.
+    pub fn stored_value(register: RegisterNames, value: u32) -> u32 {
+        if register == r15 {
+            value + 4
+        } else {
+            value
+        }
+    }
+
     pub fn load_register(
         emulator: &mut Emulator,
         destination_register: RegisterNames,
@@ -205,7 +264,7 @@ mod internal {
         destination_register: RegisterNames,
         address: u32,
     ) {
-        let value = emulator.memory.read_byte(address);
+        let value = read_byte(emulator, address);
         emulator
             .cpu
             .set_register_value(destination_register, value as u32);
@@ -362,7 +421,9 @@ mod internal {
 pub mod instructions {
     use crate::emulator::{
         armv4t::arm::internal::*,
+        armv4t::cycles,
         armv4t::utils::*,
+        bios,
         cpu::{RegisterNames::*, *},
         Emulator,
     };
@@ -392,8 +453,7 @@ pub mod instructions {
             },
         );
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Addition
@@ -415,8 +475,7 @@ pub mod instructions {
             },
         );
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Logical AND
@@ -438,8 +497,7 @@ pub mod instructions {
             },
         );
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Relative code branching by up 32MB in either direction.
@@ -461,8 +519,7 @@ pub mod instructions {
             .cpu
             .set_register_value(r15, pc_value.wrapping_add(shift));
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::BRANCH
     }
 
     /// Bit clear - Equivalent to `a AND (NOT b)`
@@ -484,8 +541,7 @@ pub mod instructions {
             },
         );
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Linked relative code branching by up 32MB in either direction. Sets r14
@@ -504,13 +560,13 @@ pub mod instructions {
             instruction & 0x7fffff
         } << 2;
 
-        emulator.cpu.set_register_value(r14, pc_value);
+        // r15 is 8 bytes ahead, so the instruction after this one is 4 back
+        emulator.cpu.set_register_value(r14, pc_value - 4);
         emulator
             .cpu
             .set_register_value(r15, pc_value.wrapping_add(shift));
 
-        // xxx: Return the actual number of cycles that the instruction should take
-        5
+        cycles::BRANCH
     }
 
     /// Branches execution relative to the current program counter by up 32MB in
@@ -569,7 +625,7 @@ pub mod instructions {
             addition_overflow(operand_register_value, shifter_operand, alu_out), // v: signed overflow occured
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Compare
@@ -594,7 +650,7 @@ pub mod instructions {
             substraction_overflow(operand_register_value, shifter_operand, alu_out), // v: signed overflow occured
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Logical XOR
@@ -625,7 +681,7 @@ pub mod instructions {
             },
         );
 
-        5
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Load coprocessor - Loads memory into a coprocessor
@@ -668,14 +724,14 @@ pub mod instructions {
                 for pos in 0..15 {
                     if register_list.is_bit_set(pos) {
                         let register = RegisterNames::try_from(pos).unwrap();
-                        let value = emulator.memory.read_word(address);
+                        let value = read_word(emulator, address);
                         emulator.cpu.set_register_value(register, value);
                         address += 4;
                     }
                 }
 
                 if register_list.is_bit_set(15) {
-                    let value = emulator.memory.read_word(address);
+                    let value = read_word(emulator, address);
                     emulator.cpu.set_register_value(r15, value & 0xFFFF_FFFC);
                     address += 4;
                 }
@@ -706,7 +762,7 @@ pub mod instructions {
                 for pos in 0..15 {
                     if register_list.is_bit_set(pos) {
                         let register = RegisterNames::try_from(pos).unwrap();
-                        let value = emulator.memory.read_word(address);
+                        let value = read_word(emulator, address);
                         emulator.cpu.set_register_value_in_operation_mode(
                             register,
                             value,
@@ -748,7 +804,7 @@ pub mod instructions {
                 for pos in 0..15 {
                     if register_list.is_bit_set(pos) {
                         let register = RegisterNames::try_from(pos).unwrap();
-                        let value = emulator.memory.read_word(address);
+                        let value = read_word(emulator, address);
                         emulator.cpu.set_register_value(register, value);
                         address += 4;
                     }
@@ -761,7 +817,7 @@ pub mod instructions {
                     panic!("LDM(3): UNPREDICTABLE");
                 }
 
-                let value = emulator.memory.read_word(address);
+                let value = read_word(emulator, address);
                 emulator.cpu.set_register_value(r15, value);
                 address += 4;
 
@@ -794,7 +850,7 @@ pub mod instructions {
 
         load_store_instruction_wrapper(emulator, instruction, load_register);
 
-        5
+        cycles::load_store(true, false)
     }
 
     /// Load register byte
@@ -807,7 +863,7 @@ pub mod instructions {
 
         load_store_instruction_wrapper(emulator, instruction, load_register_byte);
 
-        1
+        cycles::load_store(true, true)
     }
 
     /// Load register byte with translation
@@ -938,12 +994,12 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result.is_bit_set(31),
                 result == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             )
         }
 
-        1
+        2 + multiply_cycles(multiplicand, true)
     }
 
     /// Move
@@ -977,7 +1033,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Move to ARM Register from Coprocessor
@@ -1143,12 +1199,12 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result.is_bit_set(31),
                 result == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             );
         }
 
-        1
+        1 + multiply_cycles(second_operand_value, true)
     }
 
     /// Move Not (generates a logical ones complement of a value)
@@ -1182,7 +1238,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Logical OR (also referred to as the orr instruction)
@@ -1218,7 +1274,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Reverse substract
@@ -1254,7 +1310,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Reverse Substract with Carry
@@ -1296,7 +1352,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Substract with Carry
@@ -1338,7 +1394,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Signed Multiply Accumulate Long
@@ -1397,12 +1453,12 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result_high.is_bit_set(31),
                 result_low == 0 && result_high == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             )
         }
 
-        1
+        3 + multiply_cycles(multiplicand as u32, true)
     }
 
     /// Signed Multiply Long
@@ -1450,12 +1506,12 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result_high.is_bit_set(31),
                 result_low == 0 && result_high == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             )
         }
 
-        1
+        2 + multiply_cycles(multiplicand as u32, true)
     }
 
     /// Store Coprocessor
@@ -1497,16 +1553,9 @@ pub mod instructions {
                 for pos in 0..16 {
                     if register_list.is_bit_set(pos) {
                         let register = RegisterNames::try_from(pos).unwrap();
-                        let value = emulator.cpu.get_register_value(register);
-
-                        // WARN: See `Reading the program counter A2-9`
-                        // TODO: the above also needs to be considered in STR implementation
-                        // When STR or STM store R15 the the address that it contains is
-                        // incremented by either 0x8 or 0xC. GBA seems to always increment it by 12
-                        // (search for `PC+12` in GBATEK docs), at least for load/store instructions.
-                        let value = if register == r15 { value + 12 } else { value };
+                        let value = stored_register_value(emulator, register);
 
-                        emulator.memory.write_word(address, value);
+                        write_word(emulator, address, value);
                         address += 4;
                     }
                 }
@@ -1542,13 +1591,15 @@ pub mod instructions {
                 for pos in 0..16 {
                     if register_list.is_bit_set(pos) {
                         let register = RegisterNames::try_from(pos).unwrap();
-                        let value = emulator
-                            .cpu
-                            .get_register_value_in_operation_mode(register, OperationModes::USR);
-
-                        let value = if register == r15 { value + 12 } else { value };
+                        let value = stored_value(
+                            register,
+                            emulator.cpu.get_register_value_in_operation_mode(
+                                register,
+                                OperationModes::USR,
+                            ),
+                        );
 
-                        emulator.memory.write_word(address, value);
+                        write_word(emulator, address, value);
                         address += 4;
                     }
                 }
@@ -1583,7 +1634,7 @@ pub mod instructions {
 
         load_store_instruction_wrapper(emulator, instruction, store_register);
 
-        1
+        cycles::load_store(false, false)
     }
 
     /// Store register byte
@@ -1596,7 +1647,7 @@ pub mod instructions {
 
         load_store_instruction_wrapper(emulator, instruction, store_register_byte);
 
-        1
+        cycles::load_store(false, true)
     }
 
     /// Store register byte with translation
@@ -1674,12 +1725,19 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Triggers an interupt vector from software. Usually used to make system
     /// calls into the BIOS.
-    pub fn swi(emulator: &mut Emulator, _instruction: u32) -> u32 {
+    pub fn swi(emulator: &mut Emulator, instruction: u32) -> u32 {
+        // The BIOS reads the function number from bits 16-23 of the comment
+        if emulator.bios.hle {
+            if let Some(cycles) = bios::call(emulator, (instruction >> 16) as u8) {
+                return cycles;
+            }
+        }
+
         /*
         if ConditionPassed(cond) then
             R14_svc   = address of next instruction after the SWI instruction
@@ -1703,7 +1761,7 @@ pub mod instructions {
 
         // Enter supervisor mode
         emulator.cpu.set_operation_mode(OperationModes::SVC);
-        let next_instruction_address = emulator.cpu.get_register_value(r15) + 4;
+        let next_instruction_address = emulator.cpu.get_register_value(r15) - 4;
 
         // Store next instruction address and CPSR
         emulator
@@ -1822,7 +1880,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Test
@@ -1845,7 +1903,7 @@ pub mod instructions {
             },
         );
 
-        1
+        cycles::data_processing(instruction >> 21 & 0xf)
     }
 
     /// Unsigned Multiply Accumulate Long
@@ -1901,12 +1959,12 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result_high.is_bit_set(31),
                 result_low == 0 && result_high == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             )
         }
 
-        1
+        3 + multiply_cycles(multiplicand, false)
     }
 
     /// Unsigned Multiply Long
@@ -1952,11 +2010,11 @@ pub mod instructions {
             emulator.cpu.set_nzcv(
                 result_high.is_bit_set(31),
                 result_low == 0 && result_high == 0,
-                emulator.cpu.get_c(), // c: UNPREDICTABLE
+                false,                // c: meaningless, see `multiply_cycles`
                 emulator.cpu.get_v(), // v: unaffected
             )
         }
 
-        1
+        2 + multiply_cycles(multiplicand, false)
     }
 }
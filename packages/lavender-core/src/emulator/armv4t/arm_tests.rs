@@ -1,10 +1,39 @@
 use crate::emulator::{
-    armv4t::arm::{decode_instruction, instructions::*, process_instruction},
+    armv4t::arm::{
+        decode_instruction, decode_key, instruction_for_key, instructions::*, process_instruction,
+        DECODE_TABLE, DECODE_TABLE_SIZE,
+    },
     cpu::OperationModes,
     cpu::RegisterNames::*,
     Emulator,
 };
 
+#[test]
+fn decode_table_matches_decoder() {
+    // Everything outside of the decode bits should be ignored, so try a few
+    // different patterns for the rest of each instruction
+    let other_bits = !instruction_for_key(DECODE_TABLE_SIZE - 1);
+    let patterns = [
+        0,
+        other_bits,
+        0xaaaa_aaaa & other_bits,
+        0x5555_5555 & other_bits,
+    ];
+
+    for key in 0..DECODE_TABLE_SIZE {
+        for pattern in patterns.iter() {
+            let instruction = instruction_for_key(key) | pattern;
+            assert_eq!(decode_key(instruction), key);
+            assert_eq!(
+                DECODE_TABLE[key] as usize,
+                decode_instruction(instruction) as usize,
+                "{:#010x}",
+                instruction
+            );
+        }
+    }
+}
+
 #[test]
 fn decode_adc() {
     assert_eq!(decode_instruction(0x0_0a_000_0_0) as usize, adc as usize);
@@ -2066,7 +2095,8 @@ fn behavior_mul() {
         assert_eq!(emulator.cpu.get_register_value(r2), 0x1);
         assert_eq!(emulator.cpu.get_n(), false);
         assert_eq!(emulator.cpu.get_z(), false);
-        assert_eq!(emulator.cpu.get_c(), true);
+        // Multiplies destroy the carry flag
+        assert_eq!(emulator.cpu.get_c(), false);
         assert_eq!(emulator.cpu.get_v(), true);
     }
 
@@ -2161,6 +2191,30 @@ fn behavior_mul() {
     }
 }
 
+#[test]
+fn timing_multiply() {
+    let mut emulator = Emulator::dummy();
+
+    // muls r0,r1,r2 stops once the rest of r2 is all zeros or all ones
+    emulator.cpu.set_register_value(r2, 0x12);
+    assert_eq!(process_instruction(&mut emulator, 0xE010_0291), 2);
+    emulator.cpu.set_register_value(r2, 0xffff_1234);
+    assert_eq!(process_instruction(&mut emulator, 0xE010_0291), 3);
+    emulator.cpu.set_register_value(r2, 0x1234_5678);
+    assert_eq!(process_instruction(&mut emulator, 0xE010_0291), 5);
+
+    // mlas r0,r1,r2,r3 takes one more for the accumulate
+    emulator.cpu.set_register_value(r2, 0x12_3456);
+    assert_eq!(process_instruction(&mut emulator, 0xE030_3291), 5);
+
+    // Long multiplies take one more, and accumulating ones take two
+    emulator.cpu.set_register_value(r3, 0xffff_fff0);
+    assert_eq!(process_instruction(&mut emulator, 0xE0D1_0392), 3); // smulls r0,r1,r2,r3
+    assert_eq!(process_instruction(&mut emulator, 0xE0F1_0392), 4); // smlals r0,r1,r2,r3
+    assert_eq!(process_instruction(&mut emulator, 0xE091_0392), 6); // umulls r0,r1,r2,r3
+    assert_eq!(process_instruction(&mut emulator, 0xE0B1_0392), 7); // umlals r0,r1,r2,r3
+}
+
 #[test]
 fn decode_mvn() {
     assert_eq!(decode_instruction(0x0_1e_000_0_0) as usize, mvn as usize);
@@ -2877,7 +2931,8 @@ fn behavior_smlal() {
         assert_eq!(emulator.cpu.get_register_value(r3), 0x0);
         assert_eq!(emulator.cpu.get_n(), false);
         assert_eq!(emulator.cpu.get_z(), true);
-        assert_eq!(emulator.cpu.get_c(), true);
+        // Multiplies destroy the carry flag
+        assert_eq!(emulator.cpu.get_c(), false);
         assert_eq!(emulator.cpu.get_v(), true);
     }
 
@@ -3029,7 +3084,8 @@ fn behavior_smull() {
         assert_eq!(emulator.cpu.get_register_value(r3), 0x0);
         assert_eq!(emulator.cpu.get_n(), false);
         assert_eq!(emulator.cpu.get_z(), true);
-        assert_eq!(emulator.cpu.get_c(), true);
+        // Multiplies destroy the carry flag
+        assert_eq!(emulator.cpu.get_c(), false);
         assert_eq!(emulator.cpu.get_v(), true);
     }
 
@@ -3195,8 +3251,9 @@ fn behavior_stm_1() {
 
         assert_eq!(emulator.memory.read_word(0x0300_0000), 0x11aa_aa11);
         assert_eq!(emulator.memory.read_word(0x0300_0004), 0x22bb_bb22);
-        // WARN: See STM implementation
-        assert_eq!(emulator.memory.read_word(0x0300_0008), 0x33cc_cc0c);
+        // r15 is stored as the address of the instruction plus 12, which is
+        // 4 more than it reads as
+        assert_eq!(emulator.memory.read_word(0x0300_0008), 0x33cc_cc04);
     }
 
     // Decrement before + write back bit set
@@ -3669,7 +3726,7 @@ fn behavior_swi() {
         process_instruction(&mut emulator, instruction);
 
         assert_eq!(emulator.cpu.get_operation_mode(), Some(OperationModes::SVC));
-        assert_eq!(emulator.cpu.registers.r14_svc, 0xaabb_ddd0);
+        assert_eq!(emulator.cpu.registers.r14_svc, 0xaabb_ddc8);
         assert_eq!(emulator.cpu.registers.spsr_svc, 0xeeff_9910);
         assert_eq!(emulator.cpu.is_fiq_disabled(), true);
         assert_eq!(emulator.cpu.is_irq_disabled(), false);
@@ -3991,7 +4048,8 @@ fn behavior_umlal() {
         assert_eq!(emulator.cpu.get_register_value(r3), 0x0);
         assert_eq!(emulator.cpu.get_n(), false);
         assert_eq!(emulator.cpu.get_z(), true);
-        assert_eq!(emulator.cpu.get_c(), true);
+        // Multiplies destroy the carry flag
+        assert_eq!(emulator.cpu.get_c(), false);
         assert_eq!(emulator.cpu.get_v(), true);
     }
 
@@ -4143,7 +4201,8 @@ fn behavior_umull() {
         assert_eq!(emulator.cpu.get_register_value(r3), 0x0);
         assert_eq!(emulator.cpu.get_n(), false);
         assert_eq!(emulator.cpu.get_z(), true);
-        assert_eq!(emulator.cpu.get_c(), true);
+        // Multiplies destroy the carry flag
+        assert_eq!(emulator.cpu.get_c(), false);
         assert_eq!(emulator.cpu.get_v(), true);
     }
 
@@ -0,0 +1,33 @@
+//! How many cycles instructions take, not counting wait states. The
+//! interpreter and the recompiler both take their timings from here, so that
+//! a recompiled block always takes as long as the interpreter would.
+
+/// A data processing instruction, given the opcode in bits 21-24.
+pub fn data_processing(opcode: u32) -> u32 {
+    // xxx: These are placeholders, rather than the real number of cycles
+    match opcode {
+        0b0000 | 0b0001 | 0b0100 | 0b0101 | 0b1110 => 5,
+        _ => 1,
+    }
+}
+
+/// A single load or store of a word or a byte.
+pub fn load_store(load: bool, byte: bool) -> u32 {
+    // xxx: These are placeholders, rather than the real number of cycles
+    if load && !byte {
+        5
+    } else {
+        1
+    }
+}
+
+/// B and BL.
+// xxx: This is a placeholder, rather than the real number of cycles
+pub const BRANCH: u32 = 5;
+
+/// An instruction that was skipped because its condition failed.
+pub const SKIPPED: u32 = 1;
+
+/// Thumb data processing, single loads and stores, and branches.
+// xxx: This is a placeholder, rather than the real number of cycles
+pub const THUMB: u32 = 1;
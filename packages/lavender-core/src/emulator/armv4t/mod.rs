@@ -1,11 +1,20 @@
 /// The 32-bit ARM instructions and decoder
 pub mod arm;
+/// The number of cycles that instructions take
+pub mod cycles;
 /// The 16-bit Thumb instructions and decoder
 pub mod thumb;
 /// Some useful things for processing instructions that I felt would be better
 /// suited to another file, rather than crowding an already large file.
 pub mod utils;
 
+/// Builds the decode tables for both instruction sets, so that the first
+/// instructions don't have to wait for them.
+pub fn build_decode_tables() {
+    lazy_static::initialize(&arm::DECODE_TABLE);
+    lazy_static::initialize(&thumb::DECODE_TABLE);
+}
+
 #[cfg(test)]
 mod arm_tests;
 #[cfg(test)]
@@ -1,25 +1,48 @@
 use crate::emulator::Emulator;
 use instructions::*;
+use lazy_static::lazy_static;
+
+pub type Handler = fn(&mut Emulator, u16) -> u32;
+
+lazy_static! {
+    /// The implementation for every combination of the top 10 bits, which are
+    /// all that `decode_instruction` needs to look at.
+    pub static ref DECODE_TABLE: Vec<Handler> = (0..DECODE_TABLE_SIZE)
+        .map(|key| decode_instruction(instruction_for_key(key)))
+        .collect();
+}
+
+pub const DECODE_TABLE_SIZE: usize = 1 << 10;
+
+pub fn decode_key(instruction: u16) -> usize {
+    (instruction >> 6) as usize
+}
+
+/// An instruction with the given top 10 bits, and the rest cleared.
+pub fn instruction_for_key(key: usize) -> u16 {
+    (key << 6) as u16
+}
 
 /// Decodes and runs the instruction using the given emulator, and returns the
 /// number of cycles used.
 pub fn process_instruction(emulator: &mut Emulator, instruction: u16) -> u32 {
-    decode_instruction(instruction)(emulator, instruction)
+    DECODE_TABLE[decode_key(instruction)](emulator, instruction)
 }
 
-pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
+pub fn decode_instruction(instruction: u16) -> Handler {
     let category = instruction >> 13 & 7;
 
     match category {
         0b000 => {
             // Shift by rotate
-            let opcode = instruction >> 10 & 0x3;
+            let opcode = instruction >> 11 & 0x3;
             match opcode {
                 0b00 => lsl1,
                 0b01 => lsr1,
                 0b10 => asr1,
                 0b11 => {
-                    let opc = instruction >> 8 & 0x3;
+                    // Whether the operand is an immediate, then add or subtract
+                    let opc = instruction >> 9 & 0x3;
                     match opc {
                         0b00 => add3,
                         0b01 => sub3,
@@ -33,7 +56,7 @@ pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
         }
         0b001 => {
             // Add/subtract/compare/move immediate
-            let opcode = instruction >> 10 & 0x3;
+            let opcode = instruction >> 11 & 0x3;
             match opcode {
                 0b00 => mov1,
                 0b01 => cmp1,
@@ -140,15 +163,11 @@ pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
                     match misc_code {
                         0b00000 => add7,
                         0b00001 => sub4,
-                        0b01000..=0b11011 => {
-                            // TODO: Dangerous match
-                            let l = instruction >> 1 & 0x1 > 0;
-                            match l {
-                                false => push,
-                                true => pop,
-                            }
-                        }
-                        _ => unreachable!(),
+                        // Bit 8 is whether lr/pc is included
+                        0b01000..=0b01011 => push,
+                        0b11000..=0b11011 => pop,
+                        // Later architectures added more here
+                        _ => undefined,
                     }
                 }
                 false => {
@@ -165,9 +184,9 @@ pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
             let branch = instruction >> 12 & 1 > 0;
             let condition = instruction >> 8 & 0xf;
             match (branch, condition) {
-                (true, 0b1110) => unreachable!(), // undefined
-                (true, 0b1111) => swi,            // swi
-                (true, _) => b1,                  // B(1) conditional branch things
+                (true, 0b1110) => undefined,
+                (true, 0b1111) => swi, // swi
+                (true, _) => b1,       // B(1) conditional branch things
                 (false, _) => {
                     // load/store multiple
                     let l = instruction >> 11 & 0x1 > 0;
@@ -178,7 +197,14 @@ pub fn decode_instruction(instruction: u16) -> fn(&mut Emulator, u16) -> u32 {
                 }
             }
         }
-        0b111 => b2, // B(2) unconditional branches
+        0b111 => {
+            // B(2) unconditional branches, or one half of a BL
+            let h = instruction >> 11 & 0x3;
+            match h {
+                0b00 => b2,
+                _ => bl,
+            }
+        }
         _ => unreachable!(),
     }
 }
@@ -409,9 +435,15 @@ mod internal {
 }
 pub mod instructions {
     use super::super::arm::instructions::*;
+    use super::super::arm::internal::{load_register, read_byte, read_word, write_word};
+    use super::super::cycles;
     use crate::emulator::{
         armv4t::utils::*,
-        cpu::RegisterNames::{self, *},
+        bios,
+        cpu::{
+            Exception, OperationModes,
+            RegisterNames::{self, *},
+        },
         Emulator,
     };
     use std::convert::TryFrom;
@@ -428,6 +460,10 @@ pub mod instructions {
                     .wrapping_add(second_operand)
                     .wrapping_add(carry_amount);
 
+                emulator
+                    .cpu
+                    .set_register_value(destination_register, result);
+
                 (
                     carry_from_with_carry(first_operand, second_operand, carry_amount),
                     addition_overflow(first_operand, second_operand, result),
@@ -436,7 +472,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Addition (adds a 3-bit integer to a value of a register)
@@ -449,7 +485,7 @@ pub mod instructions {
             addition_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Add a large immediate value to the value of a register
@@ -468,7 +504,7 @@ pub mod instructions {
             addition_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Addition (adds values of two registers)
@@ -481,7 +517,7 @@ pub mod instructions {
             addition_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Adds the values of two registers, one or both of which are high registers
@@ -492,7 +528,7 @@ pub mod instructions {
             first_operand.wrapping_add(second_operand)
         });
 
-        1
+        cycles::THUMB
     }
 
     /// Adds an immediate value to the PC
@@ -546,7 +582,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Arithmetic Shift Right
@@ -556,11 +592,11 @@ pub mod instructions {
                                                       immed_5|
          -> (bool, u32) {
             if immed_5 == 0 {
-                let first_operand_positive = first_operand.is_bit_set(31);
-                if first_operand_positive {
-                    (false, 0)
-                } else {
+                let first_operand_negative = first_operand.is_bit_set(31);
+                if first_operand_negative {
                     (true, 0xFFFF_FFFF)
+                } else {
+                    (false, 0)
                 }
             } else {
                 (
@@ -570,7 +606,7 @@ pub mod instructions {
             }
         });
 
-        1
+        cycles::THUMB
     }
 
     /// Arithmetic Shift Right
@@ -612,7 +648,7 @@ pub mod instructions {
 
         let condition = ConditionCodes::try_from((instruction >> 8 & 0xf) as u32).unwrap();
 
-        if !emulator.cpu.check_condition(condition) {
+        if emulator.cpu.check_condition(condition) {
             let signed_immed_8 = (instruction & 0xff) as i32;
             let signed_immed_8 = ((signed_immed_8 << 24) >> 23) as u32;
 
@@ -623,7 +659,7 @@ pub mod instructions {
                 .set_register_value(r15, pc_value.wrapping_add(signed_immed_8));
         }
 
-        1
+        cycles::THUMB
     }
 
     /// Unconditional Branch
@@ -637,7 +673,7 @@ pub mod instructions {
             .cpu
             .set_register_value(r15, pc_value.wrapping_add(signed_immed_11));
 
-        1
+        cycles::THUMB
     }
 
     /// Bit Clear
@@ -656,7 +692,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Branch with Link
@@ -686,17 +722,19 @@ pub mod instructions {
         match h {
             0b10 => {
                 // TODO: use a helper to sign extend the offset (this is done in multiple places)
-                let offset_11 = (offset_11 << 21) >> 9;
+                let offset_11 = ((offset_11 << 21) as i32 >> 9) as u32;
                 emulator
                     .cpu
                     .set_register_value(r14, pc.wrapping_add(offset_11));
             }
             0b11 => {
                 let lr = emulator.cpu.get_register_value(r14);
-                emulator.cpu.set_register_value(r15, lr + (offset_11 << 1));
+                emulator
+                    .cpu
+                    .set_register_value(r15, lr.wrapping_add(offset_11 << 1));
 
-                // TODO: verify that this is okay
-                let address_of_next_instruction = pc.wrapping_add(2);
+                // r15 is 4 bytes ahead, so the next instruction is 2 back
+                let address_of_next_instruction = pc.wrapping_sub(2);
                 emulator
                     .cpu
                     .set_register_value(r14, address_of_next_instruction | 1);
@@ -706,7 +744,7 @@ pub mod instructions {
             }
         }
 
-        1
+        cycles::THUMB
     }
 
     /// Branch and Exchange
@@ -725,7 +763,7 @@ pub mod instructions {
             .cpu
             .set_thumb_bit(branch_target_address.is_bit_set(0));
 
-        let branch_target_address = (branch_target_address & 0xFFFF_FFFE) << 1;
+        let branch_target_address = branch_target_address & 0xFFFF_FFFE;
 
         emulator.cpu.set_register_value(r15, branch_target_address);
 
@@ -747,7 +785,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Compare (a register value with a large immediate value)
@@ -762,7 +800,7 @@ pub mod instructions {
             substraction_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Compare two register values
@@ -780,7 +818,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Compare the values of two registers (one or both can be high registers)
@@ -806,7 +844,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Exclusive OR
@@ -825,17 +863,17 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     macro_rules! load_store_format_1 {
         ($emulator:expr, $instruction:expr, $source_register_value:ident, $immed_5:ident, $destination_register:ident, $instruction_implementation:expr) => {
             let instruction = $instruction as u32;
 
-            let source_register = RegisterNames::try_from(instruction >> 3 & 0x3).unwrap();
+            let source_register = RegisterNames::try_from(instruction >> 3 & 0x7).unwrap();
             let $source_register_value = $emulator.cpu.get_register_value(source_register);
 
-            let $destination_register = RegisterNames::try_from(instruction & 0x3).unwrap();
+            let $destination_register = RegisterNames::try_from(instruction & 0x7).unwrap();
 
             let $immed_5 = (instruction >> 6) & 0x1F;
 
@@ -858,13 +896,13 @@ pub mod instructions {
             immed_5,
             destination_register,
             {
+                // Unaligned words are rotated the same as in ARM state
                 let address = source_register_value.wrapping_add(immed_5 << 2);
-                let data = emulator.memory.read_word(address);
-                emulator.cpu.set_register_value(destination_register, data);
+                load_register(emulator, destination_register, address);
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     pub fn ldr2(_emulator: &mut Emulator, _instruction: u16) -> u32 {
@@ -887,14 +925,14 @@ pub mod instructions {
             destination_register,
             {
                 let address = source_register_value.wrapping_add(immed_5);
-                let data = emulator.memory.read_byte(address);
+                let data = read_byte(emulator, address);
                 emulator
                     .cpu
                     .set_register_value(destination_register, data as u32);
             }
         );
 
-        1
+        cycles::THUMB
     }
     pub fn ldrb2(_emulator: &mut Emulator, _instruction: u16) -> u32 {
         1
@@ -928,7 +966,7 @@ pub mod instructions {
             }
         });
 
-        1
+        cycles::THUMB
     }
 
     /// Logical Shift Left
@@ -979,7 +1017,7 @@ pub mod instructions {
             }
         });
 
-        1
+        cycles::THUMB
     }
 
     /// Logical Shift Right
@@ -1027,7 +1065,7 @@ pub mod instructions {
             immed_8
         });
 
-        1
+        cycles::THUMB
     }
 
     pub fn mov2(_emulator: &mut Emulator, _instruction: u16) -> u32 {
@@ -1041,11 +1079,16 @@ pub mod instructions {
             second_operand
         });
 
-        1
+        cycles::THUMB
     }
 
     /// Multiply
     pub fn mul(emulator: &mut Emulator, instruction: u16) -> u32 {
+        // Rd is used as Rs, so it decides how long this takes
+        let multiplier = emulator
+            .cpu
+            .get_register_value(RegisterNames::try_from(instruction as u32 & 0x7).unwrap());
+
         instruction_format_5!(
             emulator,
             instruction,
@@ -1056,11 +1099,12 @@ pub mod instructions {
                     .cpu
                     .set_register_value(destination_register, result);
 
-                (emulator.cpu.get_c(), emulator.cpu.get_v(), result)
+                // See `multiply_cycles` for the carry flag
+                (false, emulator.cpu.get_v(), result)
             }
         );
 
-        1
+        1 + multiply_cycles(multiplier, true)
     }
 
     /// Move NOT
@@ -1077,7 +1121,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Negate
@@ -1100,7 +1144,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Logical OR (also referred to as the orr instruction)
@@ -1119,7 +1163,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     pub fn pop(_emulator: &mut Emulator, _instruction: u16) -> u32 {
@@ -1172,6 +1216,10 @@ pub mod instructions {
                     .wrapping_sub(second_operand)
                     .wrapping_sub(carry_amount);
 
+                emulator
+                    .cpu
+                    .set_register_value(destination_register, result);
+
                 // TODO: Maybe these shouldn't be bool's, too easy to mess up
                 (
                     not_borrow_from_with_carry(first_operand, second_operand, carry_amount),
@@ -1181,7 +1229,7 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 
     pub fn stmia(_emulator: &mut Emulator, _instruction: u16) -> u32 {
@@ -1201,11 +1249,11 @@ pub mod instructions {
             {
                 let address = source_register_value.wrapping_add(immed_5 << 2);
                 let data = emulator.cpu.get_register_value(destination_register);
-                emulator.memory.write_word(address, data);
+                write_word(emulator, address, data);
             }
         );
 
-        1
+        cycles::THUMB
     }
     pub fn str2(_emulator: &mut Emulator, _instruction: u16) -> u32 {
         1
@@ -1236,7 +1284,7 @@ pub mod instructions {
             substraction_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Substract a large immediate value from the value of a register
@@ -1255,7 +1303,7 @@ pub mod instructions {
             substraction_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Substraction (substracts values of two registers)
@@ -1268,7 +1316,7 @@ pub mod instructions {
             substraction_overflow
         );
 
-        1
+        cycles::THUMB
     }
 
     /// Decrements the SP by four rimes a 7-bit immediate
@@ -1278,8 +1326,40 @@ pub mod instructions {
         1
     }
 
-    pub fn swi(_emulator: &mut Emulator, _instruction: u16) -> u32 {
-        1
+    /// Software interrupt. Just like the ARM version, this is almost always a
+    /// call into the BIOS.
+    pub fn swi(emulator: &mut Emulator, instruction: u16) -> u32 {
+        if emulator.bios.hle {
+            if let Some(cycles) = bios::call(emulator, instruction as u8) {
+                return cycles;
+            }
+        }
+
+        let old_cpsr = emulator.cpu.get_register_value(cpsr);
+        let next_instruction_address = emulator.cpu.get_register_value(r15) - 2;
+
+        // Exceptions are always handled in ARM state
+        emulator.cpu.set_operation_mode(OperationModes::SVC);
+        emulator.cpu.set_thumb_bit(false);
+        emulator
+            .cpu
+            .set_register_value(r14, next_instruction_address);
+        emulator.cpu.set_register_value(spsr, old_cpsr);
+        emulator.cpu.set_irq_disable(true);
+        emulator.cpu.set_register_value(r15, 0x0000_0008);
+
+        3
+    }
+
+    /// Anything that isn't an instruction on the ARM7TDMI jumps to the
+    /// undefined instruction handler.
+    pub fn undefined(emulator: &mut Emulator, _instruction: u16) -> u32 {
+        let next_instruction_address = emulator.cpu.get_register_value(r15) - 2;
+        emulator
+            .cpu
+            .exception(Exception::UndefinedInstruction, next_instruction_address);
+
+        3
     }
 
     /// Test
@@ -1293,6 +1373,6 @@ pub mod instructions {
             }
         );
 
-        1
+        cycles::THUMB
     }
 }
@@ -1,4 +1,164 @@
+use crate::emulator::{
+    armv4t::thumb::{decode_instruction, decode_key, instructions::*, Handler, DECODE_TABLE},
+    cpu::RegisterNames::*,
+    Emulator,
+};
+
 #[test]
 fn omg_a_thumb_test() {
     assert!(true);
 }
+
+#[test]
+fn decode_table_matches_decoder() {
+    // There are few enough Thumb instructions to check every one of them
+    for instruction in 0..=u16::MAX {
+        assert_eq!(
+            DECODE_TABLE[decode_key(instruction)] as usize,
+            decode_instruction(instruction) as usize,
+            "{:#06x}",
+            instruction
+        );
+    }
+}
+
+#[test]
+fn decode_push_and_pop() {
+    // push {r0, lr} and pop {r0, pc}
+    assert_eq!(
+        decode_instruction(0xb501) as usize,
+        push as Handler as usize
+    );
+    assert_eq!(decode_instruction(0xbd01) as usize, pop as Handler as usize);
+    // push {r1} has bit 1 set, which used to decide between the two
+    assert_eq!(
+        decode_instruction(0xb402) as usize,
+        push as Handler as usize
+    );
+}
+
+#[test]
+fn decode_shifts_and_immediates() {
+    let decodes_to = |instruction, handler: Handler| {
+        assert_eq!(
+            decode_instruction(instruction) as usize,
+            handler as usize,
+            "{:#06x}",
+            instruction
+        );
+    };
+
+    // lsl r0, r1, #16, lsr r0, r1, #1 and asr r0, r1, #31
+    decodes_to(0x0408, lsl1);
+    decodes_to(0x0848, lsr1);
+    decodes_to(0x17c8, asr1);
+    // add r0, r1, r2, sub r0, r1, r2, add r0, r1, #3 and sub r0, r1, #3
+    decodes_to(0x1888, add3);
+    decodes_to(0x1a88, sub3);
+    decodes_to(0x1cc8, add1);
+    decodes_to(0x1ec8, sub1);
+    // mov r0, #1, cmp r0, #1, add r0, #1 and sub r0, #1
+    decodes_to(0x2001, mov1);
+    decodes_to(0x2801, cmp1);
+    decodes_to(0x3001, add2);
+    decodes_to(0x3801, sub2);
+}
+
+#[test]
+fn behavior_b1() {
+    let mut emulator = Emulator::dummy();
+    emulator.cpu.set_nzcv(false, true, false, false);
+
+    // bne only falls through when Z is set
+    emulator.cpu.set_register_value(r15, 0x0800_0104);
+    b1(&mut emulator, 0xd102);
+    assert_eq!(emulator.cpu.get_register_value(r15), 0x0800_0104);
+
+    // beq branches back by 8 from r15
+    b1(&mut emulator, 0xd0fc);
+    assert_eq!(emulator.cpu.get_register_value(r15), 0x0800_00fc);
+}
+
+#[test]
+fn behavior_ldr1_and_str1() {
+    let mut emulator = Emulator::dummy();
+    let address = 0x0300_0000;
+
+    // str r7, [r6, #8]
+    emulator.cpu.set_register_value(r6, address);
+    emulator.cpu.set_register_value(r7, 0x1234_5678);
+    str1(&mut emulator, 0x60b7);
+    assert_eq!(emulator.memory.read_word(address + 8), 0x1234_5678);
+
+    // ldr r5, [r6, #8] and ldrb r4, [r6, #9]
+    ldr1(&mut emulator, 0x68b5);
+    ldrb1(&mut emulator, 0x7a74);
+    assert_eq!(emulator.cpu.get_register_value(r5), 0x1234_5678);
+    assert_eq!(emulator.cpu.get_register_value(r4), 0x56);
+}
+
+#[test]
+fn behavior_ldr1_unaligned() {
+    let mut emulator = Emulator::dummy();
+    let address = 0x0300_0000;
+    emulator.memory.write_word(address + 4, 0x1234_5678);
+
+    // ldr r0, [r1, #4] rotates the word so that the addressed byte is at the
+    // bottom, the same as in ARM state
+    emulator.cpu.set_register_value(r1, address + 1);
+    ldr1(&mut emulator, 0x6848);
+    assert_eq!(emulator.cpu.get_register_value(r0), 0x7812_3456);
+
+    emulator.cpu.set_register_value(r1, address + 3);
+    ldr1(&mut emulator, 0x6848);
+    assert_eq!(emulator.cpu.get_register_value(r0), 0x3456_7812);
+}
+
+#[test]
+fn behavior_asr1() {
+    let mut emulator = Emulator::dummy();
+
+    // asr r0, r1, #32 fills the register with the sign bit
+    emulator.cpu.set_register_value(r1, 0x8000_0000);
+    asr1(&mut emulator, 0x1008);
+    assert_eq!(emulator.cpu.get_register_value(r0), 0xffff_ffff);
+    assert!(emulator.cpu.get_c());
+
+    emulator.cpu.set_register_value(r1, 0x7fff_ffff);
+    asr1(&mut emulator, 0x1008);
+    assert_eq!(emulator.cpu.get_register_value(r0), 0);
+    assert!(!emulator.cpu.get_c());
+    assert!(emulator.cpu.get_z());
+}
+
+#[test]
+fn behavior_bl() {
+    let mut emulator = Emulator::dummy();
+    emulator.cpu.set_register_value(r15, 0x0800_1004);
+
+    // bl 0x0800_0000, from 0x0800_1000
+    bl(&mut emulator, 0xf7fe);
+    assert_eq!(emulator.cpu.get_register_value(r14), 0x07ff_f004);
+
+    emulator.cpu.set_register_value(r15, 0x0800_1006);
+    bl(&mut emulator, 0xfffe);
+    assert_eq!(emulator.cpu.get_register_value(r15), 0x0800_0000);
+    assert_eq!(emulator.cpu.get_register_value(r14), 0x0800_1005);
+}
+
+#[test]
+fn behavior_adc_and_sbc() {
+    let mut emulator = Emulator::dummy();
+    emulator.cpu.set_nzcv(false, false, true, false);
+
+    // adc r0, r1 with the carry set
+    emulator.cpu.set_register_value(r0, 2);
+    emulator.cpu.set_register_value(r1, 3);
+    adc(&mut emulator, 0x4148);
+    assert_eq!(emulator.cpu.get_register_value(r0), 6);
+
+    // sbc r0, r1 with the carry set, so nothing is borrowed
+    emulator.cpu.set_nzcv(false, false, true, false);
+    sbc(&mut emulator, 0x4188);
+    assert_eq!(emulator.cpu.get_register_value(r0), 3);
+}
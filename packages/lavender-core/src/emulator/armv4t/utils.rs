@@ -211,8 +211,8 @@ where
                     let shifter_carry_out = value.is_bit_set(0);
                     (shifter_operand, shifter_carry_out)
                 } else {
-                    let shifter_operand = value.rotate_right(shift & 0xf);
-                    let shifter_carry_out = value.is_bit_set((shift & 0xf) - 1);
+                    let shifter_operand = value.rotate_right(shift & 0x1f);
+                    let shifter_carry_out = value.is_bit_set((shift & 0x1f) - 1);
                     (shifter_operand, shifter_carry_out)
                 }
             }
@@ -505,6 +505,26 @@ pub fn substraction_overflow(first: u32, second: u32, result: u32) -> bool {
     ((first ^ second) & (first ^ result)).is_bit_set(31)
 }
 
+/// The multiplier works through 8 bits of Rs every cycle, and stops as soon as
+/// the bits that are left are all zeros, or all ones for signed multiplies.
+/// Returns how many cycles that takes, from 1 to 4. Long and accumulating
+/// multiplies take extra cycles on top of this.
+///
+/// The C flag is left with a meaningless value by all multiplies on the
+/// ARM7TDMI, since it comes from the inside of the multiplier. Nothing can rely
+/// on it, so Lavender always clears it.
+pub fn multiply_cycles(multiplier: u32, signed: bool) -> u32 {
+    for cycles in 1..4 {
+        let remaining = multiplier >> (8 * cycles);
+        let all_ones = (multiplier as i32 >> (8 * cycles)) == -1;
+        if remaining == 0 || (signed && all_ones) {
+            return cycles;
+        }
+    }
+
+    4
+}
+
 #[inline]
 pub fn carry_from(first: u32, second: u32) -> bool {
     // TODO: document, optimise
@@ -897,6 +897,18 @@ fn test_addressing_mode_1_ror_immediate() {
         assert_eq!(shifter_operand, 0x7FFF_FFFF);
         assert_eq!(shifter_carry_out, false);
     }
+
+    {
+        let mut emulator = Emulator::dummy();
+        emulator.cpu.set_register_value(r1, 0x000F_2345);
+
+        //   cond    opc  S Rn   Rd             Rm
+        // 0b1110_0001_1011_0000_0000_1010_0110_0001 - movs r0,r1,ror 0x14
+        let (shifter_operand, shifter_carry_out) =
+            process_shifter_operand_tmp(&mut emulator, 0xE1B0_0A61);
+        assert_eq!(shifter_operand, 0xF234_5000);
+        assert!(shifter_carry_out);
+    }
 }
 
 #[test]
@@ -1036,3 +1048,17 @@ fn test_addressing_mode_1_rrx() {
         assert_eq!(shifter_carry_out, false);
     }
 }
+
+#[test]
+fn test_multiply_cycles() {
+    assert_eq!(multiply_cycles(0x0000_00ff, false), 1);
+    assert_eq!(multiply_cycles(0x0000_ff00, false), 2);
+    assert_eq!(multiply_cycles(0x00ff_0000, false), 3);
+    assert_eq!(multiply_cycles(0xff00_0000, false), 4);
+
+    // Signed multiplies can also stop early on negative numbers
+    assert_eq!(multiply_cycles(0xffff_ff80, true), 1);
+    assert_eq!(multiply_cycles(0xffff_8000, true), 2);
+    assert_eq!(multiply_cycles(0xff80_0000, true), 3);
+    assert_eq!(multiply_cycles(0xffff_ff80, false), 4);
+}
@@ -0,0 +1,220 @@
+use super::get;
+use crate::emulator::cpu::RegisterNames::*;
+use crate::emulator::Emulator;
+
+/// The BIOS's table of sines, for a full circle in 256 steps. The values are
+/// 1.1.14 fixed point, and were rounded down rather than to the nearest value,
+/// which is why some of them are one less than you might expect.
+#[rustfmt::skip]
+pub const SINE_TABLE: [i16; 256] = [
+    0x0000, 0x0192, 0x0323, 0x04b5, 0x0645, 0x07d5, 0x0964, 0x0af1,
+    0x0c7c, 0x0e05, 0x0f8c, 0x1111, 0x1294, 0x1413, 0x158f, 0x1708,
+    0x187d, 0x19ef, 0x1b5d, 0x1cc6, 0x1e2b, 0x1f8b, 0x20e7, 0x223d,
+    0x238e, 0x24da, 0x261f, 0x275f, 0x2899, 0x29cd, 0x2afa, 0x2c21,
+    0x2d41, 0x2e5a, 0x2f6b, 0x3076, 0x3179, 0x3274, 0x3367, 0x3453,
+    0x3536, 0x3612, 0x36e5, 0x37af, 0x3871, 0x392a, 0x39da, 0x3a82,
+    0x3b20, 0x3bb6, 0x3c42, 0x3cc5, 0x3d3e, 0x3dae, 0x3e14, 0x3e71,
+    0x3ec5, 0x3f0e, 0x3f4e, 0x3f84, 0x3fb1, 0x3fd3, 0x3fec, 0x3ffb,
+    0x4000, 0x3ffb, 0x3fec, 0x3fd3, 0x3fb1, 0x3f84, 0x3f4e, 0x3f0e,
+    0x3ec5, 0x3e71, 0x3e14, 0x3dae, 0x3d3e, 0x3cc5, 0x3c42, 0x3bb6,
+    0x3b20, 0x3a82, 0x39da, 0x392a, 0x3871, 0x37af, 0x36e5, 0x3612,
+    0x3536, 0x3453, 0x3367, 0x3274, 0x3179, 0x3076, 0x2f6b, 0x2e5a,
+    0x2d41, 0x2c21, 0x2afa, 0x29cd, 0x2899, 0x275f, 0x261f, 0x24da,
+    0x238e, 0x223d, 0x20e7, 0x1f8b, 0x1e2b, 0x1cc6, 0x1b5d, 0x19ef,
+    0x187d, 0x1708, 0x158f, 0x1413, 0x1294, 0x1111, 0x0f8c, 0x0e05,
+    0x0c7c, 0x0af1, 0x0964, 0x07d5, 0x0645, 0x04b5, 0x0323, 0x0192,
+    0x0000, -0x0192, -0x0323, -0x04b5, -0x0645, -0x07d5, -0x0964, -0x0af1,
+    -0x0c7c, -0x0e05, -0x0f8c, -0x1111, -0x1294, -0x1413, -0x158f, -0x1708,
+    -0x187d, -0x19ef, -0x1b5d, -0x1cc6, -0x1e2b, -0x1f8b, -0x20e7, -0x223d,
+    -0x238e, -0x24da, -0x261f, -0x275f, -0x2899, -0x29cd, -0x2afa, -0x2c21,
+    -0x2d41, -0x2e5a, -0x2f6b, -0x3076, -0x3179, -0x3274, -0x3367, -0x3453,
+    -0x3536, -0x3612, -0x36e5, -0x37af, -0x3871, -0x392a, -0x39da, -0x3a82,
+    -0x3b20, -0x3bb6, -0x3c42, -0x3cc5, -0x3d3e, -0x3dae, -0x3e14, -0x3e71,
+    -0x3ec5, -0x3f0e, -0x3f4e, -0x3f84, -0x3fb1, -0x3fd3, -0x3fec, -0x3ffb,
+    -0x4000, -0x3ffb, -0x3fec, -0x3fd3, -0x3fb1, -0x3f84, -0x3f4e, -0x3f0e,
+    -0x3ec5, -0x3e71, -0x3e14, -0x3dae, -0x3d3e, -0x3cc5, -0x3c42, -0x3bb6,
+    -0x3b20, -0x3a82, -0x39da, -0x392a, -0x3871, -0x37af, -0x36e5, -0x3612,
+    -0x3536, -0x3453, -0x3367, -0x3274, -0x3179, -0x3076, -0x2f6b, -0x2e5a,
+    -0x2d41, -0x2c21, -0x2afa, -0x29cd, -0x2899, -0x275f, -0x261f, -0x24da,
+    -0x238e, -0x223d, -0x20e7, -0x1f8b, -0x1e2b, -0x1cc6, -0x1b5d, -0x19ef,
+    -0x187d, -0x1708, -0x158f, -0x1413, -0x1294, -0x1111, -0x0f8c, -0x0e05,
+    -0x0c7c, -0x0af1, -0x0964, -0x07d5, -0x0645, -0x04b5, -0x0323, -0x0192,
+];
+
+/// Sine and cosine of an angle, where a full circle is 0x10000. Only the top
+/// byte of the angle is used.
+fn sine_and_cosine(angle: u16) -> (i32, i32) {
+    let index = (angle >> 8) as usize;
+    (
+        SINE_TABLE[index] as i32,
+        SINE_TABLE[(index + 0x40) & 0xff] as i32,
+    )
+}
+
+/// Works out the four parameters of an affine matrix, in 8.8 fixed point,
+/// that scales by `scale_x` and `scale_y` and then rotates by `angle`. Each
+/// product is shifted down, which rounds it down, and the BIOS only negates
+/// the second parameter after that, so it rounds up instead.
+fn matrix(scale_x: i16, scale_y: i16, angle: u16) -> [i16; 4] {
+    let (sine, cosine) = sine_and_cosine(angle);
+    let (scale_x, scale_y) = (scale_x as i32, scale_y as i32);
+
+    [
+        ((scale_x * cosine) >> 14) as i16,
+        (-((scale_x * sine) >> 14)) as i16,
+        ((scale_y * sine) >> 14) as i16,
+        ((scale_y * cosine) >> 14) as i16,
+    ]
+}
+
+/// Calculates the rotation and scaling registers for the number of
+/// backgrounds in r2. Each entry of the source in r0 is 20 bytes:
+///
+/// - 32 bits: the x coordinate of the center, in the background (8.8)
+/// - 32 bits: the y coordinate of the center, in the background (8.8)
+/// - 16 bits: the x coordinate of the center, on the screen
+/// - 16 bits: the y coordinate of the center, on the screen
+/// - 16 bits: the horizontal scale (8.8)
+/// - 16 bits: the vertical scale (8.8)
+/// - 16 bits: the angle, where a full circle is 0x10000
+///
+/// Each entry of the destination in r1 is 16 bytes, laid out just like the
+/// BG2PA-BG2PD, BG2X, and BG2Y registers.
+pub fn bg_affine_set(emulator: &mut Emulator) -> u32 {
+    let mut source = get(emulator, r0) & !3;
+    let mut destination = get(emulator, r1) & !3;
+    let count = get(emulator, r2);
+    let memory = &mut emulator.memory;
+
+    for _ in 0..count {
+        let center_x = memory.read_word(source) as i32;
+        let center_y = memory.read_word(source + 4) as i32;
+        let screen_x = memory.read_half_word(source + 8) as i16 as i32;
+        let screen_y = memory.read_half_word(source + 10) as i16 as i32;
+        let scale_x = memory.read_half_word(source + 12) as i16;
+        let scale_y = memory.read_half_word(source + 14) as i16;
+        let angle = memory.read_half_word(source + 16);
+
+        let parameters = matrix(scale_x, scale_y, angle);
+        let [a, b, c, d] = parameters.map(|parameter| parameter as i32);
+
+        // Move the background so that its center ends up at the center on
+        // the screen
+        let start_x = center_x.wrapping_sub(a * screen_x + b * screen_y);
+        let start_y = center_y.wrapping_sub(c * screen_x + d * screen_y);
+
+        for (index, parameter) in parameters.iter().enumerate() {
+            memory.write_half_word(destination + index as u32 * 2, *parameter as u16);
+        }
+        memory.write_word(destination + 8, start_x as u32);
+        memory.write_word(destination + 12, start_y as u32);
+
+        source += 20;
+        destination += 16;
+    }
+
+    20 + 50 * count
+}
+
+/// Calculates the rotation and scaling parameters for the number of sprites in
+/// r2. Each entry of the source in r0 is 8 bytes: the horizontal and vertical
+/// scales (8.8), the angle, and two bytes of padding. The four parameters are
+/// written to the destination in r1, r3 bytes apart, which is 2 to write them
+/// next to each other, or 8 to write them straight into OAM.
+pub fn obj_affine_set(emulator: &mut Emulator) -> u32 {
+    let mut source = get(emulator, r0) & !1;
+    let mut destination = get(emulator, r1) & !1;
+    let count = get(emulator, r2);
+    let stride = get(emulator, r3);
+    let memory = &mut emulator.memory;
+
+    for _ in 0..count {
+        let scale_x = memory.read_half_word(source) as i16;
+        let scale_y = memory.read_half_word(source + 2) as i16;
+        let angle = memory.read_half_word(source + 4);
+
+        for parameter in matrix(scale_x, scale_y, angle).iter() {
+            memory.write_half_word(destination, *parameter as u16);
+            destination = destination.wrapping_add(stride);
+        }
+
+        source += 8;
+    }
+
+    20 + 32 * count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::{EXT_START, OBJECT_ATTRIBUTE_START};
+
+    const SOURCE: u32 = EXT_START as u32;
+    const DESTINATION: u32 = EXT_START as u32 + 0x100;
+
+    #[test]
+    fn sine_table() {
+        assert_eq!(SINE_TABLE[0x20], 0x2d41);
+        assert_eq!(SINE_TABLE[0x40], 0x4000);
+        for index in 0..0x80 {
+            assert_eq!(SINE_TABLE[index + 0x80], -SINE_TABLE[index]);
+            assert_eq!(SINE_TABLE[index], SINE_TABLE[(0x80 - index) & 0xff]);
+        }
+    }
+
+    #[test]
+    fn sprite_matrices() {
+        assert_eq!(matrix(0x100, 0x100, 0), [0x100, 0, 0, 0x100]);
+        assert_eq!(matrix(0x100, 0x200, 0x4000), [0, -0x100, 0x200, 0]);
+        assert_eq!(matrix(0x100, 0x100, 0x20ff), [0xb5, -0xb5, 0xb5, 0xb5]);
+        // The second parameter rounds up, but all of the others round down
+        assert_eq!(matrix(-0x100, 0x100, 0x0100), [-0x100, 0x7, 0x6, 0xff]);
+    }
+
+    #[test]
+    fn obj_affine_set_into_oam() {
+        let mut emulator = Emulator::new();
+        emulator.memory.write_half_word(SOURCE, 0x200);
+        emulator.memory.write_half_word(SOURCE + 2, 0x100);
+        emulator.memory.write_half_word(SOURCE + 4, 0xc000);
+
+        let oam = OBJECT_ATTRIBUTE_START as u32;
+        emulator.cpu.set_register_value(r0, SOURCE);
+        emulator.cpu.set_register_value(r1, oam + 6);
+        emulator.cpu.set_register_value(r2, 1);
+        emulator.cpu.set_register_value(r3, 8);
+        obj_affine_set(&mut emulator);
+
+        let parameters: Vec<u16> = (0..4)
+            .map(|index| emulator.memory.read_half_word(oam + 6 + index * 8))
+            .collect();
+        assert_eq!(parameters, [0, 0x200, -0x100i16 as u16, 0]);
+    }
+
+    #[test]
+    fn bg_affine_set_centers() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+        memory.write_word(SOURCE, 64 << 8);
+        memory.write_word(SOURCE + 4, 32 << 8);
+        memory.write_half_word(SOURCE + 8, 120);
+        memory.write_half_word(SOURCE + 10, 80);
+        memory.write_half_word(SOURCE + 12, 0x100);
+        memory.write_half_word(SOURCE + 14, 0x100);
+        memory.write_half_word(SOURCE + 16, 0x4000);
+
+        emulator.cpu.set_register_value(r0, SOURCE);
+        emulator.cpu.set_register_value(r1, DESTINATION);
+        emulator.cpu.set_register_value(r2, 1);
+        bg_affine_set(&mut emulator);
+
+        // Rotated by 90 degrees, so screen y becomes background x
+        let memory = &mut emulator.memory;
+        assert_eq!(memory.read_half_word(DESTINATION), 0);
+        assert_eq!(memory.read_half_word(DESTINATION + 2), -0x100i16 as u16);
+        assert_eq!(memory.read_half_word(DESTINATION + 4), 0x100);
+        assert_eq!(memory.read_half_word(DESTINATION + 6), 0);
+        assert_eq!(memory.read_word(DESTINATION + 8), (64 + 80) << 8);
+        assert_eq!(memory.read_word(DESTINATION + 12), ((32 - 120) << 8) as u32);
+    }
+}
@@ -0,0 +1,221 @@
+use super::{get, set};
+use crate::emulator::cpu::RegisterNames::*;
+use crate::emulator::Emulator;
+use log::debug;
+
+/// Signed division of r0 by r1. Returns the quotient in r0, the remainder in
+/// r1, and the absolute value of the quotient in r3.
+pub fn div(emulator: &mut Emulator) -> u32 {
+    let numerator = get(emulator, r0) as i32;
+    let denominator = get(emulator, r1) as i32;
+    divide(emulator, numerator, denominator)
+}
+
+/// The same as Div, but with the numerator and denominator swapped. This was
+/// meant for compatibility with ARM's C library, and is a little slower.
+pub fn div_arm(emulator: &mut Emulator) -> u32 {
+    let numerator = get(emulator, r1) as i32;
+    let denominator = get(emulator, r0) as i32;
+    3 + divide(emulator, numerator, denominator)
+}
+
+fn divide(emulator: &mut Emulator, numerator: i32, denominator: i32) -> u32 {
+    if denominator == 0 {
+        // The real BIOS gets stuck in an infinite loop here. Games can't
+        // depend on that, so we return something reasonable instead.
+        debug!("BIOS division of {} by zero", numerator);
+        set(emulator, r0, if numerator < 0 { -1i32 as u32 } else { 1 });
+        set(emulator, r1, numerator as u32);
+        set(emulator, r3, 1);
+        return 11;
+    }
+
+    let quotient = numerator.wrapping_div(denominator);
+    set(emulator, r0, quotient as u32);
+    set(emulator, r1, numerator.wrapping_rem(denominator) as u32);
+    set(emulator, r3, quotient.wrapping_abs() as u32);
+
+    // The BIOS uses long division, with one step for every bit that the
+    // denominator needs to be shifted to line up with the numerator.
+    let steps = denominator.unsigned_abs().leading_zeros() as i32
+        - numerator.unsigned_abs().leading_zeros() as i32;
+    4 + 13 * steps.max(1) as u32 + 7
+}
+
+/// Integer square root of r0, which is treated as unsigned.
+pub fn sqrt(emulator: &mut Emulator) -> u32 {
+    let (root, cycles) = square_root(get(emulator, r0));
+    set(emulator, r0, root);
+    cycles
+}
+
+/// The BIOS finds square roots with Newton's method, starting from the
+/// smallest power of two that is larger than the root.
+fn square_root(value: u32) -> (u32, u32) {
+    if value == 0 {
+        return (0, 53);
+    }
+
+    let mut cycles = 15;
+    let mut upper = value;
+    let mut bound = 1u32;
+    while bound < upper {
+        upper >>= 1;
+        bound <<= 1;
+        cycles += 6;
+    }
+
+    loop {
+        cycles += 6;
+        let next = (bound + value / bound) >> 1;
+        cycles += 8 * (32 - bound.leading_zeros());
+        if next >= bound {
+            return (bound, cycles);
+        }
+        bound = next;
+    }
+}
+
+/// Arc tangent of r0, which is a 1.1.14 fixed point number. The result is in
+/// the range -π/2 to π/2, where π is 0x8000.
+pub fn arc_tan(emulator: &mut Emulator) -> u32 {
+    let tangent = get(emulator, r0) as i32;
+    let (angle, a, b, cycles) = polynomial_arc_tan(tangent);
+    set(emulator, r0, angle as u32);
+    set(emulator, r1, a as u32);
+    set(emulator, r3, b as u32);
+    cycles
+}
+
+/// Angle of the point (r0, r1), as a full circle from 0 to 0xffff.
+pub fn arc_tan2(emulator: &mut Emulator) -> u32 {
+    let x = get(emulator, r0) as i32;
+    let y = get(emulator, r1) as i32;
+
+    // For each octant, find the angle using whichever ratio is less than one
+    let arc_tan = |numerator: i32, denominator: i32| {
+        let (angle, a, _, cycles) = polynomial_arc_tan((numerator << 14).wrapping_div(denominator));
+        (angle, Some(a), cycles)
+    };
+    let (angle, a, cycles) = match (x, y) {
+        (x, 0) => (if x >= 0 { 0 } else { 0x8000 }, None, 11),
+        (0, y) => (if y >= 0 { 0x4000 } else { 0xc000 }, None, 11),
+        (x, y) if y > 0 && x > 0 && x >= y => arc_tan(y, x),
+        (x, y) if y > 0 && x < 0 && -x >= y => add(arc_tan(y, x), 0x8000),
+        (x, y) if y > 0 => subtract_from(0x4000, arc_tan(x, y)),
+        (x, y) if x < 0 && -x > -y => add(arc_tan(y, x), 0x8000),
+        (x, y) if x > 0 && x >= -y => add(arc_tan(y, x), 0x10000),
+        (x, y) => subtract_from(0xc000, arc_tan(x, y)),
+    };
+
+    set(emulator, r0, angle as u32 & 0xffff);
+    if let Some(a) = a {
+        set(emulator, r1, a as u32);
+    }
+    cycles
+}
+
+fn add((angle, a, cycles): (i32, Option<i32>, u32), offset: i32) -> (i32, Option<i32>, u32) {
+    (angle + offset, a, cycles)
+}
+
+fn subtract_from(
+    base: i32,
+    (angle, a, cycles): (i32, Option<i32>, u32),
+) -> (i32, Option<i32>, u32) {
+    (base - angle, a, cycles)
+}
+
+/// The BIOS approximates the arc tangent with a polynomial, and leaves a
+/// couple of the intermediate values in r1 and r3. Returns the angle, those
+/// two values, and the number of cycles used.
+fn polynomial_arc_tan(tangent: i32) -> (i32, i32, i32, u32) {
+    const COEFFICIENTS: [i32; 7] = [0x390, 0x91c, 0xfb6, 0x16aa, 0x2081, 0x3651, 0xa2f9];
+
+    let mut cycles = 37;
+    let square = tangent.wrapping_mul(tangent);
+    cycles += multiply_cycles(square);
+    let a = -(square >> 14);
+
+    let product = 0xa9i32.wrapping_mul(a);
+    cycles += multiply_cycles(product);
+    let mut b = (product >> 14) + COEFFICIENTS[0];
+
+    for coefficient in COEFFICIENTS.iter().skip(1) {
+        let product = b.wrapping_mul(a);
+        cycles += multiply_cycles(product);
+        b = (product >> 14) + coefficient;
+    }
+
+    let angle = (tangent.wrapping_mul(b) >> 16) as i16 as i32;
+    (angle, a, b, cycles)
+}
+
+/// Multiplies finish early when the upper bytes of the operand are all zeros
+/// or all ones.
+fn multiply_cycles(value: i32) -> u32 {
+    let value = value as u32;
+    let fits = |mask: u32| value & mask == 0 || value & mask == mask;
+
+    if fits(0xffff_ff00) {
+        1
+    } else if fits(0xffff_0000) {
+        2
+    } else if fits(0xff00_0000) {
+        3
+    } else {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(function: fn(&mut Emulator) -> u32, registers: &[u32]) -> Emulator {
+        let mut emulator = Emulator::new();
+        for (index, value) in registers.iter().enumerate() {
+            emulator
+                .cpu
+                .set_register_value([r0, r1, r2, r3][index], *value);
+        }
+        function(&mut emulator);
+        emulator
+    }
+
+    #[test]
+    fn divide() {
+        let emulator = run(div, &[-7i32 as u32, 2]);
+        assert_eq!(get(&emulator, r0), -3i32 as u32);
+        assert_eq!(get(&emulator, r1), -1i32 as u32);
+        assert_eq!(get(&emulator, r3), 3);
+
+        let emulator = run(div_arm, &[3, 100]);
+        assert_eq!(get(&emulator, r0), 33);
+        assert_eq!(get(&emulator, r1), 1);
+    }
+
+    #[test]
+    fn square_roots() {
+        for value in (0..1_000_000u32).step_by(37).chain(vec![u32::MAX]) {
+            let expected = (value as f64).sqrt().floor() as u32;
+            assert_eq!(square_root(value).0, expected, "sqrt({})", value);
+        }
+    }
+
+    #[test]
+    fn arc_tangents() {
+        // tan(π/4) = 1
+        let emulator = run(arc_tan, &[0x4000]);
+        assert_eq!(get(&emulator, r0), 0x2000);
+
+        let emulator = run(arc_tan2, &[0x100, 0x100]);
+        assert_eq!(get(&emulator, r0), 0x2000);
+        let emulator = run(arc_tan2, &[-0x100i32 as u32, 0]);
+        assert_eq!(get(&emulator, r0), 0x8000);
+        let emulator = run(arc_tan2, &[0, -0x100i32 as u32]);
+        assert_eq!(get(&emulator, r0), 0xc000);
+        let emulator = run(arc_tan2, &[0x100, -0x100i32 as u32]);
+        assert_eq!(get(&emulator, r0), 0xe000);
+    }
+}
@@ -0,0 +1,204 @@
+use super::get;
+use crate::emulator::cpu::RegisterNames::*;
+use crate::emulator::Emulator;
+
+/// Bits of the control value passed in r2.
+const FILL: u32 = 1 << 24;
+const WORDS: u32 = 1 << 26;
+
+/// Copies or fills memory, from the address in r0 to the address in r1. The
+/// bottom 21 bits of r2 are the number of units to copy, bit 24 fills the
+/// destination with the first unit of the source, and bit 26 selects 32-bit
+/// units instead of 16-bit ones.
+pub fn cpu_set(emulator: &mut Emulator) -> u32 {
+    let source = get(emulator, r0);
+    let destination = get(emulator, r1);
+    let control = get(emulator, r2);
+
+    let count = control & 0x1f_ffff;
+    let fill = control & FILL != 0;
+
+    if control & WORDS != 0 {
+        let (source, destination) = (source & !3, destination & !3);
+        let value = emulator.memory.read_word(source);
+
+        for index in 0..count {
+            let value = if fill {
+                value
+            } else {
+                emulator.memory.read_word(source + index * 4)
+            };
+            emulator.memory.write_word(destination + index * 4, value);
+        }
+    } else {
+        let (source, destination) = (source & !1, destination & !1);
+        let value = emulator.memory.read_half_word(source);
+
+        for index in 0..count {
+            let value = if fill {
+                value
+            } else {
+                emulator.memory.read_half_word(source + index * 2)
+            };
+            emulator
+                .memory
+                .write_half_word(destination + index * 2, value);
+        }
+    }
+
+    // Each unit is a load (unless filling), a store, and a loop
+    let per_unit = if fill { 6 } else { 9 };
+    20 + per_unit * count
+}
+
+/// Like CpuSet, but always works with words and moves eight of them at a time.
+/// The count is rounded up to a multiple of eight.
+pub fn cpu_fast_set(emulator: &mut Emulator) -> u32 {
+    let source = get(emulator, r0) & !3;
+    let destination = get(emulator, r1) & !3;
+    let control = get(emulator, r2);
+
+    let count = ((control & 0x1f_ffff) + 7) & !7;
+    let fill = control & FILL != 0;
+    let value = emulator.memory.read_word(source);
+
+    for index in 0..count {
+        let value = if fill {
+            value
+        } else {
+            emulator.memory.read_word(source + index * 4)
+        };
+        emulator.memory.write_word(destination + index * 4, value);
+    }
+
+    // An ldmia and stmia of eight registers for every block
+    let per_block = if fill { 11 } else { 20 };
+    24 + per_block * count / 8
+}
+
+/// Expands data with a small number of bits per unit into larger units. r0
+/// points to the source, r1 to the destination, and r2 to a structure that
+/// describes how to unpack it:
+///
+/// - 16 bits: the length of the source in bytes
+/// - 8 bits: the width of each source unit (1, 2, 4, or 8 bits)
+/// - 8 bits: the width of each destination unit (1, 2, 4, 8, 16, or 32 bits)
+/// - 31 bits: an offset added to each unit
+/// - 1 bit: whether the offset is also added to units that are zero
+pub fn bit_unpack(emulator: &mut Emulator) -> u32 {
+    let source = get(emulator, r0);
+    let mut destination = get(emulator, r1) & !3;
+    let info = get(emulator, r2);
+
+    let length = emulator.memory.read_half_word(info & !1) as u32;
+    let source_width = emulator.memory.read_byte(info + 2) as u32;
+    let destination_width = emulator.memory.read_byte(info + 3) as u32;
+    let offset_info = emulator.memory.read_half_word((info + 4) & !1) as u32
+        | (emulator.memory.read_half_word((info + 6) & !1) as u32) << 16;
+    let offset = offset_info & 0x7fff_ffff;
+    let offset_zero = offset_info >> 31 != 0;
+
+    if ![1, 2, 4, 8].contains(&source_width) || ![1, 2, 4, 8, 16, 32].contains(&destination_width) {
+        return 20;
+    }
+
+    let mut output = 0u32;
+    let mut output_bits = 0;
+    let mut cycles = 20;
+
+    for index in 0..length {
+        let byte = emulator.memory.read_byte(source + index) as u32;
+
+        for shift in (0..8).step_by(source_width as usize) {
+            let mut unit = byte >> shift & ((1 << source_width) - 1);
+            if unit != 0 || offset_zero {
+                unit = unit.wrapping_add(offset);
+            }
+
+            output |= unit.checked_shl(output_bits).unwrap_or(0);
+            output_bits += destination_width;
+            cycles += 12;
+
+            if output_bits >= 32 {
+                emulator.memory.write_word(destination, output);
+                destination += 4;
+                output = 0;
+                output_bits = 0;
+            }
+        }
+    }
+
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::{EXT_START, RAM_START};
+
+    const SOURCE: u32 = RAM_START as u32;
+    const DESTINATION: u32 = EXT_START as u32;
+
+    fn setup(control: u32) -> Emulator {
+        let mut emulator = Emulator::new();
+        for index in 0..16 {
+            emulator
+                .memory
+                .write_word(SOURCE + index * 4, 0x1111_1111 * index);
+        }
+        emulator.cpu.set_register_value(r0, SOURCE);
+        emulator.cpu.set_register_value(r1, DESTINATION);
+        emulator.cpu.set_register_value(r2, control);
+        emulator
+    }
+
+    #[test]
+    fn copy_half_words() {
+        let mut emulator = setup(3);
+        cpu_set(&mut emulator);
+
+        assert_eq!(emulator.memory.read_half_word(DESTINATION + 4), 0x1111);
+        assert_eq!(emulator.memory.read_half_word(DESTINATION + 6), 0);
+    }
+
+    #[test]
+    fn fill_words() {
+        let mut emulator = setup(FILL | WORDS | 4);
+        emulator.memory.write_word(SOURCE, 0xcafe_f00d);
+        cpu_set(&mut emulator);
+
+        for index in 0..4 {
+            assert_eq!(
+                emulator.memory.read_word(DESTINATION + index * 4),
+                0xcafe_f00d
+            );
+        }
+        assert_eq!(emulator.memory.read_word(DESTINATION + 16), 0);
+    }
+
+    #[test]
+    fn fast_set_rounds_up() {
+        let mut emulator = setup(3);
+        cpu_fast_set(&mut emulator);
+
+        assert_eq!(emulator.memory.read_word(DESTINATION + 7 * 4), 0x7777_7777);
+        assert_eq!(emulator.memory.read_word(DESTINATION + 8 * 4), 0);
+    }
+
+    #[test]
+    fn unpack_bits() {
+        let mut emulator = setup(0);
+        let info = SOURCE + 0x100;
+
+        // Two bytes of 1-bit units into 4-bit units, adding 1 to non-zero units
+        emulator.memory.write_half_word(SOURCE, 0x0f05);
+        emulator.memory.write_half_word(info, 2);
+        emulator.memory.write_half_word(info + 2, 0x0401);
+        emulator.memory.write_word(info + 4, 1);
+        emulator.cpu.set_register_value(r2, info);
+        bit_unpack(&mut emulator);
+
+        assert_eq!(emulator.memory.read_word(DESTINATION), 0x0000_0202);
+        assert_eq!(emulator.memory.read_word(DESTINATION + 4), 0x0000_2222);
+    }
+}
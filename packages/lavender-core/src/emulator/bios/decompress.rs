@@ -0,0 +1,391 @@
+use super::get;
+use crate::emulator::cpu::RegisterNames::*;
+use crate::emulator::Emulator;
+use log::debug;
+
+/// The width of the writes that a decompression function makes. The WRAM
+/// versions write a byte at a time, but VRAM can't be written that way, so
+/// the VRAM versions wait until they have a whole half word.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Writes {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+/// Every compressed stream begins with a word describing it. Bits 4-7 are
+/// the type of compression, bits 0-3 are extra information for some types,
+/// and the top 24 bits are the size of the decompressed data in bytes.
+struct Header {
+    kind: u32,
+    parameter: u32,
+    size: u32,
+}
+
+fn read_header(emulator: &mut Emulator, source: u32) -> Header {
+    let header = read_word(emulator, source);
+    Header {
+        kind: header >> 4 & 0xf,
+        parameter: header & 0xf,
+        size: header >> 8,
+    }
+}
+
+/// Compressed data isn't always word aligned, so words are read a byte at a
+/// time.
+fn read_word(emulator: &mut Emulator, address: u32) -> u32 {
+    u32::from_le_bytes([
+        emulator.memory.read_byte(address),
+        emulator.memory.read_byte(address + 1),
+        emulator.memory.read_byte(address + 2),
+        emulator.memory.read_byte(address + 3),
+    ])
+}
+
+/// Writes the decompressed data to the destination in r1, using the given
+/// width. Anything that doesn't fill a whole unit at the end is never written.
+fn write_output(emulator: &mut Emulator, data: &[u8], writes: Writes) {
+    let destination = get(emulator, r1);
+
+    match writes {
+        Writes::Byte => {
+            for (index, byte) in data.iter().enumerate() {
+                emulator
+                    .memory
+                    .write_byte(destination + index as u32, *byte);
+            }
+        }
+        Writes::HalfWord => {
+            let destination = destination & !1;
+            for (index, pair) in data.chunks_exact(2).enumerate() {
+                let value = u16::from_le_bytes([pair[0], pair[1]]);
+                emulator
+                    .memory
+                    .write_half_word(destination + index as u32 * 2, value);
+            }
+        }
+        Writes::Word => {
+            let destination = destination & !3;
+            for (index, word) in data.chunks_exact(4).enumerate() {
+                let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                emulator
+                    .memory
+                    .write_word(destination + index as u32 * 4, value);
+            }
+        }
+    }
+}
+
+/// LZ77UnCompWram and LZ77UnCompVram. The data is split into blocks of eight
+/// items, each preceded by a byte of flags (most significant bit first). An
+/// item is either a literal byte, or two bytes that describe a copy of
+/// earlier output: 4 bits of length minus 3, and 12 bits of distance minus 1.
+pub fn lz77(emulator: &mut Emulator, writes: Writes) -> u32 {
+    let mut source = get(emulator, r0);
+    let header = read_header(emulator, source);
+    source += 4;
+
+    let size = header.size as usize;
+    let mut output = Vec::with_capacity(size);
+    let mut cycles = 20;
+
+    'blocks: while output.len() < size {
+        let flags = emulator.memory.read_byte(source);
+        source += 1;
+
+        for bit in (0..8).rev() {
+            if output.len() >= size {
+                break 'blocks;
+            }
+
+            if flags >> bit & 1 == 0 {
+                output.push(emulator.memory.read_byte(source));
+                source += 1;
+                cycles += 10;
+                continue;
+            }
+
+            let first = emulator.memory.read_byte(source) as usize;
+            let second = emulator.memory.read_byte(source + 1) as usize;
+            source += 2;
+
+            let length = (first >> 4) + 3;
+            let distance = ((first & 0xf) << 8 | second) + 1;
+            if distance > output.len() {
+                debug!("LZ77 data refers to {} bytes before the start", distance);
+                return cycles;
+            }
+
+            for _ in 0..length.min(size - output.len()) {
+                output.push(output[output.len() - distance]);
+            }
+            cycles += 10 + 6 * length as u32;
+        }
+    }
+
+    write_output(emulator, &output, writes);
+    cycles
+}
+
+/// HuffUnComp. After the header is a byte giving the size of the tree, and
+/// then the tree itself, followed by a stream of words which are read most
+/// significant bit first. Each bit picks a branch of the tree, until a leaf
+/// with a 4 or 8 bit value is reached. Output is written a word at a time.
+pub fn huffman(emulator: &mut Emulator) -> u32 {
+    let source = get(emulator, r0);
+    let header = read_header(emulator, source);
+
+    let data_bits = header.parameter;
+    if data_bits != 4 && data_bits != 8 {
+        debug!("Huffman data can't have {}-bit units", data_bits);
+        return 20;
+    }
+
+    let tree_size = (emulator.memory.read_byte(source + 4) as u32 + 1) * 2;
+    let root = source + 5;
+    let mut stream = source + 4 + tree_size;
+
+    let size = header.size as usize;
+    let mut output = Vec::with_capacity(size + 4);
+    let mut word = 0u32;
+    let mut word_bits = 0;
+    let mut node_address = root;
+    let mut depth = 0;
+    let mut cycles = 20;
+
+    // A bad pointer or a broken tree can mean that no leaf is ever reached,
+    // and the output never grows. Each symbol needs at most a word of input,
+    // and no path through a valid tree is longer than the tree itself.
+    let symbols = (size as u32).div_ceil(4) * 32 / data_bits;
+    let end = stream.saturating_add(symbols * 4);
+
+    'stream: while output.len() < size {
+        if stream >= end {
+            debug!("Huffman stream at {:08x} ran past its end", source);
+            break;
+        }
+
+        let bits = read_word(emulator, stream);
+        stream += 4;
+
+        for bit in (0..32).rev() {
+            let node = emulator.memory.read_byte(node_address);
+            let right = bits >> bit & 1 != 0;
+            let children = (node_address & !1) + (node as u32 & 0x3f) * 2 + 2;
+            let child = children + right as u32;
+            let is_leaf = node >> if right { 6 } else { 7 } & 1 != 0;
+            cycles += 6;
+
+            if !is_leaf {
+                node_address = child;
+                depth += 1;
+                if depth > tree_size {
+                    debug!("Huffman tree at {:08x} has no leaves", root);
+                    break 'stream;
+                }
+                continue;
+            }
+
+            let value = emulator.memory.read_byte(child) as u32 & ((1 << data_bits) - 1);
+            word |= value << word_bits;
+            word_bits += data_bits;
+            node_address = root;
+            depth = 0;
+
+            if word_bits == 32 {
+                output.extend_from_slice(&word.to_le_bytes());
+                word = 0;
+                word_bits = 0;
+                if output.len() >= size {
+                    break;
+                }
+            }
+        }
+    }
+
+    output.truncate(size & !3);
+    write_output(emulator, &output, Writes::Word);
+    cycles
+}
+
+/// RLUnCompWram and RLUnCompVram. Each run starts with a flag byte. If bit 7
+/// is set, the next byte is repeated (flag & 0x7f) + 3 times, and otherwise
+/// the next (flag & 0x7f) + 1 bytes are copied as they are.
+pub fn run_length(emulator: &mut Emulator, writes: Writes) -> u32 {
+    let mut source = get(emulator, r0);
+    let header = read_header(emulator, source);
+    source += 4;
+
+    let size = header.size as usize;
+    let mut output = Vec::with_capacity(size);
+    let mut cycles = 20;
+
+    while output.len() < size {
+        let flag = emulator.memory.read_byte(source) as usize;
+        source += 1;
+
+        if flag & 0x80 != 0 {
+            let value = emulator.memory.read_byte(source);
+            source += 1;
+            let length = (flag & 0x7f) + 3;
+            output.resize(output.len() + length, value);
+            cycles += 10 + 4 * length as u32;
+        } else {
+            for _ in 0..(flag & 0x7f) + 1 {
+                output.push(emulator.memory.read_byte(source));
+                source += 1;
+            }
+            cycles += 10 + 8 * ((flag & 0x7f) + 1) as u32;
+        }
+    }
+
+    output.truncate(size);
+    write_output(emulator, &output, writes);
+    cycles
+}
+
+/// Diff8bitUnFilterWram, Diff8bitUnFilterVram, and Diff16bitUnFilter. The
+/// data is stored as the difference between each unit and the one before it,
+/// which makes it compress better.
+pub fn unfilter(emulator: &mut Emulator, unit_size: u32, writes: Writes) -> u32 {
+    let mut source = get(emulator, r0);
+    let header = read_header(emulator, source);
+    source += 4;
+
+    if header.kind != 8 || header.parameter != unit_size {
+        debug!(
+            "Data for a {}-byte unfilter has the wrong header",
+            unit_size
+        );
+    }
+
+    let size = header.size as usize;
+    let mut output = Vec::with_capacity(size);
+    let mut previous = 0u16;
+
+    while output.len() < size {
+        if unit_size == 1 {
+            let byte = (previous as u8).wrapping_add(emulator.memory.read_byte(source));
+            output.push(byte);
+            previous = byte as u16;
+        } else {
+            let difference = u16::from_le_bytes([
+                emulator.memory.read_byte(source),
+                emulator.memory.read_byte(source + 1),
+            ]);
+            previous = previous.wrapping_add(difference);
+            output.extend_from_slice(&previous.to_le_bytes());
+        }
+        source += unit_size;
+    }
+
+    output.truncate(size);
+    write_output(emulator, &output, writes);
+    20 + 8 * size as u32 / unit_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::{EXT_START, VRAM_START};
+
+    const SOURCE: u32 = EXT_START as u32;
+    const DESTINATION: u32 = VRAM_START as u32;
+
+    fn decompress(data: &[u8], function: impl Fn(&mut Emulator) -> u32) -> Emulator {
+        let mut emulator = Emulator::new();
+        for (index, byte) in data.iter().enumerate() {
+            emulator.memory.write_byte(SOURCE + index as u32, *byte);
+        }
+        emulator.cpu.set_register_value(r0, SOURCE);
+        emulator.cpu.set_register_value(r1, DESTINATION);
+        function(&mut emulator);
+        emulator
+    }
+
+    fn output(emulator: &mut Emulator, length: u32) -> Vec<u8> {
+        (0..length)
+            .map(|index| emulator.memory.read_byte(DESTINATION + index))
+            .collect()
+    }
+
+    #[test]
+    fn lz77_blob() {
+        // "ABC", then a copy of 9 bytes from 3 bytes back
+        let data = [0x10, 0x0c, 0x00, 0x00, 0x10, 0x41, 0x42, 0x43, 0x60, 0x02];
+
+        let mut emulator = decompress(&data, |emulator| lz77(emulator, Writes::HalfWord));
+        assert_eq!(output(&mut emulator, 12), b"ABCABCABCABC");
+
+        // Overlapping copies of a single byte
+        let data = [0x10, 0x05, 0x00, 0x00, 0x40, 0x5a, 0x10, 0x00];
+        let mut emulator = decompress(&data, |emulator| lz77(emulator, Writes::Byte));
+        assert_eq!(output(&mut emulator, 6), b"ZZZZZ\0");
+    }
+
+    #[test]
+    fn lz77_vram_only_writes_half_words() {
+        // Three literal bytes, so the last one is never written
+        let data = [0x10, 0x03, 0x00, 0x00, 0x00, 0x31, 0x32, 0x33];
+
+        let mut emulator = decompress(&data, |emulator| lz77(emulator, Writes::HalfWord));
+        assert_eq!(output(&mut emulator, 3), b"12\0");
+    }
+
+    #[test]
+    fn huffman_blob() {
+        // A tree with 'A' on the left and 'B' on the right, then 0110
+        let data = [
+            0x28, 0x04, 0x00, 0x00, 0x01, 0xc0, 0x41, 0x42, 0x00, 0x00, 0x00, 0x60,
+        ];
+
+        let mut emulator = decompress(&data, huffman);
+        assert_eq!(output(&mut emulator, 4), b"ABBA");
+    }
+
+    #[test]
+    fn huffman_four_bit_blob() {
+        // 0x1 on the left, then 0x2 on the left of the right subtree, and
+        // 0x3 and 0x4 below that
+        #[rustfmt::skip]
+        let data = [
+            0x24, 0x04, 0x00, 0x00,
+            0x03, 0x80, 0x01, 0x80, 0x02, 0xc0, 0x03, 0x04,
+            // 0, 10, 110, 111, 0, 0, 10, 10
+            0x00, 0x00, 0x94, 0x5b,
+        ];
+
+        let mut emulator = decompress(&data, huffman);
+        assert_eq!(output(&mut emulator, 4), &[0x21, 0x43, 0x11, 0x22]);
+    }
+
+    #[test]
+    fn huffman_without_leaves() {
+        // A tree of nothing but empty nodes, which the stream would never
+        // find its way out of
+        let data = [0x28, 0x00, 0x00, 0x01, 0x7f];
+
+        let mut emulator = decompress(&data, huffman);
+        assert_eq!(output(&mut emulator, 4), &[0; 4]);
+    }
+
+    #[test]
+    fn run_length_blob() {
+        // A run of five 'A's, then one literal 'B'
+        let data = [0x30, 0x06, 0x00, 0x00, 0x82, 0x41, 0x00, 0x42];
+
+        let mut emulator = decompress(&data, |emulator| run_length(emulator, Writes::HalfWord));
+        assert_eq!(output(&mut emulator, 6), b"AAAAAB");
+    }
+
+    #[test]
+    fn unfilter_blobs() {
+        let data = [0x81, 0x04, 0x00, 0x00, 0x01, 0x01, 0x01, 0xff];
+        let mut emulator = decompress(&data, |emulator| unfilter(emulator, 1, Writes::Byte));
+        assert_eq!(output(&mut emulator, 4), &[1, 2, 3, 2]);
+
+        let data = [0x82, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00];
+        let mut emulator = decompress(&data, |emulator| unfilter(emulator, 2, Writes::HalfWord));
+        assert_eq!(output(&mut emulator, 4), &[0x00, 0x01, 0x01, 0x01]);
+    }
+}
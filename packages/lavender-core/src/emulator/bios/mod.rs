@@ -0,0 +1,246 @@
+//! High-level emulation of the BIOS. Rather than running the BIOS code, `swi`
+//! can hand the call straight to one of these functions, which leave the
+//! registers and memory just like the real BIOS would. Each function returns
+//! an estimate of how many cycles the BIOS would have taken.
+
+/// BgAffineSet and ObjAffineSet
+pub mod affine;
+/// Div, Sqrt, and ArcTan
+pub mod arithmetic;
+/// CpuSet, CpuFastSet, and BitUnPack
+pub mod copy;
+/// LZ77, Huffman, and run-length decompression, and the difference filters
+pub mod decompress;
+/// Resets, halting, and waiting for interrupts
+pub mod system;
+
+use super::cpu::RegisterNames::{self, *};
+use super::memory::{BIOS_OPCODE_AFTER_SWI, BIOS_SIZE};
+use super::Emulator;
+use decompress::Writes;
+use log::debug;
+use std::error::Error;
+use std::fmt;
+
+/// Settings and state for the BIOS.
+#[derive(Default)]
+pub struct Bios {
+    /// When set, `swi` calls are handled by these functions instead of by
+    /// the BIOS in memory.
+    pub hle: bool,
+    /// Set while IntrWait is halted, so that it knows it is continuing the
+    /// same wait when the `swi` is run again.
+    pub waiting_for_interrupt: bool,
+    /// Set once a real BIOS image has been loaded, replacing the small
+    /// built-in one.
+    pub loaded: bool,
+}
+
+/// The value returned by GetBiosChecksum on a Game Boy Advance.
+pub const BIOS_CHECKSUM: u32 = 0xbaae_187f;
+/// The CRC-32 of a good dump of the Game Boy Advance BIOS. The Nintendo DS
+/// has its own copy for running Game Boy Advance games, which differs by a
+/// single byte, but it isn't recognised yet.
+pub const BIOS_CRC32: u32 = 0x8197_7335;
+
+/// Reasons for refusing to load a BIOS image.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BiosError {
+    /// Every BIOS dump is exactly 16KB.
+    WrongSize(usize),
+    /// The image isn't one of the BIOSes that we know about. It's probably a
+    /// bad dump, or a BIOS for another system.
+    UnknownCrc32(u32),
+}
+
+impl fmt::Display for BiosError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BiosError::WrongSize(size) => write!(
+                f,
+                "BIOS is {} bytes, but should be exactly {} bytes",
+                size, BIOS_SIZE
+            ),
+            BiosError::UnknownCrc32(crc) => write!(
+                f,
+                "BIOS CRC-32 is {:#010x}, which doesn't match any known BIOS",
+                crc
+            ),
+        }
+    }
+}
+
+impl Error for BiosError {}
+
+/// The CRC-32 of some data, the same one that zip files and ROM databases
+/// use to identify dumps.
+pub fn crc32(data: &[u8]) -> u32 {
+    !update_crc32(!0, data)
+}
+
+/// Runs more data through the CRC register, without the final inversion.
+fn update_crc32(register: u32, data: &[u8]) -> u32 {
+    data.iter().fold(register, |register, byte| {
+        (0..8).fold(register ^ *byte as u32, |register, _| {
+            if register & 1 != 0 {
+                register >> 1 ^ 0xedb8_8320
+            } else {
+                register >> 1
+            }
+        })
+    })
+}
+
+/// Checks that an image is a good dump of the Game Boy Advance BIOS.
+pub fn verify(image: &[u8]) -> Result<(), BiosError> {
+    if image.len() != BIOS_SIZE {
+        return Err(BiosError::WrongSize(image.len()));
+    }
+
+    match crc32(image) {
+        BIOS_CRC32 => Ok(()),
+        crc => Err(BiosError::UnknownCrc32(crc)),
+    }
+}
+
+/// Makes an image that passes `verify` without being the real BIOS, which
+/// can't be included with the tests. The last word is picked to give the
+/// image the right CRC-32, by running the register backwards from it.
+#[cfg(test)]
+pub(crate) fn test_image() -> Vec<u8> {
+    let mut image = vec![0; BIOS_SIZE];
+    let start = update_crc32(!0, &image[..BIOS_SIZE - 4]);
+
+    let mut register = !BIOS_CRC32;
+    for _ in 0..4 {
+        let index = (0..=0xff)
+            .find(|index| update_crc32(0, &[*index]) >> 24 == register >> 24)
+            .unwrap();
+        register = (register ^ update_crc32(0, &[index])) << 8 | index as u32;
+    }
+    image[BIOS_SIZE - 4..].copy_from_slice(&(register ^ start).to_le_bytes());
+    image
+}
+
+/// Roughly what it costs to get in and out of the BIOS, on top of whatever the
+/// function itself does: the `swi` exception, the BIOS looking up the
+/// function in its table, and the return back to the game.
+pub const CALL_CYCLES: u32 = 24;
+
+/// Runs a BIOS function, returning the number of cycles it took. Functions
+/// that haven't been implemented return `None`, and the caller should run the
+/// real BIOS instead.
+pub fn call(emulator: &mut Emulator, function: u8) -> Option<u32> {
+    let cycles = match function {
+        0x00 => system::soft_reset(emulator),
+        0x01 => system::register_ram_reset(emulator),
+        0x02 => system::halt(emulator),
+        0x03 => system::stop(emulator),
+        0x04 => system::intr_wait(emulator),
+        0x05 => system::vblank_intr_wait(emulator),
+        0x06 => arithmetic::div(emulator),
+        0x07 => arithmetic::div_arm(emulator),
+        0x08 => arithmetic::sqrt(emulator),
+        0x09 => arithmetic::arc_tan(emulator),
+        0x0a => arithmetic::arc_tan2(emulator),
+        0x0b => copy::cpu_set(emulator),
+        0x0c => copy::cpu_fast_set(emulator),
+        0x0d => get_bios_checksum(emulator),
+        0x0e => affine::bg_affine_set(emulator),
+        0x0f => affine::obj_affine_set(emulator),
+        0x10 => copy::bit_unpack(emulator),
+        0x11 => decompress::lz77(emulator, Writes::Byte),
+        0x12 => decompress::lz77(emulator, Writes::HalfWord),
+        0x13 => decompress::huffman(emulator),
+        0x14 => decompress::run_length(emulator, Writes::Byte),
+        0x15 => decompress::run_length(emulator, Writes::HalfWord),
+        0x16 => decompress::unfilter(emulator, 1, Writes::Byte),
+        0x17 => decompress::unfilter(emulator, 1, Writes::HalfWord),
+        0x18 => decompress::unfilter(emulator, 2, Writes::HalfWord),
+        _ => {
+            debug!("BIOS function {:#04x} isn't emulated", function);
+            return None;
+        }
+    };
+
+    // Leave the BIOS looking like it just returned from the call, for games
+    // that read it to check that they're on real hardware
+    emulator.memory.last_bios_opcode = BIOS_OPCODE_AFTER_SWI;
+
+    Some(CALL_CYCLES + cycles)
+}
+
+fn get_bios_checksum(emulator: &mut Emulator) -> u32 {
+    set(emulator, r0, BIOS_CHECKSUM);
+    set(emulator, r1, 1);
+    set(emulator, r3, 0x4000);
+    // The BIOS actually calculates the sum, which takes quite a while
+    0x4000 / 4 * 6
+}
+
+/// Shorthands for the register accesses that every function needs.
+fn get(emulator: &Emulator, name: RegisterNames) -> u32 {
+    emulator.cpu.get_register_value(name)
+}
+
+fn set(emulator: &mut Emulator, name: RegisterNames, value: u32) {
+    emulator.cpu.set_register_value(name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn verify_images() {
+        let mut image = test_image();
+        assert_eq!(crc32(&image), BIOS_CRC32);
+        assert_eq!(verify(&image), Ok(()));
+
+        image[4] = 1;
+        assert_eq!(verify(&image), Err(BiosError::UnknownCrc32(crc32(&image))));
+        assert_eq!(verify(&image[..0x1000]), Err(BiosError::WrongSize(0x1000)));
+
+        // Having the right GetBiosChecksum isn't enough
+        let mut image = vec![0; BIOS_SIZE];
+        image[..4].copy_from_slice(&BIOS_CHECKSUM.to_le_bytes());
+        assert!(verify(&image).is_err());
+    }
+
+    #[test]
+    fn unimplemented_functions_fall_through() {
+        let mut emulator = Emulator::new();
+        assert_eq!(call(&mut emulator, 0xff), None);
+
+        assert!(call(&mut emulator, 0x0d).is_some());
+        assert_eq!(get(&emulator, r0), BIOS_CHECKSUM);
+    }
+
+    #[test]
+    fn swi_calls_into_hle() {
+        use crate::emulator::armv4t::{arm, thumb};
+
+        let mut emulator = Emulator::new();
+        emulator.bios.hle = true;
+        emulator.cpu.set_register_value(r15, 0x0800_0004);
+
+        // swi 0x060000 (Div)
+        set(&mut emulator, r0, 100);
+        set(&mut emulator, r1, 7);
+        arm::instructions::swi(&mut emulator, 0xef06_0000);
+        assert_eq!(get(&emulator, r0), 14);
+        assert_eq!(get(&emulator, r1), 2);
+        assert_eq!(get(&emulator, r15), 0x0800_0004);
+
+        // swi 0x08 (Sqrt)
+        set(&mut emulator, r0, 144);
+        thumb::instructions::swi(&mut emulator, 0xdf08);
+        assert_eq!(get(&emulator, r0), 12);
+    }
+}
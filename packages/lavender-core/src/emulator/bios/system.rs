@@ -0,0 +1,271 @@
+use super::{get, set};
+use crate::emulator::cpu::{OperationModes, RegisterNames::*};
+use crate::emulator::interrupt::INTERRUPT_MASTER_ENABLE;
+use crate::emulator::memory::*;
+use crate::emulator::Emulator;
+
+/// Interrupt handlers are expected to set the same bits here as they
+/// acknowledge in IF, so that IntrWait can tell which interrupts happened.
+pub const BIOS_INTERRUPT_FLAGS: u32 = 0x0300_7ff8;
+/// If this byte is non-zero, SoftReset starts the game from EWRAM instead of
+/// from the cartridge. Used by multiboot games.
+const RETURN_ADDRESS_FLAG: u32 = 0x0300_7ffa;
+
+/// Where the BIOS puts the stack for each mode.
+pub const USER_STACK: u32 = 0x0300_7f00;
+pub const IRQ_STACK: u32 = 0x0300_7fa0;
+pub const SUPERVISOR_STACK: u32 = 0x0300_7fe0;
+
+/// The IO register used to control the display.
+const DISPLAY_CONTROL: usize = 0x000;
+
+/// IO registers that the BIOS leaves set to something other than zero when it
+/// starts the game. POSTFLG tells the BIOS that the console has already
+/// booted, and the rest are the values they have after being reset: the
+/// display is blanked, the affine backgrounds are unscaled, the sound is
+/// centered, no keys are pressed, and the serial port is in general-purpose
+/// mode.
+const BOOT_IO: [(usize, u16); 9] = [
+    (DISPLAY_CONTROL, 0x0080),
+    (0x020, 0x0100), // BG2PA
+    (0x026, 0x0100), // BG2PD
+    (0x030, 0x0100), // BG3PA
+    (0x036, 0x0100), // BG3PD
+    (0x088, 0x0200), // SOUNDBIAS
+    (0x130, 0x03ff), // KEYINPUT
+    (0x134, 0x8000), // RCNT
+    (0x300, 0x0001), // POSTFLG
+];
+
+/// Clears the top of IWRAM and restarts the game, with all of the registers
+/// set the way the BIOS leaves them.
+pub fn soft_reset(emulator: &mut Emulator) -> u32 {
+    let from_ram = emulator.memory.read_byte(RETURN_ADDRESS_FLAG) != 0;
+
+    for address in (0x0300_7e00..0x0300_8000).step_by(4) {
+        emulator.memory.write_word(address, 0);
+    }
+
+    let start = if from_ram { EXT_START } else { ROM_START };
+    reset_registers(emulator, start as u32);
+
+    200
+}
+
+/// Skips the BIOS's startup animation, and starts the game with everything
+/// set up the way the BIOS would have left it.
+pub fn direct_boot(emulator: &mut Emulator) {
+    reset_registers(emulator, ROM_START as u32);
+
+    // Interrupts are left enabled in the CPU, but not in the IO registers
+    emulator.cpu.set_irq_disable(false);
+    emulator.cpu.set_fiq_disable(false);
+
+    // These go straight into the registers rather than being written, so
+    // POSTFLG doesn't write to HALTCNT next to it, and KEYINPUT can be set
+    for (offset, value) in BOOT_IO.iter() {
+        emulator.memory.io[*offset..*offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    emulator.memory.executing_bios = false;
+    emulator.memory.last_bios_opcode = BIOS_OPCODE_AFTER_STARTUP;
+}
+
+/// Clears the registers, sets up the stacks for each mode, and jumps to
+/// `start` in system mode.
+fn reset_registers(emulator: &mut Emulator, start: u32) {
+    let cpu = &mut emulator.cpu;
+    for mode in &[OperationModes::SVC, OperationModes::IRQ] {
+        cpu.set_register_value_in_operation_mode(r14, 0, *mode);
+        cpu.set_register_value_in_operation_mode(spsr, 0, *mode);
+    }
+    cpu.set_register_value_in_operation_mode(r13, SUPERVISOR_STACK, OperationModes::SVC);
+    cpu.set_register_value_in_operation_mode(r13, IRQ_STACK, OperationModes::IRQ);
+
+    cpu.set_operation_mode(OperationModes::SYS);
+    cpu.set_thumb_bit(false);
+    for register in &[r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, r11, r12, r14] {
+        cpu.set_register_value(*register, 0);
+    }
+    cpu.set_register_value(r13, USER_STACK);
+    cpu.set_register_value(r15, start);
+}
+
+/// Clears the areas of memory selected by the bits of r0.
+pub fn register_ram_reset(emulator: &mut Emulator) -> u32 {
+    let flags = get(emulator, r0);
+    let memory = &mut emulator.memory;
+    let mut cycles = 40;
+
+    // The display is always forced blank, even if nothing is cleared
+    memory.write_half_word((IO_START + DISPLAY_CONTROL) as u32, 0x0080);
+
+    let mut clear = |start: usize, length: usize| {
+        for address in (start..start + length).step_by(4) {
+            memory.write_word(address as u32, 0);
+        }
+        cycles += length as u32 / 4 * 2;
+    };
+
+    if flags & 1 << 0 != 0 {
+        clear(EXT_START, EXT_SIZE);
+    }
+    if flags & 1 << 1 != 0 {
+        // The top of IWRAM holds the stacks and the interrupt vector
+        clear(RAM_START, RAM_SIZE - 0x200);
+    }
+    if flags & 1 << 2 != 0 {
+        clear(PALETTE_START, PALETTE_SIZE);
+    }
+    if flags & 1 << 3 != 0 {
+        clear(VRAM_START, VRAM_SIZE);
+    }
+    if flags & 1 << 4 != 0 {
+        clear(OBJECT_ATTRIBUTE_START, OBJECT_ATTRIBUTE_SIZE);
+    }
+    if flags & 1 << 5 != 0 {
+        // Serial communication
+        clear(IO_START + 0x120, 0x40);
+    }
+    if flags & 1 << 6 != 0 {
+        // Sound
+        clear(IO_START + 0x060, 0x50);
+    }
+    if flags & 1 << 7 != 0 {
+        // Everything else, except for the display control register
+        clear(IO_START + 0x004, 0x05c);
+        clear(IO_START + 0x0b0, 0x070);
+        clear(IO_START + 0x200, 0x00c);
+    }
+
+    cycles
+}
+
+/// Stops the CPU until an interrupt is requested.
+pub fn halt(emulator: &mut Emulator) -> u32 {
+    emulator.cpu.halt = true;
+    8
+}
+
+/// Like Halt, but also turns off most of the hardware. The only way out is an
+/// interrupt from the keypad, the cartridge, or the serial port.
+pub fn stop(emulator: &mut Emulator) -> u32 {
+    emulator.cpu.halt = true;
+    emulator.cpu.stopped = true;
+    8
+}
+
+/// Halts until one of the interrupts in r1 has been handled. If r0 is set,
+/// interrupts that happened before the call are ignored.
+pub fn intr_wait(emulator: &mut Emulator) -> u32 {
+    let discard_old = get(emulator, r0) != 0;
+    let wanted = get(emulator, r1) as u16;
+    wait_for_interrupt(emulator, discard_old, wanted)
+}
+
+/// IntrWait for a VBlank interrupt, always discarding old ones.
+pub fn vblank_intr_wait(emulator: &mut Emulator) -> u32 {
+    set(emulator, r0, 1);
+    set(emulator, r1, 1);
+    wait_for_interrupt(emulator, true, 1)
+}
+
+fn wait_for_interrupt(emulator: &mut Emulator, discard_old: bool, wanted: u16) -> u32 {
+    // The BIOS turns on interrupts, in case the game forgot
+    emulator
+        .memory
+        .write_half_word((IO_START + INTERRUPT_MASTER_ENABLE) as u32, 1);
+
+    // When the `swi` runs again after halting, we're still in the same wait
+    let resuming = std::mem::replace(&mut emulator.bios.waiting_for_interrupt, false);
+
+    let mut flags = emulator.memory.read_half_word(BIOS_INTERRUPT_FLAGS);
+    if discard_old && !resuming {
+        flags &= !wanted;
+    }
+
+    if flags & wanted != 0 {
+        emulator
+            .memory
+            .write_half_word(BIOS_INTERRUPT_FLAGS, flags & !wanted);
+        return 12;
+    }
+
+    emulator.memory.write_half_word(BIOS_INTERRUPT_FLAGS, flags);
+
+    // Nothing yet, so halt and then run the `swi` again once an interrupt
+    // wakes us up. r15 is two instructions past the `swi`.
+    emulator.bios.waiting_for_interrupt = true;
+    let pc = get(emulator, r15);
+    set(emulator, r15, pc - 2 * emulator.cpu.instruction_size());
+    emulator.cpu.halt = true;
+
+    12
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_stacks() {
+        let mut emulator = Emulator::new();
+        emulator.memory.write_word(0x0300_7f00, 0xffff_ffff);
+        emulator.cpu.set_register_value(r4, 4);
+        soft_reset(&mut emulator);
+
+        assert_eq!(get(&emulator, r15), ROM_START as u32);
+        assert_eq!(get(&emulator, r13), USER_STACK);
+        assert_eq!(get(&emulator, r4), 0);
+        assert_eq!(
+            emulator
+                .cpu
+                .get_register_value_in_operation_mode(r13, OperationModes::IRQ),
+            IRQ_STACK
+        );
+        assert_eq!(emulator.memory.read_word(0x0300_7f00), 0);
+    }
+
+    #[test]
+    fn boot_directly() {
+        let mut emulator = Emulator::new();
+        direct_boot(&mut emulator);
+
+        let cpu = &emulator.cpu;
+        assert_eq!(get(&emulator, r15), 0x0800_0000);
+        assert_eq!(get(&emulator, cpsr), 0x1f);
+        assert_eq!(get(&emulator, r13), 0x0300_7f00);
+        for (mode, stack) in &[
+            (OperationModes::IRQ, 0x0300_7fa0),
+            (OperationModes::SVC, 0x0300_7fe0),
+        ] {
+            assert_eq!(cpu.get_register_value_in_operation_mode(r13, *mode), *stack);
+        }
+
+        let memory = &mut emulator.memory;
+        assert_eq!(memory.read_byte(0x0400_0300), 1);
+        assert_eq!(memory.read_half_word(0x0400_0020), 0x100);
+        assert_eq!(memory.read_word(0), BIOS_OPCODE_AFTER_STARTUP);
+    }
+
+    #[test]
+    fn wait_until_flagged() {
+        let mut emulator = Emulator::new();
+        emulator.cpu.halt = false;
+        emulator.cpu.set_register_value(r15, 0x0800_0108);
+
+        // An old VBlank is discarded, so we have to wait for another one
+        emulator.memory.write_half_word(BIOS_INTERRUPT_FLAGS, 1);
+        vblank_intr_wait(&mut emulator);
+        assert!(emulator.cpu.halt);
+        assert_eq!(get(&emulator, r15), 0x0800_0100);
+
+        // The handler flags a new one, and the swi runs again
+        emulator.cpu.halt = false;
+        emulator.memory.write_half_word(BIOS_INTERRUPT_FLAGS, 1);
+        emulator.cpu.set_register_value(r15, 0x0800_0108);
+        vblank_intr_wait(&mut emulator);
+        assert!(!emulator.cpu.halt);
+        assert_eq!(emulator.memory.read_half_word(BIOS_INTERRUPT_FLAGS), 0);
+    }
+}
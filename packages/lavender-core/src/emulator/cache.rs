@@ -0,0 +1,446 @@
+use super::armv4t::{arm, thumb};
+use super::memory::{
+    Memory, EEPROM_START, EXT_END, EXT_SIZE, EXT_START, RAM_END, RAM_SIZE, RAM_START, ROM_START,
+};
+use super::recompiler::{self, CompiledBlock, Instance, Runtime};
+use super::Emulator;
+use std::collections::hash_map::{Entry, HashMap};
+use std::rc::Rc;
+
+/// Writes are tracked in pages of this many bytes. Blocks never cross into
+/// another page, so a write only needs to throw away the blocks in its page.
+const PAGE_SIZE: usize = 256;
+const PAGE_COUNT: usize = (EXT_SIZE + RAM_SIZE) / PAGE_SIZE;
+
+/// Blocks are cut off after this many instructions, even without a branch.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// Blocks are recompiled once they've been started this many times.
+const HOT_BLOCK_ENTRIES: u32 = 16;
+
+/// An instruction that has already been decoded.
+#[derive(Copy, Clone)]
+pub enum Operation {
+    Arm(u32, arm::Handler),
+    Thumb(u16, thumb::Handler),
+}
+
+impl Operation {
+    /// Runs the instruction, and returns the number of cycles used.
+    pub fn run(self, emulator: &mut Emulator) -> u32 {
+        match self {
+            Operation::Arm(instruction, handler) => arm::run(emulator, instruction, handler),
+            Operation::Thumb(instruction, handler) => handler(emulator, instruction),
+        }
+    }
+}
+
+/// What the cache has for the next instruction. Blocks that have been
+/// recompiled are run all at once, from their first instruction.
+pub enum Cached {
+    Operation(Operation),
+    Block(Rc<dyn Instance>),
+}
+
+/// A run of instructions that are next to each other in memory, up to the
+/// first unconditional branch.
+pub struct Block {
+    address: u32,
+    thumb: bool,
+    operations: Rc<[Operation]>,
+    /// How many times the block has been started from the beginning.
+    entries: u32,
+    /// The recompiled block, once it's hot. Stays empty if it can't be
+    /// recompiled, or if there's no runtime to run it.
+    compiled: Option<CompiledBlock>,
+    instance: Option<Rc<dyn Instance>>,
+}
+
+/// The block that is running, and the index of its next operation.
+struct Current {
+    address: u32,
+    thumb: bool,
+    operations: Rc<[Operation]>,
+    next: usize,
+}
+
+impl Current {
+    /// Whether `address` is the next operation in this block.
+    fn is_next(&self, address: u32, thumb: bool) -> bool {
+        let size = if self.thumb { 2 } else { 4 };
+        let next = self.address + self.next as u32 * size;
+        thumb == self.thumb && address == next && self.next < self.operations.len()
+    }
+}
+
+/// Keeps track of which pages of work RAM have cached instructions in them,
+/// and which of those have been written to since. This lives in `Memory`, so
+/// that writes can be noticed without going through the emulator.
+pub struct CodePages {
+    cached: Vec<bool>,
+    written: Vec<usize>,
+}
+
+impl Default for CodePages {
+    fn default() -> Self {
+        Self {
+            cached: vec![false; PAGE_COUNT],
+            written: Vec::new(),
+        }
+    }
+}
+
+impl CodePages {
+    pub fn written_to(&mut self, address: u32) {
+        if let Some(page) = ram_page(address) {
+            if self.cached[page] {
+                self.cached[page] = false;
+                self.written.push(page);
+            }
+        }
+    }
+}
+
+/// The page of work RAM that an address is in, counting the external work RAM
+/// first.
+fn ram_page(address: u32) -> Option<usize> {
+    let i = address as usize;
+    match i {
+        EXT_START..=EXT_END => Some((i - EXT_START) / PAGE_SIZE),
+        RAM_START..=RAM_END => Some((EXT_SIZE + i - RAM_START) / PAGE_SIZE),
+        _ => None,
+    }
+}
+
+/// Whether the instruction at `address` can be cached. Only the ROM and work
+/// RAM are, since reading from the BIOS has side effects, and nothing should
+/// be running from anywhere else. Writes to the ROM are ignored, so it never
+/// needs to be invalidated.
+fn cacheable(memory: &Memory, address: u32, size: u32) -> bool {
+    let i = address as usize;
+    match i {
+        EXT_START..=EXT_END | RAM_START..=RAM_END => true,
+        // The last mirror has the EEPROM in it
+        _ if (ROM_START..EEPROM_START).contains(&i) => {
+            (i & 0x01ff_ffff) + size as usize <= memory.rom.len()
+        }
+        _ => false,
+    }
+}
+
+/// Instructions that always jump somewhere else, so there's no point decoding
+/// anything after them.
+fn ends_block(operation: Operation) -> bool {
+    match operation {
+        Operation::Arm(instruction, _) => {
+            let always = instruction >> 28 == 0xe;
+            let branch = instruction & 0x0e00_0000 == 0x0a00_0000;
+            let swi = instruction & 0x0f00_0000 == 0x0f00_0000;
+            let bx = instruction & 0x0fff_fff0 == 0x012f_ff10;
+            always && (branch || swi || bx)
+        }
+        Operation::Thumb(instruction, _) => {
+            let b = instruction & 0xf800 == 0xe000;
+            let bl = instruction & 0xf800 == 0xf800;
+            let bx = instruction & 0xff80 == 0x4700;
+            let swi = instruction & 0xff00 == 0xdf00;
+            let pop_pc = instruction & 0xff00 == 0xbd00;
+            b || bl || bx || swi || pop_pc
+        }
+    }
+}
+
+/// Decodes straight-line runs of instructions once, so that running them
+/// again doesn't need to fetch or decode anything.
+pub struct BlockCache {
+    /// Turning this off runs everything through the pipeline instead.
+    pub enabled: bool,
+    /// Keyed by address, with the bottom bit set for Thumb blocks.
+    blocks: HashMap<u32, Block>,
+    /// The blocks in each page of work RAM.
+    pages: HashMap<usize, Vec<u32>>,
+    current: Option<Current>,
+    /// Whether the last instruction came from the cache. The pipeline isn't
+    /// kept filled while running from the cache, so it needs to be refilled
+    /// when leaving it.
+    running: bool,
+    /// Runs hot blocks once they've been recompiled. Without one, everything
+    /// stays in the interpreter.
+    pub runtime: Option<Box<dyn Runtime>>,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            current: None,
+            running: false,
+            runtime: None,
+        }
+    }
+}
+
+impl BlockCache {
+    /// Throws away every block, for when the ROM changes.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.current = None;
+    }
+
+    /// Throws away the blocks in any pages that have been written to.
+    pub fn invalidate_written(&mut self, memory: &mut Memory) {
+        if memory.code_pages.written.is_empty() {
+            return;
+        }
+
+        for page in memory.code_pages.written.drain(..) {
+            for key in self.pages.remove(&page).unwrap_or_default() {
+                self.blocks.remove(&key);
+            }
+        }
+        self.current = None;
+    }
+
+    /// The operation at `address`, decoding a new block if it isn't cached
+    /// yet, or the whole block if it starts there and has been recompiled.
+    /// Returns nothing if the address can't be cached.
+    pub fn operation(&mut self, memory: &mut Memory, address: u32, thumb: bool) -> Option<Cached> {
+        if !self.enabled {
+            return None;
+        }
+
+        // Usually the next operation in the same block
+        if let Some(current) = &mut self.current {
+            if current.is_next(address, thumb) {
+                current.next += 1;
+                return Some(Cached::Operation(current.operations[current.next - 1]));
+            }
+        }
+
+        let size = if thumb { 2 } else { 4 };
+        if !cacheable(memory, address, size) {
+            return None;
+        }
+
+        let key = address | thumb as u32;
+        let block = match self.blocks.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                if let Some(page) = ram_page(address) {
+                    memory.code_pages.cached[page] = true;
+                    self.pages.entry(page).or_default().push(key);
+                }
+                entry.insert(decode_block(memory, address, thumb))
+            }
+        };
+
+        block.entries += 1;
+        if block.entries == HOT_BLOCK_ENTRIES {
+            if let Some(runtime) = &mut self.runtime {
+                block.compiled = recompile(block);
+                block.instance = block
+                    .compiled
+                    .as_ref()
+                    .and_then(|compiled| runtime.instantiate(compiled));
+            }
+        }
+
+        if let Some(instance) = &block.instance {
+            self.current = None;
+            return Some(Cached::Block(Rc::clone(instance)));
+        }
+
+        self.current = Some(Current {
+            address,
+            thumb,
+            operations: Rc::clone(&block.operations),
+            next: 1,
+        });
+        Some(Cached::Operation(block.operations[0]))
+    }
+
+    /// The recompiled version of the block at `address`, once it has been
+    /// started often enough to be worth compiling. The bottom bit of the
+    /// address is set for Thumb blocks, the same as in the keys. Returns
+    /// nothing until then, or if the block can't be recompiled or there's no
+    /// runtime, in which case the interpreter keeps running it.
+    pub fn hot_block(&self, address: u32) -> Option<&CompiledBlock> {
+        self.blocks.get(&address)?.compiled.as_ref()
+    }
+
+    /// Called when the next instruction couldn't come from the cache. Returns
+    /// true if the last one did.
+    fn leave(&mut self) -> bool {
+        self.current = None;
+        std::mem::replace(&mut self.running, false)
+    }
+}
+
+fn decode_block(memory: &mut Memory, address: u32, thumb: bool) -> Block {
+    let size = if thumb { 2 } else { 4 };
+    let page = address as usize / PAGE_SIZE;
+    let mut operations = Vec::new();
+
+    let mut next = address;
+    while operations.len() < MAX_BLOCK_LENGTH
+        && next as usize / PAGE_SIZE == page
+        && cacheable(memory, next, size)
+    {
+        let operation = if thumb {
+            let instruction = memory.read_half_word(next);
+            Operation::Thumb(
+                instruction,
+                thumb::DECODE_TABLE[thumb::decode_key(instruction)],
+            )
+        } else {
+            let instruction = memory.read_word(next);
+            Operation::Arm(instruction, arm::DECODE_TABLE[arm::decode_key(instruction)])
+        };
+
+        operations.push(operation);
+        if ends_block(operation) {
+            break;
+        }
+        next += size;
+    }
+
+    Block {
+        address,
+        thumb,
+        operations: operations.into(),
+        entries: 0,
+        compiled: None,
+        instance: None,
+    }
+}
+
+/// Translates a block that has become hot.
+fn recompile(block: &Block) -> Option<CompiledBlock> {
+    if block.thumb {
+        let instructions = block
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Thumb(instruction, _) => Some(*instruction),
+                Operation::Arm(..) => None,
+            })
+            .collect::<Option<Vec<u16>>>()?;
+        recompiler::compile_thumb(block.address, &instructions).ok()
+    } else {
+        let instructions = block
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Arm(instruction, _) => Some(*instruction),
+                Operation::Thumb(..) => None,
+            })
+            .collect::<Option<Vec<u32>>>()?;
+        recompiler::compile(block.address, &instructions).ok()
+    }
+}
+
+impl Emulator {
+    /// Finds the next instruction in the block cache. When it isn't there,
+    /// makes sure that the pipeline is ready to run it instead.
+    pub(super) fn cached_operation(&mut self) -> Option<Cached> {
+        let address = self.cpu.next_instruction_address() & !(self.cpu.instruction_size() - 1);
+        let thumb = self.cpu.get_thumb_bit();
+
+        self.cache.invalidate_written(&mut self.memory);
+        match self.cache.operation(&mut self.memory, address, thumb) {
+            Some(cached) => {
+                self.cache.running = true;
+                Some(cached)
+            }
+            None => {
+                if self.cache.leave() && !self.cpu.pipeline.flushed {
+                    self.cpu.registers.r15 = address;
+                    self.cpu.pipeline.flushed = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::RegisterNames::*;
+    use crate::emulator::TEST_CODE as CODE;
+
+    /// Counts up to 10 in r0, stores it at the address in r1, and then loops
+    /// forever.
+    const PROGRAM: [u32; 6] = [
+        0xe3a0_0000, // mov r0, #0
+        0xe280_0001, // add r0, r0, #1
+        0xe350_000a, // cmp r0, #10
+        0x1aff_fffc, // bne 0x04
+        0xe581_0000, // str r0, [r1]
+        0xeaff_fffe, // b 0x14
+    ];
+
+    fn running(program: &[u32], cache: bool) -> Emulator {
+        let mut emulator = Emulator::running(program);
+        emulator.cache.enabled = cache;
+        emulator.cpu.set_register_value(r1, CODE + 0x100);
+        emulator
+    }
+
+    #[test]
+    fn blocks_end_at_branches() {
+        let mut emulator = running(&PROGRAM, true);
+        emulator.step_instruction();
+
+        // The conditional branch doesn't end the first block
+        let block = &emulator.cache.blocks[&CODE];
+        assert_eq!(block.operations.len(), 6);
+
+        // Jumping into the middle of it starts a new one
+        for _ in 0..4 {
+            emulator.step_instruction();
+        }
+        assert_eq!(emulator.cache.blocks[&(CODE + 4)].operations.len(), 5);
+    }
+
+    #[test]
+    fn same_as_the_pipeline() {
+        let mut cached = running(&PROGRAM, true);
+        let mut uncached = running(&PROGRAM, false);
+        for _ in 0..60 {
+            cached.step_instruction();
+            uncached.step_instruction();
+        }
+
+        assert_eq!(cached.memory.read_word(CODE + 0x100), 10);
+        assert_eq!(uncached.memory.read_word(CODE + 0x100), 10);
+        assert_eq!(cached.cpu.registers.r15, uncached.cpu.registers.r15);
+        assert_eq!(cached.scheduler.now(), uncached.scheduler.now());
+        assert!(!cached.cache.blocks.is_empty());
+        assert!(uncached.cache.blocks.is_empty());
+    }
+
+    #[test]
+    fn writes_invalidate_blocks() {
+        // mov r0, #1
+        // b 0x00
+        let mut emulator = running(&[0xe3a0_0001, 0xeaff_fffd], true);
+        emulator.step_instruction();
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.get_register_value(r0), 1);
+
+        // mov r0, #2
+        emulator.memory.write_word(CODE, 0xe3a0_0002);
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.get_register_value(r0), 2);
+
+        // Writes to other pages leave it alone
+        emulator.step_instruction();
+        emulator.memory.write_word(CODE + PAGE_SIZE as u32, 0);
+        emulator.step_instruction();
+        assert!(emulator.cache.blocks.contains_key(&CODE));
+    }
+}
@@ -0,0 +1,219 @@
+use super::memory::{ROM_SIZE, ROM_START};
+use std::error::Error;
+use std::fmt;
+
+/// Every ROM begins with a 192-byte header describing the game.
+pub const HEADER_SIZE: usize = 0xc0;
+
+/// Offsets of each of the fields within the header.
+const ENTRY_POINT: usize = 0x00;
+const LOGO: usize = 0x04;
+const TITLE: usize = 0xa0;
+const GAME_CODE: usize = 0xac;
+const MAKER_CODE: usize = 0xb0;
+const FIXED_VALUE: usize = 0xb2;
+const UNIT_CODE: usize = 0xb3;
+const DEVICE_TYPE: usize = 0xb4;
+const VERSION: usize = 0xbc;
+const COMPLEMENT: usize = 0xbd;
+
+pub const LOGO_SIZE: usize = 156;
+
+/// The reasons that a ROM might not be accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CartridgeError {
+    /// The image isn't even big enough to hold a header.
+    TooSmall(usize),
+    /// The image is bigger than the 32MB that can be mapped into memory.
+    TooLarge(usize),
+    /// The complement check in the header doesn't match the rest of the
+    /// header. The BIOS refuses to boot these.
+    HeaderChecksum { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => write!(
+                f,
+                "ROM is {} bytes, which is too small to contain a header",
+                size
+            ),
+            CartridgeError::TooLarge(size) => write!(
+                f,
+                "ROM is {} bytes, but cartridges can be at most {} bytes",
+                size, ROM_SIZE
+            ),
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header complement check is {:#04x}, but should be {:#04x}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+/// The information in a ROM's header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// An ARM branch instruction which jumps past the header to the start of
+    /// the game.
+    pub entry_point: u32,
+    /// A compressed copy of the Nintendo logo, which the BIOS displays while
+    /// booting.
+    pub logo: Vec<u8>,
+    /// Up to 12 characters of uppercase ASCII.
+    pub title: String,
+    /// Four characters identifying the game. The last one is the region.
+    pub game_code: String,
+    /// Two characters identifying the publisher.
+    pub maker_code: String,
+    /// Should always be 0x96.
+    pub fixed_value: u8,
+    /// Which console the game is for. Always 0 for the Game Boy Advance.
+    pub unit_code: u8,
+    pub device_type: u8,
+    pub version: u8,
+    pub complement: u8,
+}
+
+impl Header {
+    /// Parses the header at the beginning of a ROM, which needs to be at
+    /// least `HEADER_SIZE` bytes long.
+    pub fn parse(rom: &[u8]) -> Self {
+        Self {
+            entry_point: u32::from_le_bytes([
+                rom[ENTRY_POINT],
+                rom[ENTRY_POINT + 1],
+                rom[ENTRY_POINT + 2],
+                rom[ENTRY_POINT + 3],
+            ]),
+            logo: rom[LOGO..LOGO + LOGO_SIZE].to_vec(),
+            title: ascii(&rom[TITLE..GAME_CODE]),
+            game_code: ascii(&rom[GAME_CODE..MAKER_CODE]),
+            maker_code: ascii(&rom[MAKER_CODE..FIXED_VALUE]),
+            fixed_value: rom[FIXED_VALUE],
+            unit_code: rom[UNIT_CODE],
+            device_type: rom[DEVICE_TYPE],
+            version: rom[VERSION],
+            complement: rom[COMPLEMENT],
+        }
+    }
+
+    /// The address that the entry point branches to, if it is a branch.
+    pub fn entry_address(&self) -> Option<u32> {
+        if self.entry_point & 0x0f00_0000 != 0x0a00_0000 {
+            return None;
+        }
+
+        // Sign extend the 24-bit offset, and then account for the pipeline
+        let offset = ((self.entry_point << 8) as i32 >> 6) as u32;
+        Some((ROM_START as u32 + 8).wrapping_add(offset))
+    }
+}
+
+/// Calculates the value that the complement check should have, which is the
+/// negated sum of the bytes from the title to the version, minus 0x19.
+pub fn header_complement(rom: &[u8]) -> u8 {
+    rom[TITLE..COMPLEMENT]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte))
+        .wrapping_sub(0x19)
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| *byte as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// A ROM image that has been checked, along with its parsed header.
+pub struct Cartridge {
+    pub header: Header,
+    pub rom: Vec<u8>,
+}
+
+impl Cartridge {
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_SIZE {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        if rom.len() > ROM_SIZE {
+            return Err(CartridgeError::TooLarge(rom.len()));
+        }
+
+        let header = Header::parse(rom);
+        let expected = header_complement(rom);
+        if header.complement != expected {
+            return Err(CartridgeError::HeaderChecksum {
+                expected,
+                actual: header.complement,
+            });
+        }
+
+        Ok(Self {
+            header,
+            rom: rom.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the smallest ROM that will pass the header checks.
+    fn test_rom(title: &[u8], game_code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; HEADER_SIZE];
+        // b 0x080000c0
+        rom[0..4].copy_from_slice(&0xea00_002e_u32.to_le_bytes());
+        rom[TITLE..TITLE + title.len()].copy_from_slice(title);
+        rom[GAME_CODE..GAME_CODE + 4].copy_from_slice(game_code);
+        rom[MAKER_CODE..MAKER_CODE + 2].copy_from_slice(b"01");
+        rom[FIXED_VALUE] = 0x96;
+        rom[COMPLEMENT] = header_complement(&rom);
+        rom
+    }
+
+    #[test]
+    fn parse_header() {
+        let cartridge = Cartridge::new(&test_rom(b"LAVENDER", b"ALVE")).unwrap();
+        let header = cartridge.header;
+
+        assert_eq!(header.title, "LAVENDER");
+        assert_eq!(header.game_code, "ALVE");
+        assert_eq!(header.maker_code, "01");
+        assert_eq!(header.fixed_value, 0x96);
+        assert_eq!(header.entry_address(), Some(0x0800_00c0));
+    }
+
+    #[test]
+    fn reject_bad_images() {
+        assert_eq!(
+            Cartridge::new(&[0; 16]).err(),
+            Some(CartridgeError::TooSmall(16))
+        );
+        assert_eq!(
+            Cartridge::new(&vec![0; ROM_SIZE + 1]).err(),
+            Some(CartridgeError::TooLarge(ROM_SIZE + 1))
+        );
+
+        let mut rom = test_rom(b"LAVENDER", b"ALVE");
+        let expected = rom[COMPLEMENT];
+        rom[COMPLEMENT] = expected.wrapping_add(1);
+        assert_eq!(
+            Cartridge::new(&rom).err(),
+            Some(CartridgeError::HeaderChecksum {
+                expected,
+                actual: expected.wrapping_add(1)
+            })
+        );
+    }
+}
@@ -4,8 +4,29 @@ use std::convert::TryFrom;
 /// The primary processor of the Game Boy Advance. This is the CPU used to run
 /// Game Boy Advance Games.
 pub struct Arm7Tdmi {
+    /// Set while the CPU is waiting for an interrupt, after HALTCNT has been
+    /// written to or the Halt BIOS function has been called.
     pub halt: bool,
+    /// Set along with `halt` in stop mode, where only the keypad, the
+    /// serial port, and the cartridge can wake the CPU back up.
+    pub stopped: bool,
     pub registers: Registers,
+    pub pipeline: Pipeline,
+}
+
+/// The ARM7TDMI fetches one instruction, decodes the one before it, and
+/// executes the one before that, all at the same time. r15 always holds the
+/// address being fetched, which is why it reads as 8 bytes past the executing
+/// instruction in ARM state, or 4 bytes in Thumb state.
+#[derive(Default)]
+pub struct Pipeline {
+    /// The two instructions that have been fetched but not executed yet,
+    /// with the next one to be executed first.
+    pub opcodes: [u32; 2],
+    /// Set whenever r15 is written to. The instructions that were fetched
+    /// after the old value are thrown away, and the pipeline is refilled from
+    /// the new one before anything else runs.
+    pub flushed: bool,
 }
 
 /// The secondary processor of the Game Boy Advance. It is the same one used in
@@ -16,8 +37,10 @@ pub struct LR35902;
 impl Arm7Tdmi {
     pub fn init() -> Self {
         let mut cpu = Self {
-            halt: true,
+            halt: false,
+            stopped: false,
             registers: Registers::new(),
+            pipeline: Pipeline::default(),
         };
 
         cpu.reset();
@@ -154,7 +177,10 @@ impl Arm7Tdmi {
             (r14, IRQ) => self.registers.r14_irq = value,
             (r14, UND) => self.registers.r14_und = value,
             (r14, _) => self.registers.r14 = value,
-            (r15, _) => self.registers.r15 = value,
+            (r15, _) => {
+                self.registers.r15 = value;
+                self.pipeline.flushed = true;
+            }
 
             // We might want to protect these from writes, but we also
             // might not need to.
@@ -265,7 +291,46 @@ impl Arm7Tdmi {
         true
     }
 
-    pub fn exception() {}
+    /// The size of an instruction in the current state.
+    pub fn instruction_size(&self) -> u32 {
+        if self.get_thumb_bit() {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// The address of the next instruction that will be executed. Between
+    /// instructions, r15 is two instructions ahead of this, unless it has
+    /// just been written to and the pipeline hasn't been refilled yet.
+    pub fn next_instruction_address(&self) -> u32 {
+        if self.pipeline.flushed {
+            self.registers.r15
+        } else {
+            self.registers.r15 - 2 * self.instruction_size()
+        }
+    }
+
+    /// Enters an exception handler. The current cpsr is saved in the spsr of
+    /// the exception's mode, and `return_address` in its r14, which the
+    /// handler uses to get back to where it was.
+    pub fn exception(&mut self, exception: Exception, return_address: u32) {
+        use RegisterNames::*;
+
+        let status = self.registers.cpsr;
+        let mode = exception.mode();
+        self.set_register_value_in_operation_mode(spsr, status, mode);
+        self.set_register_value_in_operation_mode(r14, return_address, mode);
+
+        self.set_operation_mode(mode);
+        self.set_thumb_bit(false);
+        self.set_irq_disable(true);
+        if exception == Exception::Reset || exception == Exception::FastInterrupt {
+            self.set_fiq_disable(true);
+        }
+
+        self.set_register_value(r15, exception.vector());
+    }
 
     pub fn check_condition(&self, cond: ConditionCodes) -> bool {
         use ConditionCodes::*;
@@ -285,7 +350,7 @@ impl Arm7Tdmi {
             GE => self.get_n() == self.get_v(),
             LT => self.get_n() != self.get_v(),
             GT => !self.get_z() && (self.get_n() == self.get_v()),
-            LE => self.get_z() && (self.get_n() != self.get_v()),
+            LE => self.get_z() || (self.get_n() != self.get_v()),
 
             AL => true,
             NO => true, // "Unpredictable behavior"
@@ -380,6 +445,43 @@ impl Registers {
     }
 }
 
+/// The exceptions that the processor can take. Each one switches to a
+/// privileged mode and jumps to its own vector at the start of the BIOS.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Exception {
+    Reset,
+    UndefinedInstruction,
+    SoftwareInterrupt,
+    PrefetchAbort,
+    DataAbort,
+    Interrupt,
+    FastInterrupt,
+}
+
+impl Exception {
+    pub fn vector(self) -> u32 {
+        match self {
+            Exception::Reset => 0x00,
+            Exception::UndefinedInstruction => 0x04,
+            Exception::SoftwareInterrupt => 0x08,
+            Exception::PrefetchAbort => 0x0c,
+            Exception::DataAbort => 0x10,
+            Exception::Interrupt => 0x18,
+            Exception::FastInterrupt => 0x1c,
+        }
+    }
+
+    pub fn mode(self) -> OperationModes {
+        match self {
+            Exception::Reset | Exception::SoftwareInterrupt => OperationModes::SVC,
+            Exception::UndefinedInstruction => OperationModes::UND,
+            Exception::PrefetchAbort | Exception::DataAbort => OperationModes::ABT,
+            Exception::Interrupt => OperationModes::IRQ,
+            Exception::FastInterrupt => OperationModes::FIQ,
+        }
+    }
+}
+
 /// All of the operation modes that are available to the processor. Using this
 /// enum ensures that we are always in a valid operation mode.
 #[derive(Copy, Clone, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
@@ -590,6 +692,10 @@ mod tests {
         // Z bit is set and C is not.
         assert!(cpu.check_condition(LS));
 
+        // N and V match, but LE passes on Z alone.
+        assert!(cpu.check_condition(LE));
+        assert!(!cpu.check_condition(GT));
+
         // Turn on the carry bit. CS should pass, CC should not.
         cpu.set_nzcv(false, false, true, false);
         assert!(cpu.check_condition(CS));
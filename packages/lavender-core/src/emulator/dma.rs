@@ -0,0 +1,297 @@
+use super::interrupt::{self, Interrupt};
+use super::memory::{Memory, IO_START};
+use super::save::Save;
+use super::scheduler::{Event, Request};
+
+/// Offsets of the registers for each of the four DMA channels, relative to the
+/// beginning of IO memory. Each channel has a source address, a destination
+/// address, a word count, and a control register, in that order.
+pub const DMA_REGISTERS: [usize; 4] = [0x0b0, 0x0bc, 0x0c8, 0x0d4];
+/// The offset of the control register within each channel's registers.
+pub const DMA_CONTROL: usize = 0x0a;
+/// How long a channel takes to start up once it's been enabled.
+pub const START_CYCLES: u64 = 2;
+
+/// Possible values of bits 12-13 of the control register, which decide when a
+/// channel starts transferring.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StartTiming {
+    Immediately,
+    VBlank,
+    HBlank,
+    /// Depends on the channel. Used for sound FIFOs and video capture.
+    Special,
+}
+
+impl StartTiming {
+    fn from_control(control: u16) -> Self {
+        match control >> 12 & 0b11 {
+            0b00 => StartTiming::Immediately,
+            0b01 => StartTiming::VBlank,
+            0b10 => StartTiming::HBlank,
+            _ => StartTiming::Special,
+        }
+    }
+}
+
+/// The registers a channel actually works from. The source, destination and
+/// count are copied in when the channel is enabled, so writing to the
+/// channel's registers while it's running only affects the next time it's
+/// enabled. The addresses carry on from where they got to each time a
+/// repeating transfer starts again.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Internal {
+    pub source: u32,
+    pub destination: u32,
+    pub count: u32,
+}
+
+/// Returns the channel whose control register contains the given IO offset.
+pub fn channel_for_control_offset(offset: usize) -> Option<usize> {
+    DMA_REGISTERS
+        .iter()
+        .position(|base| offset == base + DMA_CONTROL || offset == base + DMA_CONTROL + 1)
+}
+
+/// Called whenever the high byte of a channel's control register is written,
+/// with the value of the control register before the write. Enabling a
+/// channel copies its registers in, and transfers which should start
+/// immediately are scheduled to start once the channel is ready.
+pub fn control_written(memory: &mut Memory, channel: usize, previous_control: u16) {
+    let control = read_control(memory, channel);
+    let enabled = control >> 15 & 1 > 0;
+    let was_enabled = previous_control >> 15 & 1 > 0;
+    if !enabled || was_enabled {
+        return;
+    }
+
+    let base = DMA_REGISTERS[channel];
+    memory.dma_channels[channel] = Internal {
+        source: read_io_word(memory, base) & 0x0fff_ffff,
+        destination: read_io_word(memory, base + 4) & 0x0fff_ffff,
+        count: read_count(memory, channel),
+    };
+
+    if StartTiming::from_control(control) == StartTiming::Immediately {
+        memory
+            .requests
+            .push(Request::Schedule(Event::DmaStart(channel), START_CYCLES));
+    }
+}
+
+/// Starts every enabled channel that is waiting for the given timing.
+pub fn trigger(memory: &mut Memory, timing: StartTiming) {
+    for channel in 0..4 {
+        let control = read_control(memory, channel);
+        if control >> 15 & 1 > 0 && StartTiming::from_control(control) == timing {
+            transfer(memory, channel);
+        }
+    }
+}
+
+/// Copies data for the given channel as described by its internal registers.
+pub fn transfer(memory: &mut Memory, channel: usize) {
+    let Internal {
+        source,
+        destination,
+        count,
+    } = memory.dma_channels[channel];
+    let control = read_control(memory, channel);
+
+    let word_transfer = control >> 10 & 1 > 0;
+    let unit = if word_transfer { 4 } else { 2 };
+
+    let step = |adjustment: u16| -> u32 {
+        match adjustment {
+            // Increment (or increment and reload for destinations)
+            0b00 | 0b11 => unit,
+            // Decrement
+            0b01 => unit.wrapping_neg(),
+            // Fixed
+            _ => 0,
+        }
+    };
+    let destination_adjustment = control >> 5 & 0b11;
+    let destination_step = step(destination_adjustment);
+    let source_step = step(control >> 7 & 0b11);
+
+    // EEPROM requests are always sent by DMA, and the length of the transfer
+    // is the only way to tell which size of chip the game expects.
+    if memory.is_eeprom_address(destination) {
+        if let Save::Eeprom(eeprom) = &mut memory.save {
+            eeprom.detect_size(count);
+        }
+    }
+
+    let mut source_address = source & !(unit - 1);
+    let mut destination_address = destination & !(unit - 1);
+
+    for _ in 0..count {
+        if word_transfer {
+            let value = memory.read_word(source_address);
+            memory.write_word(destination_address, value);
+        } else {
+            let value = memory.read_half_word(source_address);
+            memory.write_half_word(destination_address, value);
+        }
+
+        source_address = source_address.wrapping_add(source_step);
+        destination_address = destination_address.wrapping_add(destination_step);
+    }
+
+    let internal = &mut memory.dma_channels[channel];
+    internal.source = source_address;
+    internal.destination = destination_address;
+
+    let repeat = control >> 9 & 1 > 0;
+    let timing = StartTiming::from_control(control);
+    if !repeat || timing == StartTiming::Immediately {
+        write_control(memory, channel, control & 0x7fff);
+    } else {
+        // Repeats start again with the full count, and with the original
+        // destination if it's set to reload
+        memory.dma_channels[channel].count = read_count(memory, channel);
+        if destination_adjustment == 0b11 {
+            let destination = read_io_word(memory, DMA_REGISTERS[channel] + 4) & 0x0fff_ffff;
+            memory.dma_channels[channel].destination = destination;
+        }
+    }
+
+    if control >> 14 & 1 > 0 {
+        let interrupt = [
+            Interrupt::Dma0,
+            Interrupt::Dma1,
+            Interrupt::Dma2,
+            Interrupt::Dma3,
+        ];
+        interrupt::request(memory, interrupt[channel]);
+    }
+}
+
+/// The number of units the channel's count register asks for. A count of zero
+/// means the largest possible transfer.
+fn read_count(memory: &Memory, channel: usize) -> u32 {
+    match (
+        read_io_half_word(memory, DMA_REGISTERS[channel] + 8),
+        channel,
+    ) {
+        (0, 3) => 0x1_0000,
+        (0, _) => 0x4000,
+        (count, _) => count as u32,
+    }
+}
+
+fn read_control(memory: &Memory, channel: usize) -> u16 {
+    read_io_half_word(memory, DMA_REGISTERS[channel] + DMA_CONTROL)
+}
+
+fn write_control(memory: &mut Memory, channel: usize, value: u16) {
+    let offset = DMA_REGISTERS[channel] + DMA_CONTROL;
+    memory.io[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_io_half_word(memory: &Memory, offset: usize) -> u16 {
+    u16::from_le_bytes([memory.io[offset], memory.io[offset + 1]])
+}
+
+fn read_io_word(memory: &Memory, offset: usize) -> u32 {
+    read_io_half_word(memory, offset) as u32 | (read_io_half_word(memory, offset + 2) as u32) << 16
+}
+
+/// The address of a channel register, for use in tests and debugging tools.
+pub fn register_address(channel: usize, offset: usize) -> u32 {
+    (IO_START + DMA_REGISTERS[channel] + offset) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::{EXT_START, RAM_START};
+    use crate::emulator::scheduler::{run_due_events, schedule_requests};
+    use crate::emulator::Emulator;
+
+    /// Lets the channels that have just been enabled start up.
+    fn start_up(emulator: &mut Emulator) {
+        schedule_requests(emulator);
+        emulator.scheduler.advance(START_CYCLES as u32);
+        run_due_events(emulator);
+    }
+
+    #[test]
+    fn immediate_half_word_transfer() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+
+        for index in 0..8 {
+            memory.write_half_word(RAM_START as u32 + index * 2, index as u16 + 1);
+        }
+
+        memory.write_word(register_address(3, 0), RAM_START as u32);
+        memory.write_word(register_address(3, 4), EXT_START as u32);
+        memory.write_half_word(register_address(3, 8), 8);
+        memory.write_half_word(register_address(3, DMA_CONTROL), 0x8000);
+        assert_eq!(memory.read_half_word(EXT_START as u32), 0);
+
+        start_up(&mut emulator);
+        let memory = &mut emulator.memory;
+
+        for index in 0..8 {
+            assert_eq!(
+                memory.read_half_word(EXT_START as u32 + index * 2),
+                index as u16 + 1
+            );
+        }
+
+        // Immediate transfers disable themselves once they're done
+        assert_eq!(memory.read_half_word(register_address(3, DMA_CONTROL)), 0);
+    }
+
+    #[test]
+    fn fixed_source_word_transfer() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+
+        memory.write_word(RAM_START as u32, 0xdead_beef);
+        memory.write_word(register_address(0, 0), RAM_START as u32);
+        memory.write_word(register_address(0, 4), EXT_START as u32);
+        memory.write_half_word(register_address(0, 8), 4);
+        // Enabled, 32-bit, fixed source, raise an interrupt
+        memory.write_half_word(register_address(0, DMA_CONTROL), 0xc500);
+
+        start_up(&mut emulator);
+        let memory = &mut emulator.memory;
+
+        for index in 0..4 {
+            assert_eq!(memory.read_word(EXT_START as u32 + index * 4), 0xdead_beef);
+        }
+        assert_eq!(memory.io[interrupt::INTERRUPT_REQUEST_FLAGS + 1] & 1, 1);
+    }
+
+    #[test]
+    fn repeats_carry_on_from_the_internal_registers() {
+        let mut memory = Memory::init();
+        for index in 0..4 {
+            memory.write_half_word(RAM_START as u32 + index * 2, index as u16 + 1);
+        }
+        memory.write_word(register_address(1, 0), RAM_START as u32);
+        memory.write_word(register_address(1, 4), EXT_START as u32);
+        memory.write_half_word(register_address(1, 8), 2);
+        // Enabled, on HBlank, repeating, increment and reload the destination
+        memory.write_half_word(register_address(1, DMA_CONTROL), 0xa260);
+
+        // Changing the registers now only matters the next time it's enabled
+        memory.write_word(register_address(1, 0), RAM_START as u32 + 0x100);
+        memory.write_half_word(register_address(1, 8), 1);
+
+        trigger(&mut memory, StartTiming::HBlank);
+        assert_eq!(memory.read_half_word(EXT_START as u32), 1);
+        assert_eq!(memory.read_half_word(EXT_START as u32 + 2), 2);
+
+        // The source carries on, the destination starts again, and the count
+        // is reloaded from the register
+        trigger(&mut memory, StartTiming::HBlank);
+        assert_eq!(memory.read_half_word(EXT_START as u32), 3);
+        assert_eq!(memory.read_half_word(EXT_START as u32 + 2), 2);
+        assert_eq!(read_control(&memory, 1) >> 15, 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! The emulator doesn't know anything about screens, speakers, or keyboards.
+//! Whatever is running it plugs in a sink for the frames and one for the
+//! sound, along with a source for the buttons, and the emulator hands things
+//! over once per frame, at the start of VBlank.
+
+use super::interrupt::{self, Interrupt};
+use super::memory::Memory;
+use super::scheduler::{Event, Scheduler};
+use super::Emulator;
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+/// The keypad registers, relative to the beginning of IO memory. KEYINPUT is
+/// active low, so a button that is held down reads as 0.
+pub const KEY_INPUT: usize = 0x130;
+pub const KEY_CONTROL: usize = 0x132;
+
+/// The bit for each button, in KEYINPUT and KEYCNT as well as in the values
+/// returned by an `InputSource`.
+pub const BUTTON_A: u16 = 1 << 0;
+pub const BUTTON_B: u16 = 1 << 1;
+pub const BUTTON_SELECT: u16 = 1 << 2;
+pub const BUTTON_START: u16 = 1 << 3;
+pub const BUTTON_RIGHT: u16 = 1 << 4;
+pub const BUTTON_LEFT: u16 = 1 << 5;
+pub const BUTTON_UP: u16 = 1 << 6;
+pub const BUTTON_DOWN: u16 = 1 << 7;
+pub const BUTTON_R: u16 = 1 << 8;
+pub const BUTTON_L: u16 = 1 << 9;
+const ALL_BUTTONS: u16 = 0x3ff;
+
+/// Bits 14 and 15 of KEYCNT. The interrupt fires either when any of the
+/// selected buttons are held, or only when all of them are.
+const KEY_INTERRUPT: u16 = 1 << 14;
+const KEY_INTERRUPT_ALL: u16 = 1 << 15;
+
+/// The sound hardware produces a sample at 32768Hz, which is once every 512
+/// cycles.
+pub const SAMPLE_CYCLES: u64 = 512;
+
+/// Everything the display needs to draw a completed frame. The emulator
+/// doesn't draw anything itself, so this is the video memory as it was at the
+/// start of VBlank.
+pub struct Frame<'a> {
+    pub io: &'a [u8],
+    pub palette: &'a [u8],
+    pub vram: &'a [u8],
+    pub object: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    fn new(memory: &'a Memory) -> Self {
+        Self {
+            io: &memory.io,
+            palette: &memory.palette,
+            vram: &memory.vram,
+            object: &memory.object,
+        }
+    }
+}
+
+/// One sample of stereo sound.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub left: i16,
+    pub right: i16,
+}
+
+/// Somewhere to show each frame once it's finished.
+pub trait VideoSink {
+    fn frame(&mut self, frame: &Frame);
+}
+
+/// Somewhere to play the sound. It gets every sample produced during the last
+/// frame, in order.
+pub trait AudioSink {
+    fn samples(&mut self, samples: &[Sample]);
+}
+
+/// Somewhere to find out which buttons are being held.
+pub trait InputSource {
+    /// The buttons that are held down right now, using the `BUTTON_` bits.
+    fn buttons(&mut self) -> u16;
+}
+
+/// Throws away everything it's given, and never presses any buttons. This is
+/// what an emulator starts out with, until something else is plugged in.
+pub struct Disconnected;
+
+impl VideoSink for Disconnected {
+    fn frame(&mut self, _frame: &Frame) {}
+}
+
+impl AudioSink for Disconnected {
+    fn samples(&mut self, _samples: &[Sample]) {}
+}
+
+impl InputSource for Disconnected {
+    fn buttons(&mut self) -> u16 {
+        0
+    }
+}
+
+/// Where the emulator sends its output, and gets its input from.
+pub struct Frontend {
+    pub video: Box<dyn VideoSink>,
+    pub audio: Box<dyn AudioSink>,
+    pub input: Box<dyn InputSource>,
+    /// The samples produced since the last VBlank.
+    samples: Vec<Sample>,
+}
+
+impl Default for Frontend {
+    fn default() -> Self {
+        Self::new(
+            Box::new(Disconnected),
+            Box::new(Disconnected),
+            Box::new(Disconnected),
+        )
+    }
+}
+
+impl Frontend {
+    pub fn new(
+        video: Box<dyn VideoSink>,
+        audio: Box<dyn AudioSink>,
+        input: Box<dyn InputSource>,
+    ) -> Self {
+        Self {
+            video,
+            audio,
+            input,
+            samples: Vec::new(),
+        }
+    }
+}
+
+/// Keeps everything the emulator produces in memory, and holds down whichever
+/// buttons it's told to. Clones all share the same recording, so a test can
+/// plug one into the emulator and keep another to look at.
+#[derive(Clone, Default)]
+pub struct Headless(Rc<RefCell<Recording>>);
+
+#[derive(Default)]
+pub struct Recording {
+    /// The number of frames that have been completed.
+    pub frames: u32,
+    /// Copies of the video memory from the most recent frame.
+    pub palette: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub object: Vec<u8>,
+    /// Every sample that has been played.
+    pub samples: Vec<Sample>,
+    /// The buttons being held down.
+    pub buttons: u16,
+}
+
+impl Headless {
+    /// A frontend that sends everything to this recording.
+    pub fn frontend(&self) -> Frontend {
+        Frontend::new(
+            Box::new(self.clone()),
+            Box::new(self.clone()),
+            Box::new(self.clone()),
+        )
+    }
+
+    pub fn recording(&self) -> Ref<'_, Recording> {
+        self.0.borrow()
+    }
+
+    /// Holds down exactly the given buttons, from the next frame onwards.
+    pub fn press(&self, buttons: u16) {
+        self.0.borrow_mut().buttons = buttons;
+    }
+}
+
+impl VideoSink for Headless {
+    fn frame(&mut self, frame: &Frame) {
+        let mut recording = self.0.borrow_mut();
+        recording.frames += 1;
+        recording.palette = frame.palette.to_vec();
+        recording.vram = frame.vram.to_vec();
+        recording.object = frame.object.to_vec();
+    }
+}
+
+impl AudioSink for Headless {
+    fn samples(&mut self, samples: &[Sample]) {
+        self.0.borrow_mut().samples.extend_from_slice(samples);
+    }
+}
+
+impl InputSource for Headless {
+    fn buttons(&mut self) -> u16 {
+        self.0.borrow().buttons
+    }
+}
+
+/// Starts producing samples, one every `SAMPLE_CYCLES`.
+pub fn start(scheduler: &mut Scheduler) {
+    scheduler.schedule(Event::AudioSample, SAMPLE_CYCLES);
+}
+
+/// Adds the next sample to the ones waiting for the end of the frame. Until
+/// the sound channels are emulated all there is to play is silence, but
+/// there's still the right amount of it, so that the sink can keep time.
+pub fn sample(emulator: &mut Emulator, time: u64) {
+    emulator.frontend.samples.push(Sample::default());
+    emulator
+        .scheduler
+        .schedule_at(Event::AudioSample, time + SAMPLE_CYCLES);
+}
+
+/// Hands the finished frame and its sound to the frontend, and finds out
+/// which buttons will be held during the next one.
+pub fn vblank(emulator: &mut Emulator) {
+    let frontend = &mut emulator.frontend;
+    frontend.video.frame(&Frame::new(&emulator.memory));
+    frontend.audio.samples(&frontend.samples);
+    frontend.samples.clear();
+
+    let buttons = frontend.input.buttons() & ALL_BUTTONS;
+    update_keypad(&mut emulator.memory, buttons);
+}
+
+/// Writes the held buttons to KEYINPUT, and requests an interrupt if the game
+/// asked for one when they're pressed.
+fn update_keypad(memory: &mut Memory, buttons: u16) {
+    memory.io[KEY_INPUT..KEY_INPUT + 2].copy_from_slice(&(!buttons & ALL_BUTTONS).to_le_bytes());
+
+    let control = u16::from_le_bytes([memory.io[KEY_CONTROL], memory.io[KEY_CONTROL + 1]]);
+    let selected = control & ALL_BUTTONS;
+    let triggered = if control & KEY_INTERRUPT_ALL != 0 {
+        selected != 0 && buttons & selected == selected
+    } else {
+        buttons & selected != 0
+    };
+
+    if control & KEY_INTERRUPT != 0 && triggered {
+        interrupt::request(memory, Interrupt::Keypad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::interrupt::INTERRUPT_REQUEST_FLAGS;
+    use crate::emulator::scheduler::run_due_events;
+    use crate::emulator::video::{FRAME_CYCLES, LINE_CYCLES, VISIBLE_LINES};
+
+    const VBLANK_CYCLES: u64 = LINE_CYCLES * VISIBLE_LINES as u64;
+
+    fn headless() -> (Emulator, Headless) {
+        let mut emulator = Emulator::new();
+        let headless = Headless::default();
+        emulator.frontend = headless.frontend();
+        (emulator, headless)
+    }
+
+    fn run_for(emulator: &mut Emulator, cycles: u64) {
+        emulator.scheduler.advance(cycles as u32);
+        run_due_events(emulator);
+    }
+
+    #[test]
+    fn one_frame_per_vblank() {
+        let (mut emulator, headless) = headless();
+        emulator.memory.vram[0] = 0x1f;
+
+        // Samples wait until the end of the frame
+        run_for(&mut emulator, VBLANK_CYCLES - 1);
+        assert_eq!(headless.recording().frames, 0);
+        assert!(headless.recording().samples.is_empty());
+        assert_eq!(
+            emulator.frontend.samples.len() as u64,
+            VBLANK_CYCLES / SAMPLE_CYCLES - 1
+        );
+
+        run_for(&mut emulator, 1);
+        let recording = headless.recording();
+        assert_eq!(recording.frames, 1);
+        assert_eq!(recording.vram[0], 0x1f);
+        assert_eq!(
+            recording.samples.len() as u64,
+            VBLANK_CYCLES / SAMPLE_CYCLES
+        );
+    }
+
+    #[test]
+    fn samples_keep_time() {
+        let (mut emulator, headless) = headless();
+
+        for _ in 0..10 {
+            run_for(&mut emulator, FRAME_CYCLES);
+        }
+
+        let recording = headless.recording();
+        assert_eq!(recording.frames, 10);
+        assert_eq!(
+            recording.samples.len() as u64,
+            (VBLANK_CYCLES + 9 * FRAME_CYCLES) / SAMPLE_CYCLES
+        );
+    }
+
+    #[test]
+    fn buttons_are_active_low() {
+        let (mut emulator, headless) = headless();
+        headless.press(BUTTON_A | BUTTON_START);
+
+        run_for(&mut emulator, VBLANK_CYCLES);
+        assert_eq!(
+            emulator.memory.io[KEY_INPUT..KEY_INPUT + 2],
+            0x3f6_u16.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn keypad_interrupt() {
+        let mut memory = Memory::init();
+        let control = |memory: &mut Memory, value: u16| {
+            memory.io[KEY_CONTROL..KEY_CONTROL + 2].copy_from_slice(&value.to_le_bytes());
+            memory.io[INTERRUPT_REQUEST_FLAGS + 1] = 0;
+        };
+        let requested = |memory: &Memory| memory.io[INTERRUPT_REQUEST_FLAGS + 1] & 1 << 4 != 0;
+
+        // Any of A or B
+        control(&mut memory, KEY_INTERRUPT | BUTTON_A | BUTTON_B);
+        update_keypad(&mut memory, BUTTON_B);
+        assert!(requested(&memory));
+
+        // Both A and B
+        control(
+            &mut memory,
+            KEY_INTERRUPT_ALL | KEY_INTERRUPT | BUTTON_A | BUTTON_B,
+        );
+        update_keypad(&mut memory, BUTTON_B);
+        assert!(!requested(&memory));
+        update_keypad(&mut memory, BUTTON_A | BUTTON_B | BUTTON_L);
+        assert!(requested(&memory));
+
+        // Disabled
+        control(&mut memory, BUTTON_A);
+        update_keypad(&mut memory, BUTTON_A);
+        assert!(!requested(&memory));
+    }
+}
@@ -0,0 +1,65 @@
+use super::GpioDevice;
+
+/// The pins that the gyro sensor is connected to.
+const SAMPLE: u8 = 1 << 0;
+const CLOCK: u8 = 1 << 1;
+const DATA: u8 = 1 << 2;
+
+/// The value the sensor reports while it isn't being turned.
+pub const GYRO_CENTER: i32 = 0x6c0;
+
+/// The gyro sensor from WarioWare: Twisted!, which measures how quickly the
+/// console is being rotated. Setting the sample pin takes a new 12-bit
+/// reading, which is shifted out one bit at a time on the falling edge of
+/// the clock pin, most significant bit first.
+#[derive(Default)]
+pub struct GyroSensor {
+    /// How quickly the console is turning, with positive values being
+    /// clockwise. The sensor's range is roughly ±0x6c0.
+    pub rate: i16,
+    sample: u16,
+    output: u8,
+    pins: u8,
+}
+
+impl GpioDevice for GyroSensor {
+    fn read_pins(&self) -> u8 {
+        self.output
+    }
+
+    fn write_pins(&mut self, pins: u8, _now: i64) {
+        let previous = std::mem::replace(&mut self.pins, pins);
+
+        if pins & SAMPLE != 0 {
+            self.sample = (GYRO_CENTER + self.rate as i32).clamp(0, 0xfff) as u16;
+        }
+
+        if previous & CLOCK != 0 && pins & CLOCK == 0 {
+            self.output = if self.sample & 0x8000 != 0 { DATA } else { 0 };
+            self.sample <<= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_out_sample() {
+        let mut gyro = GyroSensor {
+            rate: 0x100,
+            ..GyroSensor::default()
+        };
+
+        gyro.write_pins(SAMPLE | CLOCK, 0);
+        let mut value = 0;
+        for _ in 0..16 {
+            gyro.write_pins(0, 0);
+            value = value << 1 | (gyro.read_pins() >> 2) as u16;
+            gyro.write_pins(CLOCK, 0);
+        }
+
+        assert_eq!(value, 0x7c0);
+    }
+}
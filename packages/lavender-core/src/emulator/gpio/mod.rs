@@ -0,0 +1,352 @@
+//! Some cartridges have extra hardware wired to a 4-bit general purpose IO
+//! port, which is controlled by three registers that sit on top of the ROM
+//! just after the header.
+
+/// The gyro sensor from WarioWare: Twisted!
+pub mod gyro;
+/// The real-time clock used by games which keep track of the time of day.
+pub mod rtc;
+/// The rumble motor used by a few games.
+pub mod rumble;
+/// The light sensor from the Boktai games.
+pub mod solar;
+
+pub use gyro::GyroSensor;
+pub use rtc::{Clock, FixedClock, Rtc, SystemClock};
+pub use rumble::Rumble;
+pub use solar::SolarSensor;
+
+use super::save::contains_library_string;
+
+/// Offsets of the GPIO registers from the beginning of the ROM.
+pub const GPIO_DATA: usize = 0xc4;
+pub const GPIO_DIRECTION: usize = 0xc6;
+pub const GPIO_CONTROL: usize = 0xc8;
+pub const GPIO_START: usize = GPIO_DATA;
+pub const GPIO_END: usize = GPIO_CONTROL + 1;
+
+/// The offset of the game code within the ROM header.
+const GAME_CODE: usize = 0xac;
+
+/// Anything that can be connected to the GPIO port. Devices only see the pins
+/// that the game has set as outputs, and the game only sees the pins that a
+/// device drives when they are set as inputs.
+pub trait GpioDevice {
+    /// The pins that the device is currently driving.
+    fn read_pins(&self) -> u8;
+    /// Called whenever the game writes to the data register, with the
+    /// current time in case the device needs it.
+    fn write_pins(&mut self, pins: u8, now: i64);
+}
+
+/// Which extra hardware a cartridge has.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Devices {
+    pub rtc: bool,
+    pub solar: bool,
+    pub gyro: bool,
+    pub rumble: bool,
+    /// The tilt sensor isn't connected to the GPIO port, but it's found the
+    /// same way as everything else.
+    pub tilt: bool,
+}
+
+impl Devices {
+    const NONE: Devices = Devices {
+        rtc: false,
+        solar: false,
+        gyro: false,
+        rumble: false,
+        tilt: false,
+    };
+
+    /// The games we know have extra hardware, by the first three characters
+    /// of their game code. The last character is the region, which doesn't
+    /// change anything.
+    const KNOWN_GAMES: [(&'static [u8; 3], Devices); 13] = [
+        // Pokémon Ruby, Sapphire, and Emerald
+        (
+            b"AXV",
+            Devices {
+                rtc: true,
+                ..Devices::NONE
+            },
+        ),
+        (
+            b"AXP",
+            Devices {
+                rtc: true,
+                ..Devices::NONE
+            },
+        ),
+        (
+            b"BPE",
+            Devices {
+                rtc: true,
+                ..Devices::NONE
+            },
+        ),
+        // Rockman EXE 4.5
+        (
+            b"BR4",
+            Devices {
+                rtc: true,
+                ..Devices::NONE
+            },
+        ),
+        // Sennen Kazoku
+        (
+            b"BKA",
+            Devices {
+                rtc: true,
+                ..Devices::NONE
+            },
+        ),
+        // Boktai 1, 2, and 3
+        (
+            b"U3I",
+            Devices {
+                rtc: true,
+                solar: true,
+                ..Devices::NONE
+            },
+        ),
+        (
+            b"U32",
+            Devices {
+                rtc: true,
+                solar: true,
+                ..Devices::NONE
+            },
+        ),
+        (
+            b"U33",
+            Devices {
+                rtc: true,
+                solar: true,
+                ..Devices::NONE
+            },
+        ),
+        // WarioWare: Twisted!
+        (
+            b"RZW",
+            Devices {
+                gyro: true,
+                rumble: true,
+                ..Devices::NONE
+            },
+        ),
+        // Drill Dozer
+        (
+            b"V49",
+            Devices {
+                rumble: true,
+                ..Devices::NONE
+            },
+        ),
+        // Yoshi Topsy-Turvy (and Yoshi's Universal Gravitation)
+        (
+            b"KYG",
+            Devices {
+                tilt: true,
+                ..Devices::NONE
+            },
+        ),
+        // Koro Koro Puzzle Happy Panechu!
+        (
+            b"KHP",
+            Devices {
+                tilt: true,
+                ..Devices::NONE
+            },
+        ),
+        // Pokémon Pinball: Ruby & Sapphire
+        (
+            b"BPP",
+            Devices {
+                rumble: true,
+                ..Devices::NONE
+            },
+        ),
+    ];
+
+    /// Figures out which hardware a cartridge has from its game code. Games
+    /// that aren't in the list are still given a clock if they were built
+    /// with the RTC library.
+    pub fn detect(rom: &[u8]) -> Devices {
+        let known = rom.get(GAME_CODE..GAME_CODE + 3).and_then(|code| {
+            Self::KNOWN_GAMES
+                .iter()
+                .find(|(game, _)| &game[..] == code)
+                .map(|(_, devices)| *devices)
+        });
+
+        known.unwrap_or(Devices {
+            rtc: contains_library_string(rom, b"SIIRTC_V"),
+            ..Devices::NONE
+        })
+    }
+}
+
+pub struct Gpio {
+    /// The values the game has written to the data register.
+    data: u8,
+    /// Each bit decides whether the matching pin is an output (1) or an
+    /// input (0) from the game's point of view.
+    direction: u8,
+    /// Unless this is set, reads from the registers see the ROM instead.
+    pub readable: bool,
+    /// Where the RTC gets the time from.
+    pub clock: Box<dyn Clock>,
+    pub rtc: Option<Rtc>,
+    pub solar: Option<SolarSensor>,
+    pub gyro: Option<GyroSensor>,
+    pub rumble: Option<Rumble>,
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Self {
+            data: 0,
+            direction: 0,
+            readable: false,
+            clock: Box::new(SystemClock),
+            rtc: None,
+            solar: None,
+            gyro: None,
+            rumble: None,
+        }
+    }
+}
+
+impl Gpio {
+    /// Connects the given devices, throwing away the state of any that were
+    /// already connected. The clock is kept.
+    pub fn connect(&mut self, devices: Devices) {
+        self.data = 0;
+        self.direction = 0;
+        self.readable = false;
+        self.rtc = if devices.rtc {
+            Some(Rtc::default())
+        } else {
+            None
+        };
+        self.solar = if devices.solar {
+            Some(SolarSensor::default())
+        } else {
+            None
+        };
+        self.gyro = if devices.gyro {
+            Some(GyroSensor::default())
+        } else {
+            None
+        };
+        self.rumble = if devices.rumble {
+            Some(Rumble::default())
+        } else {
+            None
+        };
+    }
+
+    /// Cartridges without any devices don't have a GPIO port at all, and the
+    /// registers are just part of the ROM.
+    pub fn is_connected(&self) -> bool {
+        self.devices().next().is_some()
+    }
+
+    fn devices(&self) -> impl Iterator<Item = &dyn GpioDevice> {
+        let rtc = self.rtc.as_ref().map(|device| device as &dyn GpioDevice);
+        let solar = self.solar.as_ref().map(|device| device as &dyn GpioDevice);
+        let gyro = self.gyro.as_ref().map(|device| device as &dyn GpioDevice);
+        let rumble = self.rumble.as_ref().map(|device| device as &dyn GpioDevice);
+
+        rtc.into_iter().chain(solar).chain(gyro).chain(rumble)
+    }
+
+    fn devices_mut(&mut self) -> impl Iterator<Item = &mut dyn GpioDevice> {
+        let rtc = self
+            .rtc
+            .as_mut()
+            .map(|device| device as &mut dyn GpioDevice);
+        let solar = self
+            .solar
+            .as_mut()
+            .map(|device| device as &mut dyn GpioDevice);
+        let gyro = self
+            .gyro
+            .as_mut()
+            .map(|device| device as &mut dyn GpioDevice);
+        let rumble = self
+            .rumble
+            .as_mut()
+            .map(|device| device as &mut dyn GpioDevice);
+
+        rtc.into_iter().chain(solar).chain(gyro).chain(rumble)
+    }
+
+    /// Reads a byte from the registers, with the offset relative to the
+    /// beginning of the ROM.
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        match offset {
+            GPIO_DATA => {
+                let inputs = self
+                    .devices()
+                    .fold(0, |pins, device| pins | device.read_pins());
+                (self.data & self.direction | inputs & !self.direction) & 0xf
+            }
+            GPIO_DIRECTION => self.direction,
+            GPIO_CONTROL => self.readable as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write_byte(&mut self, offset: usize, value: u8) {
+        match offset {
+            GPIO_DATA => {
+                self.data = value & 0xf;
+                let pins = self.data & self.direction;
+                let now = self.clock.now();
+                for device in self.devices_mut() {
+                    device.write_pins(pins, now);
+                }
+            }
+            GPIO_DIRECTION => self.direction = value & 0xf,
+            GPIO_CONTROL => self.readable = value & 1 > 0,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_by_game_code() {
+        let mut rom = vec![0; 0x200];
+        assert_eq!(Devices::detect(&rom), Devices::default());
+
+        rom[0x100..0x108].copy_from_slice(b"SIIRTC_V");
+        assert!(Devices::detect(&rom).rtc);
+
+        rom[GAME_CODE..GAME_CODE + 4].copy_from_slice(b"RZWE");
+        let devices = Devices::detect(&rom);
+        assert!(devices.gyro && devices.rumble && !devices.rtc);
+    }
+
+    #[test]
+    fn devices_share_the_port() {
+        let mut gpio = Gpio::default();
+        gpio.connect(Devices {
+            rtc: true,
+            solar: true,
+            ..Devices::default()
+        });
+        gpio.solar.as_mut().unwrap().level = 255;
+
+        // Clock, reset, and chip select are outputs, and the flag is an input
+        gpio.write_byte(GPIO_DIRECTION, 0b0111);
+        gpio.write_byte(GPIO_DATA, 0b0010);
+        assert_eq!(gpio.read_byte(GPIO_DATA), 0b1010);
+    }
+}
@@ -0,0 +1,465 @@
+use super::GpioDevice;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pins that the RTC is connected to.
+const SCK: u8 = 1 << 0;
+const SIO: u8 = 1 << 1;
+const CS: u8 = 1 << 2;
+
+/// Every command byte starts with these four bits. Anything else is ignored.
+const COMMAND_MAGIC: u8 = 0b0110;
+
+/// Status register bits. Only 24-hour mode really matters to games, and the
+/// interrupt bits are stored but otherwise ignored.
+const STATUS_24_HOUR: u8 = 1 << 6;
+const STATUS_WRITABLE: u8 = 0b0110_1010;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A source of the current time. Anything which keeps time can be plugged
+/// into the RTC, which makes it easy to give tests a clock that never moves.
+pub trait Clock {
+    /// The number of seconds since the Unix epoch, adjusted to local time.
+    fn now(&self) -> i64;
+}
+
+/// Reads the time from the operating system. There isn't a way to find the
+/// local timezone without help from the platform, so this is always in UTC.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that is stuck at a single moment.
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
+
+/// The commands understood by the S-3511, taken from bits 4-6 of the command
+/// byte. The number of bytes each of them transfers is fixed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Command {
+    Reset,
+    DateTime,
+    ForceInterrupt,
+    Status,
+    Time,
+    Unknown,
+}
+
+impl Command {
+    fn from_byte(byte: u8) -> Self {
+        match byte >> 4 & 0b111 {
+            0 => Command::Reset,
+            2 => Command::DateTime,
+            3 => Command::ForceInterrupt,
+            4 => Command::Status,
+            6 => Command::Time,
+            _ => Command::Unknown,
+        }
+    }
+
+    fn length(self) -> usize {
+        match self {
+            Command::DateTime => 7,
+            Command::Time => 3,
+            Command::Status => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// What the chip is doing in the middle of a transfer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transfer {
+    /// Waiting for a command byte
+    Command,
+    /// Sending the prepared bytes back to the game
+    Reading { command: Command, bytes: [u8; 7] },
+    /// Receiving the parameters of a command
+    Writing { command: Command, bytes: [u8; 7] },
+    /// Nothing left to do until chip select goes low
+    Done,
+}
+
+/// The Seiko S-3511 real-time clock. It is connected to three of the GPIO
+/// pins and talks over a simple serial protocol, with the least significant
+/// bit of each byte sent first.
+pub struct Rtc {
+    pub status: u8,
+    /// How far the game has moved the clock away from the host's time, in
+    /// seconds. Games set the time when they're first started.
+    pub offset: i64,
+    transfer: Transfer,
+    /// The bits of the current byte, and which byte of the transfer it is.
+    byte: u8,
+    bit: u32,
+    index: usize,
+    /// The value of the pins the last time they were written.
+    pins: u8,
+    /// The bit the chip is currently driving onto SIO.
+    output: u8,
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self {
+            status: STATUS_24_HOUR,
+            offset: 0,
+            transfer: Transfer::Command,
+            byte: 0,
+            bit: 0,
+            index: 0,
+            pins: 0,
+            output: 0,
+        }
+    }
+}
+
+impl GpioDevice for Rtc {
+    fn read_pins(&self) -> u8 {
+        self.output << 1
+    }
+
+    fn write_pins(&mut self, pins: u8, now: i64) {
+        let previous = self.pins;
+        self.pins = pins;
+
+        // Ending a transfer by dropping chip select throws away anything that
+        // hasn't finished.
+        if pins & CS == 0 {
+            self.transfer = Transfer::Command;
+            self.byte = 0;
+            self.bit = 0;
+            self.index = 0;
+            return;
+        }
+
+        // Bits are moved on the rising edge of the clock
+        if previous & SCK != 0 || pins & SCK == 0 {
+            return;
+        }
+
+        match self.transfer {
+            Transfer::Reading { bytes, .. } => {
+                self.output = bytes[self.index] >> self.bit & 1;
+                self.bit += 1;
+            }
+            Transfer::Command | Transfer::Writing { .. } => {
+                self.byte |= (pins & SIO) >> 1 << self.bit;
+                self.bit += 1;
+            }
+            Transfer::Done => (),
+        }
+
+        if self.bit == 8 {
+            self.bit = 0;
+            self.finish_byte(now);
+        }
+    }
+}
+
+impl Rtc {
+    fn finish_byte(&mut self, now: i64) {
+        let byte = std::mem::replace(&mut self.byte, 0);
+
+        self.transfer = match self.transfer {
+            Transfer::Command => self.start_command(byte, now),
+            Transfer::Reading { command, bytes } => {
+                self.index += 1;
+                if self.index < command.length() {
+                    Transfer::Reading { command, bytes }
+                } else {
+                    Transfer::Done
+                }
+            }
+            Transfer::Writing { command, mut bytes } => {
+                bytes[self.index] = byte;
+                self.index += 1;
+                if self.index < command.length() {
+                    Transfer::Writing { command, bytes }
+                } else {
+                    self.finish_write(command, &bytes, now);
+                    Transfer::Done
+                }
+            }
+            Transfer::Done => Transfer::Done,
+        };
+    }
+
+    fn start_command(&mut self, byte: u8, now: i64) -> Transfer {
+        if byte & 0xf != COMMAND_MAGIC {
+            return Transfer::Done;
+        }
+
+        let command = Command::from_byte(byte);
+        let reading = byte >> 7 & 1 > 0;
+        self.index = 0;
+
+        match command {
+            Command::Reset => {
+                self.status = 0;
+                self.offset = 0;
+                Transfer::Done
+            }
+            Command::Unknown | Command::ForceInterrupt => Transfer::Done,
+            _ if reading => Transfer::Reading {
+                command,
+                bytes: self.registers(command, now),
+            },
+            _ => Transfer::Writing {
+                command,
+                bytes: [0; 7],
+            },
+        }
+    }
+
+    /// The bytes a read command sends back, in the order they're sent.
+    fn registers(&self, command: Command, now: i64) -> [u8; 7] {
+        let date_time = DateTime::from_unix(now + self.offset);
+
+        let hour = if self.status & STATUS_24_HOUR != 0 {
+            bcd(date_time.hour)
+        } else {
+            // Bit 6 is the PM flag in 12-hour mode
+            bcd(date_time.hour % 12) | if date_time.hour >= 12 { 0x40 } else { 0 }
+        };
+
+        let date = [
+            bcd(date_time.year % 100),
+            bcd(date_time.month),
+            bcd(date_time.day),
+            bcd(date_time.weekday),
+        ];
+        let time = [hour, bcd(date_time.minute), bcd(date_time.second)];
+
+        let mut bytes = [0; 7];
+        match command {
+            Command::DateTime => {
+                bytes[0..4].copy_from_slice(&date);
+                bytes[4..7].copy_from_slice(&time);
+            }
+            Command::Time => bytes[0..3].copy_from_slice(&time),
+            Command::Status => bytes[0] = self.status,
+            _ => (),
+        }
+        bytes
+    }
+
+    fn finish_write(&mut self, command: Command, bytes: &[u8; 7], now: i64) {
+        let current = DateTime::from_unix(now + self.offset);
+
+        let set = match command {
+            Command::Status => {
+                self.status = bytes[0] & STATUS_WRITABLE;
+                return;
+            }
+            Command::DateTime => DateTime {
+                year: 2000 + from_bcd(bytes[0]),
+                month: from_bcd(bytes[1] & 0x1f),
+                day: from_bcd(bytes[2] & 0x3f),
+                weekday: from_bcd(bytes[3] & 0x07),
+                hour: from_bcd(bytes[4] & 0x3f),
+                minute: from_bcd(bytes[5] & 0x7f),
+                second: from_bcd(bytes[6] & 0x7f),
+            },
+            Command::Time => DateTime {
+                hour: from_bcd(bytes[0] & 0x3f),
+                minute: from_bcd(bytes[1] & 0x7f),
+                second: from_bcd(bytes[2] & 0x7f),
+                ..current
+            },
+            _ => return,
+        };
+
+        self.offset = set.to_unix() - now;
+    }
+}
+
+/// A broken down calendar date and time. Years are in full, and the weekday
+/// counts from Sunday.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch into a calendar date, using
+    /// Howard Hinnant's `civil_from_days` algorithm.
+    pub fn from_unix(time: i64) -> Self {
+        let days = time.div_euclid(SECONDS_PER_DAY);
+        let seconds = time.rem_euclid(SECONDS_PER_DAY);
+
+        let shifted = days + 719_468;
+        let era = shifted.div_euclid(146_097);
+        let day_of_era = shifted.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            // The epoch was on a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+            hour: (seconds / 3600) as u32,
+            minute: (seconds / 60 % 60) as u32,
+            second: (seconds % 60) as u32,
+        }
+    }
+
+    /// The inverse of `from_unix`. The weekday is ignored.
+    pub fn to_unix(self) -> i64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month = self.month as i64;
+        let month_index = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_index + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+fn bcd(value: u32) -> u8 {
+    (((value / 10 % 10) << 4) | (value % 10)) as u8
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0xf) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Saturday, 2004-06-05 13:45:30 UTC
+    const TIME: i64 = 1_086_443_130;
+
+    fn transfer_byte(rtc: &mut Rtc, byte: u8) {
+        for bit in 0..8 {
+            let sio = (byte >> bit & 1) << 1;
+            rtc.write_pins(CS | sio, TIME);
+            rtc.write_pins(CS | SCK | sio, TIME);
+        }
+    }
+
+    fn read_byte(rtc: &mut Rtc) -> u8 {
+        let mut byte = 0;
+        for bit in 0..8 {
+            rtc.write_pins(CS, TIME);
+            rtc.write_pins(CS | SCK, TIME);
+            byte |= (rtc.read_pins() & SIO) >> 1 << bit;
+        }
+        byte
+    }
+
+    fn begin(rtc: &mut Rtc, command: u8) {
+        rtc.write_pins(SCK, TIME);
+        rtc.write_pins(CS | SCK, TIME);
+        transfer_byte(rtc, COMMAND_MAGIC | command << 4);
+    }
+
+    fn end(rtc: &mut Rtc) {
+        rtc.write_pins(SCK, TIME);
+    }
+
+    #[test]
+    fn civil_dates() {
+        let date_time = DateTime::from_unix(TIME);
+        assert_eq!(
+            date_time,
+            DateTime {
+                year: 2004,
+                month: 6,
+                day: 5,
+                weekday: 6,
+                hour: 13,
+                minute: 45,
+                second: 30,
+            }
+        );
+        assert_eq!(date_time.to_unix(), TIME);
+        assert_eq!(DateTime::from_unix(0).to_unix(), 0);
+    }
+
+    #[test]
+    fn read_date_time() {
+        let mut rtc = Rtc::default();
+
+        // Read date and time
+        begin(&mut rtc, 0b1010);
+        let bytes: Vec<u8> = (0..7).map(|_| read_byte(&mut rtc)).collect();
+        end(&mut rtc);
+
+        assert_eq!(bytes, vec![0x04, 0x06, 0x05, 0x06, 0x13, 0x45, 0x30]);
+    }
+
+    #[test]
+    fn twelve_hour_mode() {
+        let mut rtc = Rtc::default();
+
+        // Write status, turning off 24-hour mode
+        begin(&mut rtc, 0b0100);
+        transfer_byte(&mut rtc, 0);
+        end(&mut rtc);
+
+        // Read status, then time
+        begin(&mut rtc, 0b1100);
+        assert_eq!(read_byte(&mut rtc), 0);
+        end(&mut rtc);
+
+        begin(&mut rtc, 0b1110);
+        assert_eq!(read_byte(&mut rtc), 0x41);
+        end(&mut rtc);
+    }
+
+    #[test]
+    fn set_time() {
+        let mut rtc = Rtc::default();
+
+        // Write time, moving the clock forward by an hour
+        begin(&mut rtc, 0b0110);
+        for byte in &[0x14, 0x45, 0x30] {
+            transfer_byte(&mut rtc, *byte);
+        }
+        end(&mut rtc);
+        assert_eq!(rtc.offset, 3600);
+
+        // Reset
+        begin(&mut rtc, 0b0000);
+        end(&mut rtc);
+        assert_eq!(rtc.offset, 0);
+        assert_eq!(rtc.status, 0);
+    }
+}
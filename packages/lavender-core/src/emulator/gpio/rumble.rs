@@ -0,0 +1,21 @@
+use super::GpioDevice;
+
+/// The motor is switched on and off with a single pin.
+const MOTOR: u8 = 1 << 3;
+
+/// The rumble motor found in WarioWare: Twisted! and Drill Dozer. There's
+/// nothing to read back, the frontend just needs to know when to shake.
+#[derive(Default)]
+pub struct Rumble {
+    pub active: bool,
+}
+
+impl GpioDevice for Rumble {
+    fn read_pins(&self) -> u8 {
+        0
+    }
+
+    fn write_pins(&mut self, pins: u8, _now: i64) {
+        self.active = pins & MOTOR != 0;
+    }
+}
@@ -0,0 +1,80 @@
+use super::GpioDevice;
+
+/// The pins that the solar sensor is connected to.
+const CLOCK: u8 = 1 << 0;
+const RESET: u8 = 1 << 1;
+/// The sensor shares the port with the RTC, and is only listening while this
+/// pin is low.
+const CHIP_SELECT: u8 = 1 << 2;
+const FLAG: u8 = 1 << 3;
+
+/// The light sensor on the Boktai cartridges. Games measure the amount of
+/// light by resetting a counter, and then counting up until the sensor raises
+/// its flag pin. More light means the flag is raised sooner.
+#[derive(Default)]
+pub struct SolarSensor {
+    /// How bright it is, from 0 (complete darkness) to 255 (direct sunlight).
+    pub level: u8,
+    counter: u8,
+    pins: u8,
+}
+
+impl GpioDevice for SolarSensor {
+    fn read_pins(&self) -> u8 {
+        if self.counter >= 255 - self.level {
+            FLAG
+        } else {
+            0
+        }
+    }
+
+    fn write_pins(&mut self, pins: u8, _now: i64) {
+        let previous = std::mem::replace(&mut self.pins, pins);
+
+        if pins & CHIP_SELECT != 0 {
+            return;
+        }
+
+        if pins & RESET != 0 {
+            self.counter = 0;
+        }
+
+        if pins & CLOCK != 0 && previous & CLOCK == 0 {
+            self.counter = self.counter.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts clock pulses until the flag is raised, like the game does.
+    fn measure(sensor: &mut SolarSensor) -> u32 {
+        sensor.write_pins(RESET, 0);
+        sensor.write_pins(0, 0);
+
+        let mut pulses = 0;
+        while sensor.read_pins() & FLAG == 0 {
+            sensor.write_pins(CLOCK, 0);
+            sensor.write_pins(0, 0);
+            pulses += 1;
+        }
+        pulses
+    }
+
+    #[test]
+    fn brighter_is_faster() {
+        let mut sensor = SolarSensor {
+            level: 0x20,
+            ..SolarSensor::default()
+        };
+
+        let dim = measure(&mut sensor);
+        sensor.level = 0xe0;
+        let bright = measure(&mut sensor);
+
+        assert_eq!(dim, 0xdf);
+        assert_eq!(bright, 0x1f);
+    }
+}
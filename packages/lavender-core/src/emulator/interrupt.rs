@@ -0,0 +1,199 @@
+use super::cpu::Exception;
+use super::memory::Memory;
+use super::Emulator;
+
+/// Offsets of the interrupt registers, relative to the beginning of IO
+/// memory. An interrupt is requested by setting its bit in IF, and it is only
+/// taken if the same bit is set in IE, IME is set, and the CPU doesn't have
+/// IRQs disabled. Games acknowledge an interrupt by writing a 1 to its bit in
+/// IF, which clears it.
+pub const INTERRUPT_ENABLE: usize = 0x200;
+pub const INTERRUPT_REQUEST_FLAGS: usize = 0x202;
+pub const INTERRUPT_MASTER_ENABLE: usize = 0x208;
+/// Writing to this register halts the CPU. Bit 7 picks stop mode instead.
+pub const HALT_CONTROL: usize = 0x301;
+
+/// The bit that each source of interrupts sets in IE and IF.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    HBlank = 1,
+    VCounter = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Serial = 7,
+    Dma0 = 8,
+    Dma1 = 9,
+    Dma2 = 10,
+    Dma3 = 11,
+    Keypad = 12,
+    GamePak = 13,
+}
+
+/// Most of the hardware is turned off in stop mode, so only these can wake the
+/// CPU back up.
+const STOP_WAKE_UP: u16 =
+    1 << Interrupt::Serial as u16 | 1 << Interrupt::Keypad as u16 | 1 << Interrupt::GamePak as u16;
+
+/// The two ways to power down the CPU with HALTCNT.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PowerDown {
+    Halt,
+    Stop,
+}
+
+impl PowerDown {
+    pub fn from_control(value: u8) -> Self {
+        if value & 0x80 == 0 {
+            PowerDown::Halt
+        } else {
+            PowerDown::Stop
+        }
+    }
+}
+
+fn read_register(memory: &Memory, offset: usize) -> u16 {
+    u16::from_le_bytes([memory.io[offset], memory.io[offset + 1]])
+}
+
+/// Sets the bit for an interrupt in IF.
+pub fn request(memory: &mut Memory, interrupt: Interrupt) {
+    let flags = read_register(memory, INTERRUPT_REQUEST_FLAGS) | 1 << interrupt as u16;
+    memory.io[INTERRUPT_REQUEST_FLAGS..INTERRUPT_REQUEST_FLAGS + 2]
+        .copy_from_slice(&flags.to_le_bytes());
+}
+
+/// The interrupts that have been requested and are enabled in IE.
+pub fn pending(memory: &Memory) -> u16 {
+    read_register(memory, INTERRUPT_ENABLE)
+        & read_register(memory, INTERRUPT_REQUEST_FLAGS)
+        & 0x3fff
+}
+
+/// Called between instructions. Wakes up a halted CPU if there is an
+/// interrupt for it, and then enters the IRQ handler if interrupts are
+/// enabled. Returns true if the CPU is still halted, and shouldn't run
+/// anything.
+pub fn check(emulator: &mut Emulator) -> bool {
+    let pending = pending(&emulator.memory);
+
+    if emulator.cpu.halt {
+        // Halting doesn't care about IME, so the CPU can wake up without
+        // taking the interrupt
+        let wake_up = if emulator.cpu.stopped {
+            pending & STOP_WAKE_UP
+        } else {
+            pending
+        };
+        if wake_up == 0 {
+            return true;
+        }
+
+        emulator.cpu.halt = false;
+        emulator.cpu.stopped = false;
+    }
+
+    let master_enable = emulator.memory.io[INTERRUPT_MASTER_ENABLE] & 1 != 0;
+    if pending != 0 && master_enable && !emulator.cpu.is_irq_disabled() {
+        // The handler returns with `subs pc, lr, #4`
+        let return_address = emulator.cpu.next_instruction_address() + 4;
+        emulator.cpu.exception(Exception::Interrupt, return_address);
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::{OperationModes, RegisterNames::*};
+    use crate::emulator::memory::{EXT_START, IO_START};
+
+    const CODE: u32 = EXT_START as u32;
+
+    /// An emulator that's about to run `strb r0, [r1]`, with r1 pointing to
+    /// HALTCNT.
+    fn about_to_halt(control: u8) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.direct_boot();
+        emulator.memory.write_word(CODE, 0xe5c1_0000);
+        emulator.cpu.set_register_value(r0, control as u32);
+        emulator
+            .cpu
+            .set_register_value(r1, (IO_START + HALT_CONTROL) as u32);
+        emulator.cpu.set_register_value(r15, CODE);
+        emulator
+    }
+
+    fn write_io(emulator: &mut Emulator, offset: usize, value: u16) {
+        emulator
+            .memory
+            .write_half_word((IO_START + offset) as u32, value);
+    }
+
+    #[test]
+    fn halt_until_requested() {
+        let mut emulator = about_to_halt(0);
+        emulator.step_instruction();
+        assert!(emulator.cpu.halt);
+
+        // Nothing runs while halted
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 4);
+
+        // An enabled interrupt wakes the CPU, even without IME
+        write_io(&mut emulator, INTERRUPT_ENABLE, 1);
+        request(&mut emulator.memory, Interrupt::VBlank);
+        emulator.step_instruction();
+        assert!(!emulator.cpu.halt);
+        assert_eq!(emulator.cpu.get_operation_mode(), Some(OperationModes::SYS));
+    }
+
+    #[test]
+    fn enter_irq_handler() {
+        let mut emulator = about_to_halt(0);
+        write_io(
+            &mut emulator,
+            INTERRUPT_ENABLE,
+            1 << Interrupt::Timer0 as u16,
+        );
+        write_io(&mut emulator, INTERRUPT_MASTER_ENABLE, 1);
+        emulator.step_instruction();
+
+        request(&mut emulator.memory, Interrupt::Timer0);
+        let status = emulator.cpu.registers.cpsr;
+        assert!(!check(&mut emulator));
+
+        let cpu = &emulator.cpu;
+        assert_eq!(cpu.get_operation_mode(), Some(OperationModes::IRQ));
+        assert_eq!(cpu.get_register_value(r15), 0x18);
+        assert_eq!(cpu.get_register_value(r14), CODE + 8);
+        assert_eq!(cpu.get_register_value(spsr), status);
+        assert!(cpu.is_irq_disabled());
+
+        // Acknowledging the interrupt clears it
+        write_io(
+            &mut emulator,
+            INTERRUPT_REQUEST_FLAGS,
+            1 << Interrupt::Timer0 as u16,
+        );
+        assert_eq!(pending(&emulator.memory), 0);
+    }
+
+    #[test]
+    fn stop_until_keypad() {
+        let mut emulator = about_to_halt(0x80);
+        write_io(&mut emulator, INTERRUPT_ENABLE, 0x3fff);
+        emulator.step_instruction();
+        assert!(emulator.cpu.stopped);
+
+        request(&mut emulator.memory, Interrupt::VBlank);
+        assert!(check(&mut emulator));
+
+        request(&mut emulator.memory, Interrupt::Keypad);
+        assert!(!check(&mut emulator));
+        assert!(!emulator.cpu.halt && !emulator.cpu.stopped);
+    }
+}
@@ -1,3 +1,15 @@
+use super::cache::CodePages;
+use super::dma;
+use super::gpio::{self, Gpio};
+use super::interrupt::{PowerDown, HALT_CONTROL, INTERRUPT_REQUEST_FLAGS};
+use super::save::{Save, SaveType};
+use super::scheduler::Request;
+use super::serial;
+use super::tilt::TiltSensor;
+use super::timer;
+use super::timing::{self, Timing, Width};
+use super::video;
+use log::debug;
 use std::convert::TryInto;
 
 pub const BIOS_SIZE: usize = 16 * 1024;
@@ -36,10 +48,86 @@ pub const ROM_WAIT1_END: usize = ROM_WAIT1_START + ROM_SIZE - 1;
 pub const ROM_WAIT2_START: usize = 0x0c00_0000;
 pub const ROM_WAIT2_END: usize = ROM_WAIT2_START + ROM_SIZE - 1;
 
+/// EEPROM is connected to the top half of the last ROM mirror. Cartridges with
+/// 32MB ROMs only leave the last 256 bytes of it for the EEPROM.
+pub const EEPROM_START: usize = 0x0d00_0000;
+pub const EEPROM_END: usize = ROM_WAIT2_END;
+pub const EEPROM_LARGE_ROM_START: usize = 0x0dff_ff00;
+
 pub const SAVE_SIZE: usize = 64 * 1024;
 pub const SAVE_START: usize = 0x0e00_0000;
 pub const SAVE_END: usize = SAVE_START + SAVE_SIZE - 1;
 
+/// The address space is split into 16KB pages, each of which is either backed
+/// directly by one of the regions of memory, or needs to go through the slow
+/// path to work out what an access does.
+const PAGE_BITS: u32 = 14;
+/// Nothing is mapped above `0x0fff_ffff`.
+const PAGE_COUNT: usize = 1 << (28 - PAGE_BITS);
+
+/// The regions of memory that pages can point into.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Region {
+    /// Reads and writes both need the slow path.
+    None,
+    Ext,
+    Ram,
+    Palette,
+    Vram,
+    Object,
+    Rom,
+}
+
+impl Region {
+    /// Every region starts at the beginning of its 16MB area, apart from the
+    /// ROM which is 32MB.
+    fn offset(self, address: u32) -> usize {
+        match self {
+            Region::Rom => address as usize & (ROM_SIZE - 1),
+            _ => address as usize & 0x00ff_ffff,
+        }
+    }
+
+    /// The ROM can't be written to at all, so it only reads directly.
+    fn writable(self) -> bool {
+        self != Region::Rom
+    }
+}
+
+/// Where each page of the address space points. Accesses past the end of a
+/// region (like most of the palette's page, or the ROM past the end of the
+/// cartridge) fall back to the slow path too, so this never changes. It's
+/// worked out at compile time rather than with `lazy_static`, since every
+/// memory access looks at it.
+static PAGES: [Region; PAGE_COUNT] = {
+    let mut pages = [Region::None; PAGE_COUNT];
+    let mut page = 0;
+    while page < PAGE_COUNT {
+        pages[page] = region_for(page << PAGE_BITS);
+        page += 1;
+    }
+    pages
+};
+
+const fn region_for(start: usize) -> Region {
+    match start {
+        EXT_START..=EXT_END => Region::Ext,
+        RAM_START..=RAM_END => Region::Ram,
+        PALETTE_START..=PALETTE_END => Region::Palette,
+        VRAM_START..=VRAM_END => Region::Vram,
+        OBJECT_ATTRIBUTE_START..=OBJECT_ATTRIBUTE_END => Region::Object,
+        // The GPIO registers are near the start of the ROM
+        _ if start == ROM_START => Region::None,
+        ROM_START..=ROM_WAIT1_END => Region::Rom,
+        // The EEPROM can be anywhere in the top half of the last mirror
+        _ if start >= ROM_WAIT2_START && start < EEPROM_START => Region::Rom,
+        // The BIOS is protected, the save memory is a chip of its own, and
+        // some IO registers (like the timer counters) are worked out when
+        // they're read
+        _ => Region::None,
+    }
+}
+
 pub struct Memory {
     /// Stores the BIOS of the Game Boy Advance, which is home to the software
     /// interupt table and some useful methods that there are not instructions for.
@@ -64,9 +152,47 @@ pub struct Memory {
     /// The writtable save memory inside of the cartridge is mappen here. The
     /// contents of this memory are copied out exactly as is when creating a
     /// save state.
-    pub save: Vec<u8>,
+    pub save: Save,
+    /// Extra hardware, like a real-time clock, which some cartridges connect
+    /// to registers inside of the ROM.
+    pub gpio: Gpio,
+    /// The tilt sensor, which takes over part of the save region on the few
+    /// cartridges that have one.
+    pub tilt: Option<TiltSensor>,
+    /// The BIOS can only be read by code that is running inside of it. This
+    /// is updated every time an instruction is fetched.
+    pub executing_bios: bool,
+    /// Reads from the BIOS by code running anywhere else see the last
+    /// instruction that was fetched from it instead.
+    pub last_bios_opcode: u32,
+    /// Set when HALTCNT is written to. The CPU powers down once the current
+    /// instruction has finished.
+    pub power_down: Option<PowerDown>,
+    /// Events that writes to IO registers have started or stopped, waiting
+    /// to be passed on to the scheduler.
+    pub requests: Vec<Request>,
+    /// The values each timer's counter is set to when it starts, and when it
+    /// overflows.
+    pub timer_reloads: [u16; 4],
+    /// When and how each running timer last started counting up.
+    pub timer_starts: [timer::Start; 4],
+    /// The registers each DMA channel works from once it's been enabled.
+    pub dma_channels: [dma::Internal; 4],
+    /// The time in cycles, as of the last time the scheduler ran any events.
+    /// Timers use it to work out their counters when they're read.
+    pub now: u64,
+    /// Counts the wait states of every instruction fetch, and every access
+    /// made by an instruction.
+    pub timing: Timing,
+    /// Notices writes to work RAM that has instructions in the block cache.
+    pub code_pages: CodePages,
 }
 
+/// The instruction that the BIOS fetches last before jumping to the game when
+/// it starts up, and the one it fetches last when returning from a `swi`.
+pub const BIOS_OPCODE_AFTER_STARTUP: u32 = 0xe129_f000;
+pub const BIOS_OPCODE_AFTER_SWI: u32 = 0xe3a0_2004;
+
 impl Memory {
     pub fn init() -> Self {
         let mut memory = Self {
@@ -78,7 +204,19 @@ impl Memory {
             vram: vec![0; VRAM_SIZE],
             object: vec![0; OBJECT_ATTRIBUTE_SIZE],
             rom: vec![0; 1],
-            save: vec![0; SAVE_SIZE],
+            save: Save::new(SaveType::Sram),
+            gpio: Gpio::default(),
+            tilt: None,
+            executing_bios: true,
+            last_bios_opcode: BIOS_OPCODE_AFTER_STARTUP,
+            power_down: None,
+            requests: Vec::new(),
+            timer_reloads: [0; 4],
+            timer_starts: Default::default(),
+            dma_channels: Default::default(),
+            now: 0,
+            timing: Timing::default(),
+            code_pages: CodePages::default(),
         };
 
         // Copy the BIOS into memory
@@ -99,10 +237,98 @@ impl Memory {
             vram: vec![0; 32],
             object: vec![0; 32],
             rom: vec![0; 1],
-            save: vec![0; 32],
+            save: Save::Sram(vec![0; 32]),
+            gpio: Gpio::default(),
+            tilt: None,
+            executing_bios: true,
+            last_bios_opcode: BIOS_OPCODE_AFTER_STARTUP,
+            power_down: None,
+            requests: Vec::new(),
+            timer_reloads: [0; 4],
+            timer_starts: Default::default(),
+            dma_channels: Default::default(),
+            now: 0,
+            timing: Timing::default(),
+            code_pages: CodePages::default(),
         }
     }
 
+    /// Reads an ARM instruction for the CPU to run. Unlike other reads, this
+    /// keeps track of whether the CPU is running code from the BIOS.
+    pub fn fetch_word(&mut self, address: u32) -> u32 {
+        self.executing_bios = (address as usize) <= BIOS_END;
+        self.timing.fetch(address, Width::Word);
+        let opcode = self.read_word(address);
+        if self.executing_bios {
+            self.last_bios_opcode = opcode;
+        }
+        opcode
+    }
+
+    /// Reads a Thumb instruction for the CPU to run. The BIOS is always read
+    /// a word at a time, so the last opcode includes both halves of the word.
+    pub fn fetch_half_word(&mut self, address: u32) -> u16 {
+        self.executing_bios = (address as usize) <= BIOS_END;
+        self.timing.fetch(address, Width::HalfWord);
+        if self.executing_bios {
+            self.last_bios_opcode = self.read_word(address & !3);
+        }
+        self.read_half_word(address)
+    }
+
+    /// When the CPU isn't running code from the BIOS, reads from the BIOS
+    /// return the last instruction fetched from it instead. Returns that
+    /// instruction, shifted so that the byte at `address` is at the bottom.
+    fn protected_bios_read(&self, address: u32) -> Option<u32> {
+        if (address as usize) <= BIOS_END && !self.executing_bios {
+            Some(self.last_bios_opcode >> ((address & 3) * 8))
+        } else {
+            None
+        }
+    }
+
+    fn region(&self, region: Region) -> &[u8] {
+        match region {
+            Region::None => &[],
+            Region::Ext => &self.ext,
+            Region::Ram => &self.ram,
+            Region::Palette => &self.palette,
+            Region::Vram => &self.vram,
+            Region::Object => &self.object,
+            Region::Rom => &self.rom,
+        }
+    }
+
+    fn region_mut(&mut self, region: Region) -> &mut [u8] {
+        match region {
+            Region::Ext => &mut self.ext,
+            Region::Ram => &mut self.ram,
+            Region::Palette => &mut self.palette,
+            Region::Vram => &mut self.vram,
+            Region::Object => &mut self.object,
+            _ => &mut [],
+        }
+    }
+
+    /// The `size` bytes at `address`, if they can be read straight out of one
+    /// of the regions.
+    fn direct(&self, address: u32, size: usize) -> Option<&[u8]> {
+        let region = *PAGES.get((address >> PAGE_BITS) as usize)?;
+        let offset = region.offset(address);
+        self.region(region).get(offset..offset + size)
+    }
+
+    /// The same as `direct`, for writing. Writes to work RAM still need to be
+    /// passed on to `code_pages`.
+    fn direct_mut(&mut self, address: u32, size: usize) -> Option<&mut [u8]> {
+        let region = *PAGES.get((address >> PAGE_BITS) as usize)?;
+        if !region.writable() {
+            return None;
+        }
+        let offset = region.offset(address);
+        self.region_mut(region).get_mut(offset..offset + size)
+    }
+
     pub fn get_mapped_segment_and_real_offset(&self, address: u32) -> Option<(&Vec<u8>, usize)> {
         let i = address as usize;
 
@@ -119,14 +345,74 @@ impl Memory {
             ROM_START..=ROM_END => Some((&self.rom, i - ROM_START)),
             ROM_WAIT1_START..=ROM_WAIT1_END => Some((&self.rom, i - ROM_WAIT1_START)),
             ROM_WAIT2_START..=ROM_WAIT2_END => Some((&self.rom, i - ROM_WAIT2_START)),
-            SAVE_START..=SAVE_END => Some((&self.save, i - SAVE_START)),
             _ => None,
         }
     }
 
-    pub fn read_word(&self, address: u32) -> u32 {
+    /// Checks if an address is handled by the cartridge's EEPROM, rather than
+    /// by the ROM.
+    pub fn is_eeprom_address(&self, address: u32) -> bool {
+        let i = address as usize;
+
+        match self.save {
+            Save::Eeprom(_) if self.rom.len() > 16 * 1024 * 1024 => {
+                (EEPROM_LARGE_ROM_START..=EEPROM_END).contains(&i)
+            }
+            Save::Eeprom(_) => (EEPROM_START..=EEPROM_END).contains(&i),
+            _ => false,
+        }
+    }
+
+    /// Returns the offset of a GPIO register, if the address is one and the
+    /// cartridge has a GPIO port.
+    fn gpio_offset(&self, address: u32) -> Option<usize> {
+        let offset = (address as usize).checked_sub(ROM_START)?;
+
+        if self.gpio.is_connected() && (gpio::GPIO_START..=gpio::GPIO_END).contains(&offset) {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn readable_gpio_offset(&self, address: u32) -> Option<usize> {
+        self.gpio_offset(address).filter(|_| self.gpio.readable)
+    }
+
+    /// Works out the IO registers that change on their own, like the timer
+    /// counters, before one of them is read.
+    fn update_io(&mut self, address: u32) {
+        if (IO_START..=IO_END).contains(&(address as usize)) {
+            timer::update_counter(self, address as usize - IO_START);
+        }
+    }
+
+    pub fn read_word(&mut self, address: u32) -> u32 {
         // assert_eq!(address % 4, 0);
 
+        if let Some(bytes) = self.direct(address, 4) {
+            return u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        self.update_io(address);
+
+        if let Some(opcode) = self.protected_bios_read(address & !3) {
+            return opcode;
+        }
+
+        if self.readable_gpio_offset(address).is_some()
+            || self.readable_gpio_offset(address + 2).is_some()
+        {
+            return self.read_half_word(address) as u32
+                | (self.read_half_word(address + 2) as u32) << 16;
+        }
+
+        // The save memory is only connected to an 8-bit bus, so the same byte
+        // is seen on every lane.
+        if is_save_address(address) {
+            return self.read_byte(address) as u32 * 0x0101_0101;
+        }
+
         if let Some((mem, offset)) = self.get_mapped_segment_and_real_offset(address) {
             u32::from_le_bytes(
                 mem[offset..offset + 4]
@@ -141,14 +427,47 @@ impl Memory {
     pub fn write_word(&mut self, address: u32, value: u32) {
         assert_eq!(address % 4, 0);
 
+        if let Some(bytes) = self.direct_mut(address, 4) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+            self.code_pages.written_to(address);
+            return;
+        }
+
         for (index, each) in value.to_le_bytes().iter().enumerate() {
             self.write_byte(address + index as u32, *each);
         }
     }
 
-    pub fn read_half_word(&self, address: u32) -> u16 {
+    pub fn read_half_word(&mut self, address: u32) -> u16 {
         assert_eq!(address % 2, 0);
 
+        if let Some(bytes) = self.direct(address, 2) {
+            return u16::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        self.update_io(address);
+
+        if let Some(opcode) = self.protected_bios_read(address) {
+            return opcode as u16;
+        }
+
+        if self.is_eeprom_address(address) {
+            if let Save::Eeprom(eeprom) = &mut self.save {
+                return eeprom.read_bit();
+            }
+        }
+
+        if is_save_address(address) {
+            return self.read_byte(address) as u16 * 0x0101;
+        }
+
+        if let Some(offset) = self.readable_gpio_offset(address) {
+            return u16::from_le_bytes([
+                self.gpio.read_byte(offset),
+                self.gpio.read_byte(offset + 1),
+            ]);
+        }
+
         if let Some((mem, offset)) = self.get_mapped_segment_and_real_offset(address) {
             u16::from_le_bytes(
                 mem[offset..offset + 2]
@@ -163,14 +482,41 @@ impl Memory {
     pub fn write_half_word(&mut self, address: u32, value: u16) {
         assert_eq!(address % 2, 0);
 
+        if let Some(bytes) = self.direct_mut(address, 2) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+            self.code_pages.written_to(address);
+            return;
+        }
+
+        if self.is_eeprom_address(address) {
+            if let Save::Eeprom(eeprom) = &mut self.save {
+                eeprom.write_bit(value);
+            }
+            return;
+        }
+
         for (index, each) in value.to_le_bytes().iter().enumerate() {
             self.write_byte(address + index as u32, *each);
         }
     }
 
-    pub fn read_byte(&self, address: u32) -> u8 {
+    pub fn read_byte(&mut self, address: u32) -> u8 {
+        if let Some(bytes) = self.direct(address, 1) {
+            return bytes[0];
+        }
+
+        self.update_io(address);
+
         let i = address as usize;
 
+        if let Some(offset) = self.readable_gpio_offset(address) {
+            return self.gpio.read_byte(offset);
+        }
+
+        if let Some(opcode) = self.protected_bios_read(address) {
+            return opcode as u8;
+        }
+
         match i {
             BIOS_START..=BIOS_END => self.bios[i],
             EXT_START..=EXT_END => self.ext[i - EXT_START],
@@ -184,33 +530,100 @@ impl Memory {
             ROM_START..=ROM_END => self.rom[i - ROM_START],
             ROM_WAIT1_START..=ROM_WAIT1_END => self.rom[i - ROM_WAIT1_START],
             ROM_WAIT2_START..=ROM_WAIT2_END => self.rom[i - ROM_WAIT2_START],
-            SAVE_START..=SAVE_END => self.save[i - SAVE_START],
+            SAVE_START..=SAVE_END => match &self.tilt {
+                Some(tilt) if TiltSensor::handles(i - SAVE_START) => tilt.read_byte(i - SAVE_START),
+                _ => self.save.read_byte(i - SAVE_START),
+            },
             _ => 0,
         }
     }
 
     pub fn write_byte(&mut self, address: u32, value: u8) {
+        if let Some(bytes) = self.direct_mut(address, 1) {
+            bytes[0] = value;
+            self.code_pages.written_to(address);
+            return;
+        }
+
         let i = address as usize;
 
+        if let Some(offset) = self.gpio_offset(address) {
+            self.gpio.write_byte(offset, value);
+            return;
+        }
+
         match i {
             // Note that BIOS is intentionally missing.
-            EXT_START..=EXT_END => self.ext[i - EXT_START] = value,
-            RAM_START..=RAM_END => self.ram[i - RAM_START] = value,
-            IO_START..=IO_END => self.io[i - IO_START] = value,
+            EXT_START..=EXT_END => {
+                self.ext[i - EXT_START] = value;
+                self.code_pages.written_to(address);
+            }
+            RAM_START..=RAM_END => {
+                self.ram[i - RAM_START] = value;
+                self.code_pages.written_to(address);
+            }
+            IO_START..=IO_END => self.write_io(i - IO_START, value),
             PALETTE_START..=PALETTE_END => self.palette[i - PALETTE_START] = value,
             VRAM_START..=VRAM_END => self.vram[i - VRAM_START] = value,
             OBJECT_ATTRIBUTE_START..=OBJECT_ATTRIBUTE_END => {
                 self.object[i - OBJECT_ATTRIBUTE_START] = value
             }
-            ROM_START..=ROM_END => self.rom[i - ROM_START] = value,
-            ROM_WAIT1_START..=ROM_WAIT1_END => self.rom[i - ROM_WAIT1_START] = value,
-            ROM_WAIT2_START..=ROM_WAIT2_END => self.rom[i - ROM_WAIT2_START] = value,
-            SAVE_START..=SAVE_END => self.save[i - SAVE_START] = value,
+            // The ROM is read-only. Writes that mean something (GPIO and
+            // EEPROM) have already been handled, so anything else is probably
+            // a bug, either in the game or in the emulator.
+            ROM_START..=ROM_WAIT2_END => debug!(
+                "ignoring write of {:#04x} to ROM at {:#010x}",
+                value, address
+            ),
+            SAVE_START..=SAVE_END => match &mut self.tilt {
+                Some(tilt) if TiltSensor::handles(i - SAVE_START) => {
+                    tilt.write_byte(i - SAVE_START, value)
+                }
+                _ => self.save.write_byte(i - SAVE_START, value),
+            },
             _ => (),
         };
     }
 }
 
+impl Memory {
+    /// Most IO registers are just memory, but some of them cause things to
+    /// happen when they're written to.
+    fn write_io(&mut self, offset: usize, value: u8) {
+        let previous = self.io[offset];
+        self.io[offset] = value;
+
+        match offset {
+            // The flags in DISPSTAT and the line in VCOUNT can only be read
+            video::DISPLAY_STATUS => self.io[offset] = value & !0b111 | previous & 0b111,
+            video::VERTICAL_COUNT => self.io[offset] = previous,
+            // Writing a 1 to a bit of IF acknowledges that interrupt
+            _ if offset & !1 == INTERRUPT_REQUEST_FLAGS => self.io[offset] = previous & !value,
+            HALT_CONTROL => self.power_down = Some(PowerDown::from_control(value)),
+            serial::SERIAL_CONTROL | 0x129 => serial::control_written(self, offset, previous),
+            0x100..=0x10f => timer::register_written(self, offset, previous),
+            timing::WAIT_CONTROL | 0x205 => self.timing.set_control(u16::from_le_bytes([
+                self.io[timing::WAIT_CONTROL],
+                self.io[timing::WAIT_CONTROL + 1],
+            ])),
+            _ => (),
+        }
+
+        if let Some(channel) = dma::channel_for_control_offset(offset) {
+            // Transfers only start once the high byte (with the enable bit)
+            // has been written.
+            if offset % 2 == 1 {
+                let previous_control = u16::from_le_bytes([self.io[offset - 1], previous]);
+                dma::control_written(self, channel, previous_control);
+            }
+        }
+    }
+}
+
+fn is_save_address(address: u32) -> bool {
+    (SAVE_START..=SAVE_END).contains(&(address as usize))
+}
+
 pub static BIOS: [u8; 548] = [
     0x06, 0x00, 0x00, 0xea, 0xfe, 0xff, 0xff, 0xea, 0x0b, 0x00, 0x00, 0xea, 0xfe, 0xff, 0xff, 0xea,
     0xfe, 0xff, 0xff, 0xea, 0x00, 0x00, 0xa0, 0xe1, 0x2c, 0x00, 0x00, 0xea, 0xfe, 0xff, 0xff, 0xea,
@@ -264,6 +677,38 @@ mod tests {
         assert_eq!(memory.read_word(0), 0xea000006);
     }
 
+    #[test]
+    fn bios_is_protected() {
+        let mut memory = Memory::init();
+        memory.rom = vec![0; 16];
+
+        // Running from the BIOS, so it can be read
+        memory.fetch_word(0x08);
+        let opcode = memory.read_word(0x08);
+        assert_eq!(memory.read_word(0), 0xea00_0006);
+
+        // But once we're in the game, reads see the last instruction fetched
+        memory.fetch_word(ROM_START as u32);
+        assert_eq!(memory.read_word(0), opcode);
+        assert_eq!(memory.read_half_word(0x22), (opcode >> 16) as u16);
+        assert_eq!(memory.read_byte(0x03), (opcode >> 24) as u8);
+
+        memory.fetch_half_word(0x1e);
+        assert_eq!(memory.read_word(0), 0xea00_0006);
+    }
+
+    #[test]
+    fn cant_write_to_rom() {
+        let mut memory = Memory::init();
+        memory.rom = vec![0x12, 0x34, 0x56, 0x78];
+
+        for mirror in &[ROM_START, ROM_WAIT1_START, ROM_WAIT2_START] {
+            memory.write_word(*mirror as u32, 0xdeadbeef);
+            memory.write_byte(*mirror as u32 + 1, 0xff);
+            assert_eq!(memory.read_word(*mirror as u32), 0x7856_3412);
+        }
+    }
+
     #[test]
     fn write_to_ram() {
         let mut memory = Memory::init();
@@ -306,4 +751,104 @@ mod tests {
         // Make sure that the red pixel has the correct value
         assert_eq!(memory.read_half_word(offset), 0x001f);
     }
+
+    #[test]
+    fn flash_through_save_region() {
+        let mut memory = Memory::init();
+        memory.save = Save::new(SaveType::Flash128K);
+
+        let command = |memory: &mut Memory, command: u8| {
+            memory.write_byte(0x0e00_5555, 0xaa);
+            memory.write_byte(0x0e00_2aaa, 0x55);
+            memory.write_byte(0x0e00_5555, command);
+        };
+
+        command(&mut memory, 0x90);
+        assert_eq!(memory.read_byte(0x0e00_0000), 0x62);
+        assert_eq!(memory.read_byte(0x0e00_0001), 0x13);
+        command(&mut memory, 0xf0);
+
+        command(&mut memory, 0xa0);
+        memory.write_byte(0x0e00_0100, 0x5a);
+        assert_eq!(memory.read_byte(0x0e00_0100), 0x5a);
+        assert_eq!(memory.read_half_word(0x0e00_0100), 0x5a5a);
+        assert_eq!(memory.save.data()[0x100], 0x5a);
+    }
+
+    #[test]
+    fn eeprom_through_rom_region() {
+        let mut memory = Memory::init();
+        memory.save = Save::new(SaveType::Eeprom512);
+
+        // The EEPROM is ready when it isn't doing anything else
+        assert_eq!(memory.read_half_word(0x0d00_0000), 1);
+
+        // Read request for block 0
+        for bit in &[1, 1, 0, 0, 0, 0, 0, 0, 0] {
+            memory.write_half_word(0x0d00_0000, *bit);
+        }
+        let bits = (0..68)
+            .map(|_| memory.read_half_word(0x0d00_0000))
+            .collect::<Vec<_>>();
+        assert!(bits.iter().take(4).all(|bit| *bit == 0));
+        assert!(bits.iter().skip(4).all(|bit| *bit == 1));
+    }
+
+    #[test]
+    fn pages_match_the_memory_map() {
+        let mut memory = Memory::init();
+        memory.rom = (0..0x10_0000).map(|i| (i * 7) as u8).collect();
+
+        // Every page that can be read directly should give the same bytes as
+        // looking the address up the slow way
+        for page in 0..PAGE_COUNT as u32 {
+            for offset in &[0, 0x122, (1 << PAGE_BITS) - 4] {
+                let address = page << PAGE_BITS | offset;
+                if let Some(bytes) = memory.direct(address, 4) {
+                    let (region, offset) =
+                        memory.get_mapped_segment_and_real_offset(address).unwrap();
+                    assert_eq!(bytes, &region[offset..offset + 4], "{:#010x}", address);
+                }
+            }
+        }
+
+        // Anything with side effects goes the slow way
+        let slow = [
+            BIOS_START,
+            ROM_START + gpio::GPIO_DATA,
+            EEPROM_START,
+            SAVE_START,
+            PALETTE_START + PALETTE_SIZE,
+            ROM_START + 0x10_0000,
+        ];
+        for address in &slow {
+            assert!(memory.direct(*address as u32, 1).is_none());
+        }
+        assert!(memory.direct(IO_START as u32, 2).is_none());
+        assert!(memory.direct_mut(IO_START as u32, 2).is_none());
+        assert!(memory.direct_mut(ROM_START as u32 + 0x4000, 2).is_none());
+    }
+
+    #[test]
+    fn gpio_registers_over_rom() {
+        let mut memory = Memory::init();
+        memory.rom = vec![0xab; 0x200];
+        memory.rom[0x100..0x108].copy_from_slice(b"SIIRTC_V");
+        memory.gpio.connect(gpio::Devices::detect(&memory.rom));
+
+        let data = (ROM_START + gpio::GPIO_DATA) as u32;
+        let direction = (ROM_START + gpio::GPIO_DIRECTION) as u32;
+        let control = (ROM_START + gpio::GPIO_CONTROL) as u32;
+
+        // The registers can't be read until the game asks for them
+        memory.write_half_word(direction, 0b0111);
+        assert_eq!(memory.read_half_word(direction), 0xabab);
+
+        memory.write_half_word(control, 1);
+        assert_eq!(memory.read_half_word(direction), 0b0111);
+        assert_eq!(memory.read_word(data), 0x0007_0000);
+
+        // Writes shouldn't have touched the ROM underneath
+        assert_eq!(memory.rom[gpio::GPIO_DATA], 0xab);
+    }
 }
@@ -1,27 +1,78 @@
 pub mod armv4t;
+pub mod bios;
+pub mod cache;
+pub mod cartridge;
 pub mod cpu;
+pub mod dma;
+pub mod frontend;
+pub mod gpio;
+pub mod interrupt;
 pub mod memory;
+pub mod recompiler;
+pub mod save;
+pub mod scheduler;
+pub mod serial;
+pub mod tilt;
+pub mod timer;
+pub mod timing;
+pub mod video;
 
 use armv4t::{arm, thumb};
+use bios::{Bios, BiosError};
+use cache::{BlockCache, Cached};
+use cartridge::{Cartridge, CartridgeError, Header};
 use cpu::*;
+use frontend::Frontend;
+use gpio::Devices;
+use interrupt::PowerDown;
 use memory::*;
+use save::{Save, SaveType};
+use scheduler::Scheduler;
+use tilt::TiltSensor;
+use timing::Width;
 
 pub struct Emulator {
     pub cpu: Arm7Tdmi,
     pub memory: Memory,
 
-    /// Used to keep track of how much more the emulator should do before
-    /// updating the screen. When this reaches zero, the emulator will pause
-    /// execution until the next `requestAnimationFrame` delegation.
-    pub remaining_cycles: u32,
+    /// Keeps track of the time, and of everything the rest of the hardware
+    /// needs to do at a particular time.
+    pub scheduler: Scheduler,
+
+    /// Forces a specific kind of save memory, for games where detecting it
+    /// from the ROM contents gets it wrong.
+    pub save_type_override: Option<SaveType>,
+
+    /// The header of the inserted cartridge, if there is one.
+    pub header: Option<Header>,
+
+    pub bios: Bios,
+
+    /// Instructions that have already been decoded.
+    pub cache: BlockCache,
+
+    /// Where finished frames and sound go, and where button presses come from.
+    pub frontend: Frontend,
 }
 
 impl Default for Emulator {
     fn default() -> Self {
+        armv4t::build_decode_tables();
+
         Self {
             cpu: Arm7Tdmi::init(),
             memory: Memory::init(),
-            remaining_cycles: 0,
+            scheduler: Emulator::start_scheduler(),
+            save_type_override: None,
+            header: None,
+            // Without a real BIOS, the built-in one can't do much more than
+            // handle interrupts, so BIOS calls have to be emulated
+            bios: Bios {
+                hle: true,
+                ..Bios::default()
+            },
+            cache: BlockCache::default(),
+            frontend: Frontend::default(),
         }
     }
 }
@@ -39,41 +90,230 @@ impl Emulator {
         Self {
             cpu: Arm7Tdmi::init(),
             memory: Memory::init_small_no_bios(),
-            remaining_cycles: 0,
+            scheduler: Emulator::start_scheduler(),
+            save_type_override: None,
+            header: None,
+            bios: Bios::default(),
+            cache: BlockCache::default(),
+            frontend: Frontend::default(),
         }
     }
 
-    /// Insert a cartridge into the emulator.
-    pub fn load_rom(&mut self, rom: &[u8]) {
-        self.memory.rom = rom.to_vec();
+    /// A scheduler with the events that always need to be running.
+    fn start_scheduler() -> Scheduler {
+        let mut scheduler = Scheduler::default();
+        video::start(&mut scheduler);
+        frontend::start(&mut scheduler);
+        scheduler
+    }
+
+    /// Insert a cartridge into the emulator. ROMs with a broken header are
+    /// rejected, and the emulator is left as it was.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+        let cartridge = Cartridge::new(rom)?;
+
+        self.header = Some(cartridge.header);
+        self.memory.rom = cartridge.rom;
+        self.cache.clear();
+        self.memory.save = Save::new(self.save_type());
+
+        let devices = Devices::detect(rom);
+        self.memory.gpio.connect(devices);
+        self.memory.tilt = if devices.tilt {
+            Some(TiltSensor::default())
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    /// Replaces the built-in BIOS with a real one, and restarts so that it
+    /// boots through the startup animation. BIOS calls go to the real BIOS
+    /// from now on. Images that aren't a known BIOS are rejected, and the
+    /// emulator is left as it was.
+    pub fn load_bios(&mut self, image: &[u8]) -> Result<(), BiosError> {
+        bios::verify(image)?;
+
+        self.memory.bios = image.to_vec();
+        self.bios = Bios {
+            loaded: true,
+            ..Bios::default()
+        };
+
+        // The console starts up in supervisor mode, at the reset vector
+        self.cpu.reset();
+        self.cpu.set_operation_mode(OperationModes::SVC);
+
+        Ok(())
+    }
+
+    /// Skips the BIOS, and starts the game with the registers and memory set
+    /// up the way the BIOS would have left them.
+    pub fn direct_boot(&mut self) {
+        bios::system::direct_boot(self);
+    }
+
+    /// Decides which kind of save memory the inserted cartridge should have.
+    /// Games which don't seem to save get SRAM, which is harmless if unused.
+    pub fn save_type(&self) -> SaveType {
+        self.save_type_override
+            .or_else(|| SaveType::detect(&self.memory.rom))
+            .unwrap_or(SaveType::Sram)
+    }
+
+    /// Overrides the detected save type, or goes back to detecting it if
+    /// `None` is given. The save memory is recreated, so this should be done
+    /// before importing a save file.
+    pub fn set_save_type_override(&mut self, save_type: Option<SaveType>) {
+        self.save_type_override = save_type;
+        self.memory.save = Save::new(self.save_type());
     }
 
     /// Step forward by one frame (or about 280 thousand cycles)
     pub fn step_frame(&mut self) {
-        // 16.78MHz CPU clock speed / 60Hz display refresh rate = 279,666 CPU cycles
-        self.remaining_cycles += 279_666;
+        let end = self.scheduler.now() + video::FRAME_CYCLES;
 
-        while self.remaining_cycles > 0 {
+        while self.scheduler.now() < end {
             self.step_instruction();
         }
     }
 
     /// Step forward by one instruction
     pub fn step_instruction(&mut self) {
+        if interrupt::check(self) {
+            // Nothing will happen until the next event, which might be the
+            // interrupt that wakes the CPU back up
+            self.scheduler.skip_to_next_event();
+            scheduler::run_due_events(self);
+            return;
+        }
+
+        let cycles_used = match self.cached_operation() {
+            Some(Cached::Operation(operation)) => self.run_cached(operation),
+            Some(Cached::Block(instance)) => self.run_compiled(&*instance),
+            None => self.run_pipeline(),
+        };
+
+        // Everything else is timed in cycles where memory responds straight
+        // away, so the wait states go on top
+        let wait_states = self.memory.timing.take_cycles();
+        scheduler::schedule_requests(self);
+        self.scheduler.advance(cycles_used + wait_states);
+
+        scheduler::run_due_events(self);
+
+        if let Some(power_down) = self.memory.power_down.take() {
+            self.cpu.halt = true;
+            self.cpu.stopped = power_down == PowerDown::Stop;
+        }
+    }
+}
+
+impl Emulator {
+    /// Runs the next instruction in the pipeline, and fetches the one after.
+    fn run_pipeline(&mut self) -> u32 {
+        if self.cpu.pipeline.flushed {
+            self.refill_pipeline();
+        }
+
+        let instruction = self.cpu.pipeline.opcodes[0];
+        let cycles_used = if self.cpu.get_thumb_bit() {
+            thumb::process_instruction(self, instruction as u16)
+        } else {
+            arm::process_instruction(self, instruction)
+        };
+
+        // The cartridge can prefetch more of the ROM while the instruction is
+        // busy with anything other than its own fetch
+        self.memory.timing.idle(cycles_used.saturating_sub(1));
+
+        // Unless the instruction jumped somewhere else, move the pipeline
+        // along by one instruction
+        if !self.cpu.pipeline.flushed {
+            let address = self.cpu.registers.r15;
+            self.cpu.pipeline.opcodes[0] = self.cpu.pipeline.opcodes[1];
+            self.cpu.pipeline.opcodes[1] = self.fetch(address);
+            self.cpu.registers.r15 = address + self.cpu.instruction_size();
+        }
+
+        cycles_used
+    }
+
+    /// Runs an instruction from the block cache. Nothing is read from memory,
+    /// but r15 and the fetch timing are kept the same as they would be with
+    /// the pipeline.
+    fn run_cached(&mut self, operation: cache::Operation) -> u32 {
+        self.refill_cached_pipeline();
+
+        let cycles_used = operation.run(self);
+        self.memory.timing.idle(cycles_used.saturating_sub(1));
+
+        if !self.cpu.pipeline.flushed {
+            let address = self.cpu.registers.r15;
+            self.memory.timing.fetch(address, self.fetch_width());
+            self.cpu.registers.r15 = address + self.cpu.instruction_size();
+        }
+
+        cycles_used
+    }
+
+    /// Runs a whole compiled block. Like `run_cached`, it only keeps track of
+    /// r15 and the fetch timing, and the block does the rest. Blocks stop
+    /// after any write to IO, so interrupts, events and halts that the write
+    /// sets off are dealt with before the next instruction.
+    pub fn run_compiled(&mut self, instance: &dyn recompiler::Instance) -> u32 {
+        self.refill_cached_pipeline();
+
+        let mut state = [0; recompiler::STATE_SIZE];
+        self.enter_compiled(&mut state);
+        let cycles_used = instance.run(self, &mut state);
+        self.leave_compiled(&state);
+
+        cycles_used
+    }
+
+    /// Times the fetches that refilling the pipeline would make, when running
+    /// from the cache after it has been flushed.
+    fn refill_cached_pipeline(&mut self) {
+        if self.cpu.pipeline.flushed {
+            let size = self.cpu.instruction_size();
+            let address = self.cpu.registers.r15 & !(size - 1);
+            self.memory.timing.fetch(address, self.fetch_width());
+            self.memory.timing.fetch(address + size, self.fetch_width());
+            self.memory.executing_bios = false;
+            self.cpu.registers.r15 = address + 2 * size;
+            self.cpu.pipeline.flushed = false;
+        }
+    }
+
+    fn fetch_width(&self) -> Width {
+        if self.cpu.get_thumb_bit() {
+            Width::HalfWord
+        } else {
+            Width::Word
+        }
+    }
+
+    /// Reads an instruction for the pipeline, in whichever state the CPU is in.
+    fn fetch(&mut self, address: u32) -> u32 {
         if self.cpu.get_thumb_bit() {
-            self.cpu.registers.r15 += 2;
+            self.memory.fetch_half_word(address) as u32
         } else {
-            // Read the instruction and increment the PC before running the
-            // instruction so that we don't do anything weird if the instruction
-            // changes the value of r15.
-            let instruction = self.memory.read_word(self.cpu.registers.r15);
-            self.cpu.registers.r15 += 4;
-
-            let cycles_used = arm::process_instruction(self, instruction);
-            // let cycles_used = arm::process_instruction(self, 0b1110_00_1_0100_1_0011_0011_0000_00000001);
-            self.remaining_cycles = self.remaining_cycles.saturating_sub(cycles_used);
+            self.memory.fetch_word(address)
         }
     }
+
+    /// Fills the pipeline with the two instructions at r15, after it has been
+    /// written to. The bottom bits of the new address are ignored.
+    fn refill_pipeline(&mut self) {
+        let size = self.cpu.instruction_size();
+        let address = self.cpu.registers.r15 & !(size - 1);
+
+        self.cpu.pipeline.opcodes = [self.fetch(address), self.fetch(address + size)];
+        self.cpu.registers.r15 = address + 2 * size;
+        self.cpu.pipeline.flushed = false;
+    }
 }
 
 impl Emulator {
@@ -88,3 +328,112 @@ impl Emulator {
         self.memory.write_half_word(point(120, 96), 0x4fe3);
     }
 }
+
+/// Where the tests put the instructions they run, at the start of external
+/// work RAM.
+#[cfg(test)]
+pub(crate) const TEST_CODE: u32 = memory::EXT_START as u32;
+
+#[cfg(test)]
+impl Emulator {
+    /// An emulator that's about to run the given ARM instructions, starting
+    /// at `TEST_CODE`.
+    pub(crate) fn running(instructions: &[u32]) -> Self {
+        let mut emulator = Emulator::new();
+        emulator.direct_boot();
+        for (index, instruction) in instructions.iter().enumerate() {
+            emulator
+                .memory
+                .write_word(TEST_CODE + index as u32 * 4, *instruction);
+        }
+        emulator
+            .cpu
+            .set_register_value(RegisterNames::r15, TEST_CODE);
+        emulator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TEST_CODE as CODE;
+    use super::*;
+
+    #[test]
+    fn load_a_bios() {
+        let mut emulator = Emulator::new();
+        assert!(emulator.bios.hle);
+
+        assert!(emulator.load_bios(&[0; 16]).is_err());
+        assert!(!emulator.bios.loaded);
+
+        let image = bios::test_image();
+        let last_word =
+            u32::from_le_bytes([image[0x3ffc], image[0x3ffd], image[0x3ffe], image[0x3fff]]);
+        emulator
+            .cpu
+            .set_register_value(RegisterNames::r15, 0x0800_0000);
+        emulator.load_bios(&image).unwrap();
+
+        assert!(emulator.bios.loaded);
+        assert!(!emulator.bios.hle);
+        assert_eq!(emulator.memory.read_word(0x3ffc), last_word);
+        assert_eq!(emulator.cpu.get_register_value(RegisterNames::r15), 0);
+        assert_eq!(emulator.cpu.get_operation_mode(), Some(OperationModes::SVC));
+
+        // Even into memory that started out without room for one
+        let mut emulator = Emulator::dummy();
+        emulator.load_bios(&image).unwrap();
+        assert_eq!(emulator.memory.read_word(0x3ffc), last_word);
+    }
+
+    #[test]
+    fn pc_is_two_instructions_ahead() {
+        // mov r0, pc
+        // str pc, [r1]
+        let mut emulator = Emulator::running(&[0xe1a0_000f, 0xe581_f000]);
+        let output = memory::EXT_START as u32 + 0x100;
+        emulator.cpu.set_register_value(RegisterNames::r1, output);
+
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.get_register_value(RegisterNames::r0), CODE + 8);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 4);
+
+        // Stores see it one cycle later
+        emulator.step_instruction();
+        assert_eq!(emulator.memory.read_word(output), CODE + 4 + 12);
+    }
+
+    #[test]
+    fn pc_relative_load() {
+        // ldr r0, [pc, #4]
+        let mut emulator = Emulator::running(&[0xe59f_0004, 0, 0, 0x1234_5678]);
+        emulator.step_instruction();
+        assert_eq!(
+            emulator.cpu.get_register_value(RegisterNames::r0),
+            0x1234_5678
+        );
+    }
+
+    #[test]
+    fn writing_pc_flushes_the_pipeline() {
+        // add pc, pc, #4
+        // mov r0, #1
+        // mov r0, #2
+        // mov r0, #3
+        // mov r0, #4
+        let mut emulator = Emulator::running(&[
+            0xe28f_f004,
+            0xe3a0_0001,
+            0xe3a0_0002,
+            0xe3a0_0003,
+            0xe3a0_0004,
+        ]);
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 12);
+
+        // The instructions that were already fetched are skipped
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.get_register_value(RegisterNames::r0), 3);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 16);
+    }
+}
@@ -0,0 +1,1890 @@
+//! Translates blocks of ARM and Thumb instructions into small WebAssembly
+//! modules, so that the browser can compile them down to native code. Only the
+//! most common instructions are translated: data processing, single loads and
+//! stores, and branches. A block with anything else in it can't be compiled,
+//! and keeps running through the interpreter.
+//!
+//! Each module imports its memory and six functions from `env`:
+//!
+//! - `memory`, which holds the registers that the block works on, laid out
+//!   as described by the `*_OFFSET` constants
+//! - `read_byte(address) -> value` and `read_word(address) -> value`
+//! - `write_byte(address, value) -> stop` and `write_word(address, value) ->
+//!   stop`, where `stop` is 1 if the block has to leave after the
+//!   instruction, because the write could have changed something that only
+//!   gets noticed between instructions
+//! - `idle(cycles)`, for the cycles that an instruction spends on anything
+//!   other than its own fetch
+//! - `fetch(address)`, for the fetch that the pipeline makes after each
+//!   instruction that doesn't branch
+//!
+//! and exports a single function, `run() -> cycles`. A `Runtime` instantiates
+//! the module with the functions in `host`, and `Emulator::run_compiled` runs
+//! it.
+
+use super::armv4t::arm::internal;
+use super::armv4t::cycles;
+use super::cpu::RegisterNames;
+use super::Emulator;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+/// r0 to r15 are stored as words from the start of the memory. r15 is only
+/// written by the block, and is the address of the next instruction to run.
+pub const REGISTERS_OFFSET: u32 = 0;
+pub const CPSR_OFFSET: u32 = 64;
+/// Written by the block as 1 if it stopped at a branch, which flushes the
+/// pipeline, or 0 if it ran off the end or stopped after a write to IO.
+pub const BRANCHED_OFFSET: u32 = 68;
+/// How many bytes of the memory are used.
+pub const STATE_SIZE: usize = 72;
+
+/// The indices of the imported functions.
+const READ_BYTE: u32 = 0;
+const READ_WORD: u32 = 1;
+const WRITE_BYTE: u32 = 2;
+const WRITE_WORD: u32 = 3;
+const IDLE: u32 = 4;
+const FETCH: u32 = 5;
+/// The exported function comes after the imported ones.
+const RUN: u32 = 6;
+
+/// r0 to r14 are kept in locals 0 to 14 while the block is running, and the
+/// rest are scratch space.
+const CPSR: u32 = 15;
+const CYCLES: u32 = 16;
+const FIRST: u32 = 17;
+const SECOND: u32 = 18;
+const RESULT: u32 = 19;
+const CARRY: u32 = 20;
+const ADDRESS: u32 = 21;
+/// Set by stores that the block has to leave after.
+const STOP: u32 = 22;
+const I32_LOCALS: u32 = 23;
+/// Local 23 is the only 64-bit one, for working out carries.
+const WIDE: u32 = 23;
+
+// The opcodes that the recompiler uses
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_LOAD: u8 = 0x28;
+const I32_STORE: u8 = 0x36;
+const I32_CONST: u8 = 0x41;
+const I32_EQZ: u8 = 0x45;
+const I32_GE_U: u8 = 0x4f;
+const I64_GE_U: u8 = 0x5a;
+const I32_ADD: u8 = 0x6a;
+const I32_SUB: u8 = 0x6b;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const I32_XOR: u8 = 0x73;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_S: u8 = 0x75;
+const I32_SHR_U: u8 = 0x76;
+const I32_ROTR: u8 = 0x78;
+const I64_ADD: u8 = 0x7c;
+const I64_SHR_U: u8 = 0x88;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_U: u8 = 0xad;
+const I64_CONST: u8 = 0x42;
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const EMPTY: u8 = 0x40;
+
+/// Reasons that a block can't be compiled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    /// There's nothing in the block.
+    Empty,
+    /// The instruction at the address isn't one that the recompiler knows
+    /// how to translate.
+    Unsupported { address: u32, instruction: u32 },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Empty => write!(f, "Block has no instructions in it"),
+            CompileError::Unsupported {
+                address,
+                instruction,
+            } => write!(
+                f,
+                "Instruction {:#010x} at {:#010x} can't be recompiled",
+                instruction, address
+            ),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+/// A block of ARM or Thumb instructions, translated into a WebAssembly module.
+#[derive(Clone, Debug)]
+pub struct CompiledBlock {
+    /// The address of the first instruction.
+    pub address: u32,
+    /// How many instructions were translated.
+    pub length: usize,
+    /// The WebAssembly module, ready to be instantiated.
+    pub module: Vec<u8>,
+}
+
+/// Translates the ARM instructions starting at `address` into a WebAssembly
+/// module. The block stops early at any branch that is taken.
+pub fn compile(address: u32, instructions: &[u32]) -> Result<CompiledBlock, CompileError> {
+    compile_block(address, 4, instructions, arm)
+}
+
+/// Translates Thumb instructions in the same way as `compile`.
+pub fn compile_thumb(address: u32, instructions: &[u16]) -> Result<CompiledBlock, CompileError> {
+    let instructions: Vec<u32> = instructions
+        .iter()
+        .map(|instruction| *instruction as u32)
+        .collect();
+    compile_block(address, 2, &instructions, thumb)
+}
+
+fn compile_block(
+    address: u32,
+    size: u32,
+    instructions: &[u32],
+    translate: fn(&mut Function, u32, u32) -> bool,
+) -> Result<CompiledBlock, CompileError> {
+    if instructions.is_empty() {
+        return Err(CompileError::Empty);
+    }
+
+    let mut body = Function::default();
+    for register in 0..15 {
+        body.i32_const(0);
+        body.load(REGISTERS_OFFSET + register * 4);
+        body.set(register);
+    }
+    body.i32_const(0);
+    body.load(CPSR_OFFSET);
+    body.set(CPSR);
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        let current = address + index as u32 * size;
+        if !translate(&mut body, current, *instruction) {
+            return Err(CompileError::Unsupported {
+                address: current,
+                instruction: *instruction,
+            });
+        }
+
+        // Branches that are taken have already left, so this is the fetch of
+        // whatever comes after the next instruction
+        body.i32_const(current + 2 * size);
+        body.call(FETCH);
+
+        // Writes to IO registers can raise interrupts, start DMA and timers,
+        // or halt the CPU, which all need to happen before the next
+        // instruction
+        if std::mem::take(&mut body.stores) {
+            body.get(STOP);
+            body.op(IF);
+            body.op(EMPTY);
+            body.leave(current + size, false);
+            body.op(END);
+        }
+    }
+
+    body.leave(address + instructions.len() as u32 * size, false);
+    body.op(END);
+
+    Ok(CompiledBlock {
+        address,
+        length: instructions.len(),
+        module: module(&body.code),
+    })
+}
+
+/// Adds the code for a single ARM instruction, which only runs if its
+/// condition passes. Returns false if the instruction can't be translated.
+fn arm(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let mut translated = Function::default();
+    if !translate(&mut translated, address, instruction) {
+        return false;
+    }
+
+    function.stores |= translated.stores;
+    match instruction >> 28 {
+        0xe => function.code.extend(translated.code),
+        // The "never" condition is unpredictable on the ARM7TDMI
+        0xf => return false,
+        condition => {
+            function.condition(condition);
+            function.op(IF);
+            function.op(EMPTY);
+            function.code.extend(translated.code);
+            function.op(ELSE);
+            function.executed(cycles::SKIPPED);
+            function.op(END);
+        }
+    }
+    true
+}
+
+/// Adds the code for a single instruction, not including the condition check.
+/// Returns false if the instruction can't be translated.
+fn translate(function: &mut Function, address: u32, instruction: u32) -> bool {
+    match instruction >> 25 & 7 {
+        0b000 | 0b001 => data_processing(function, address, instruction),
+        0b010 | 0b011 => load_store(function, address, instruction),
+        0b101 => branch(function, address, instruction),
+        _ => false,
+    }
+}
+
+fn data_processing(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let immediate = instruction >> 25 & 1 == 1;
+    let opcode = instruction >> 21 & 0xf;
+    let set_flags = instruction >> 20 & 1 == 1;
+    let rn = instruction >> 16 & 0xf;
+    let rd = instruction >> 12 & 0xf;
+    let compare = (0b1000..=0b1011).contains(&opcode);
+
+    // Register shifts share their encoding with multiplies and the extra
+    // loads and stores, and comparisons without the S bit are the
+    // miscellaneous instructions. Writing to r15 changes where the block goes.
+    if !immediate && instruction >> 4 & 1 == 1 {
+        return false;
+    }
+    if compare && !set_flags {
+        return false;
+    }
+    if !compare && rd == 15 {
+        return false;
+    }
+
+    // Everything works out the shifter operand and its carry first
+    if immediate {
+        let rotate = (instruction >> 8 & 0xf) * 2;
+        let value = (instruction & 0xff).rotate_right(rotate);
+        function.i32_const(value);
+        function.set(SECOND);
+        if rotate == 0 {
+            function.flag(29);
+        } else {
+            function.i32_const(value >> 31);
+        }
+        function.set(CARRY);
+    } else {
+        shifted_register(function, address, instruction, true);
+    }
+
+    function.register(rn, address);
+    function.set(FIRST);
+
+    // Then the result, and which flags it changes
+    let arithmetic = match opcode {
+        0b0000 | 0b1000 => logical(function, I32_AND, false),
+        0b0001 | 0b1001 => logical(function, I32_XOR, false),
+        0b1100 => logical(function, I32_OR, false),
+        0b1110 => logical(function, I32_AND, true),
+        0b1101 => {
+            function.get(SECOND);
+            function.set(RESULT);
+            false
+        }
+        0b1111 => {
+            function.i32_const(!0);
+            function.get(SECOND);
+            function.op(I32_XOR);
+            function.set(RESULT);
+            false
+        }
+        0b0010 | 0b1010 => subtract(function, FIRST, SECOND, false),
+        0b0011 => subtract(function, SECOND, FIRST, false),
+        0b0110 => subtract(function, FIRST, SECOND, true),
+        0b0111 => subtract(function, SECOND, FIRST, true),
+        0b0100 | 0b1011 => add(function, false),
+        0b0101 => add(function, true),
+        _ => unreachable!(),
+    };
+
+    if !compare {
+        function.get(RESULT);
+        function.set(rd);
+    }
+
+    if set_flags {
+        function.set_flags(arithmetic);
+    }
+
+    function.executed(cycles::data_processing(opcode));
+    true
+}
+
+/// Works out a register shifted by an immediate amount into `SECOND`, and the
+/// carry out of the shifter into `CARRY` if `carry` is set.
+fn shifted_register(function: &mut Function, address: u32, instruction: u32, carry: bool) {
+    let rm = instruction & 0xf;
+    let shift = instruction >> 7 & 0x1f;
+
+    // The carry is always one of the bits of the register, apart from when
+    // nothing is shifted at all
+    let carry_bit = match (instruction >> 5 & 3, shift) {
+        // LSL
+        (0, 0) => None,
+        (0, _) => Some(32 - shift),
+        // LSR and ASR by 0 mean by 32
+        (1, 0) | (2, 0) => Some(31),
+        // RRX
+        (3, 0) => Some(0),
+        (_, _) => Some(shift - 1),
+    };
+
+    if carry {
+        match carry_bit {
+            Some(bit) => {
+                function.register(rm, address);
+                function.i32_const(bit);
+                function.op(I32_SHR_U);
+                function.i32_const(1);
+                function.op(I32_AND);
+            }
+            None => function.flag(29),
+        }
+        function.set(CARRY);
+    }
+
+    match (instruction >> 5 & 3, shift) {
+        (0, _) => {
+            function.register(rm, address);
+            function.i32_const(shift);
+            function.op(I32_SHL);
+        }
+        (1, 0) => function.i32_const(0),
+        (1, _) => {
+            function.register(rm, address);
+            function.i32_const(shift);
+            function.op(I32_SHR_U);
+        }
+        (2, _) => {
+            function.register(rm, address);
+            function.i32_const(if shift == 0 { 31 } else { shift });
+            function.op(I32_SHR_S);
+        }
+        (3, 0) => {
+            function.flag(29);
+            function.i32_const(31);
+            function.op(I32_SHL);
+            function.register(rm, address);
+            function.i32_const(1);
+            function.op(I32_SHR_U);
+            function.op(I32_OR);
+        }
+        (_, _) => {
+            function.register(rm, address);
+            function.i32_const(shift);
+            function.op(I32_ROTR);
+        }
+    }
+    function.set(SECOND);
+}
+
+/// AND, EOR and ORR, and BIC when `invert` is set. The carry comes from the
+/// shifter, so there's nothing else to work out.
+fn logical(function: &mut Function, opcode: u8, invert: bool) -> bool {
+    function.get(FIRST);
+    function.get(SECOND);
+    if invert {
+        function.i32_const(!0);
+        function.op(I32_XOR);
+    }
+    function.op(opcode);
+    function.set(RESULT);
+    false
+}
+
+/// ADD and CMN, or ADC if `with_carry` is set. The carry comes from doing the
+/// addition with 64 bits.
+fn add(function: &mut Function, with_carry: bool) -> bool {
+    function.get(FIRST);
+    function.op(I64_EXTEND_I32_U);
+    function.get(SECOND);
+    function.op(I64_EXTEND_I32_U);
+    function.op(I64_ADD);
+    if with_carry {
+        function.flag(29);
+        function.op(I64_EXTEND_I32_U);
+        function.op(I64_ADD);
+    }
+    function.set(WIDE);
+
+    function.get(WIDE);
+    function.op(I32_WRAP_I64);
+    function.set(RESULT);
+
+    function.get(WIDE);
+    function.op(I64_CONST);
+    function.code.push(32);
+    function.op(I64_SHR_U);
+    function.op(I32_WRAP_I64);
+    function.set(CARRY);
+
+    // Overflow if the result has a different sign to both operands
+    function.get(FIRST);
+    function.get(RESULT);
+    function.op(I32_XOR);
+    function.get(SECOND);
+    function.get(RESULT);
+    function.op(I32_XOR);
+    function.op(I32_AND);
+    function.set(SECOND);
+    true
+}
+
+/// SUB, RSB and CMP, or SBC and RSC if `with_carry` is set. Takes the locals
+/// to subtract, so that the reversed versions can swap them around.
+fn subtract(function: &mut Function, left: u32, right: u32, with_carry: bool) -> bool {
+    function.get(left);
+    function.get(right);
+    function.op(I32_SUB);
+    if with_carry {
+        function.flag(29);
+        function.i32_const(1);
+        function.op(I32_XOR);
+        function.op(I32_SUB);
+    }
+    function.set(RESULT);
+
+    // There's no borrow if the right side (plus the borrow that's already
+    // there) is no bigger than the left
+    if with_carry {
+        function.get(left);
+        function.op(I64_EXTEND_I32_U);
+        function.get(right);
+        function.op(I64_EXTEND_I32_U);
+        function.flag(29);
+        function.i32_const(1);
+        function.op(I32_XOR);
+        function.op(I64_EXTEND_I32_U);
+        function.op(I64_ADD);
+        function.op(I64_GE_U);
+    } else {
+        function.get(left);
+        function.get(right);
+        function.op(I32_GE_U);
+    }
+    function.set(CARRY);
+
+    // Overflow if the operands have different signs, and the result has a
+    // different sign to the left one
+    function.get(left);
+    function.get(right);
+    function.op(I32_XOR);
+    function.get(left);
+    function.get(RESULT);
+    function.op(I32_XOR);
+    function.op(I32_AND);
+    function.set(SECOND);
+    true
+}
+
+fn load_store(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let register_offset = instruction >> 25 & 1 == 1;
+    let pre_indexed = instruction >> 24 & 1 == 1;
+    let add_offset = instruction >> 23 & 1 == 1;
+    let byte = instruction >> 22 & 1 == 1;
+    let write_back = instruction >> 21 & 1 == 1 || !pre_indexed;
+    let load = instruction >> 20 & 1 == 1;
+    let rn = instruction >> 16 & 0xf;
+    let rd = instruction >> 12 & 0xf;
+
+    // Bit 4 is set for undefined instructions, and post-indexing with the W
+    // bit set is the "with translation" versions. Loading into r15 or writing
+    // back to it would branch, and writing back to the register that's loaded
+    // or stored is unpredictable, as is storing r15 as a byte.
+    if register_offset && (instruction >> 4 & 1 == 1 || instruction & 0xf == 15) {
+        return false;
+    }
+    if !pre_indexed && instruction >> 21 & 1 == 1 {
+        return false;
+    }
+    if ((load || byte) && rd == 15) || (write_back && (rn == 15 || rn == rd)) {
+        return false;
+    }
+
+    if register_offset {
+        shifted_register(function, address, instruction, false);
+    } else {
+        function.i32_const(instruction & 0xfff);
+        function.set(SECOND);
+    }
+
+    function.register(rn, address);
+    function.get(SECOND);
+    function.op(if add_offset { I32_ADD } else { I32_SUB });
+    function.set(RESULT);
+
+    if pre_indexed {
+        function.get(RESULT);
+    } else {
+        function.register(rn, address);
+    }
+    function.set(ADDRESS);
+
+    // The base register is written back before the access, the same as in
+    // the interpreter
+    if write_back {
+        function.get(RESULT);
+        function.set(rn);
+    }
+
+    match (load, byte) {
+        (true, false) => {
+            function.read_rotated_word();
+            function.set(rd);
+        }
+        (true, true) => {
+            function.get(ADDRESS);
+            function.call(READ_BYTE);
+            function.set(rd);
+        }
+        (false, _) => {
+            function.get(ADDRESS);
+            if !byte {
+                function.i32_const(!3);
+                function.op(I32_AND);
+            }
+            // Stores see r15 one instruction later
+            if rd == 15 {
+                function.i32_const(address + 12);
+            } else {
+                function.get(rd);
+            }
+            function.write(byte);
+        }
+    }
+
+    function.executed(cycles::load_store(load, byte));
+    true
+}
+
+fn branch(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let offset = ((instruction << 8) as i32 >> 6) as u32;
+    let target = (address + 8).wrapping_add(offset);
+
+    if instruction >> 24 & 1 == 1 {
+        function.i32_const(address + 4);
+        function.set(14);
+    }
+
+    function.executed(cycles::BRANCH);
+    function.leave(target, true);
+    true
+}
+
+/// Adds the code for a single Thumb instruction. Returns false if the
+/// instruction can't be translated.
+fn thumb(function: &mut Function, address: u32, instruction: u32) -> bool {
+    match instruction >> 13 {
+        0b000 if instruction >> 11 & 3 == 3 => thumb_add_subtract(function, instruction),
+        0b000 => thumb_shift(function, instruction),
+        0b001 => thumb_immediate(function, instruction),
+        0b010 => match instruction >> 10 & 7 {
+            0b000 => thumb_data_processing(function, instruction),
+            0b001 => thumb_high_registers(function, address, instruction),
+            _ => false,
+        },
+        0b011 => thumb_load_store(function, instruction),
+        0b110 if instruction >> 12 & 1 == 1 => {
+            thumb_conditional_branch(function, address, instruction)
+        }
+        0b111 => thumb_branch(function, address, instruction),
+        _ => false,
+    }
+}
+
+/// LSL, LSR and ASR by an immediate, which work the same as ARM's shifted
+/// registers.
+fn thumb_shift(function: &mut Function, instruction: u32) -> bool {
+    let rd = instruction & 7;
+    let rm = instruction >> 3 & 7;
+    let shift = instruction >> 6 & 0x1f;
+    let opcode = instruction >> 11 & 3;
+
+    // The address is only used for reading r15, which Thumb shifts can't
+    shifted_register(function, 0, rm | opcode << 5 | shift << 7, true);
+    function.get(SECOND);
+    function.set(rd);
+    function.get(SECOND);
+    function.set(RESULT);
+    function.set_flags(false);
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// ADD and SUB, with either a register or a 3-bit immediate.
+fn thumb_add_subtract(function: &mut Function, instruction: u32) -> bool {
+    let rd = instruction & 7;
+    let rn = instruction >> 3 & 7;
+    let operand = instruction >> 6 & 7;
+    let immediate = instruction >> 10 & 1 == 1;
+    let sub = instruction >> 9 & 1 == 1;
+
+    function.get(rn);
+    function.set(FIRST);
+    if immediate {
+        function.i32_const(operand);
+    } else {
+        function.get(operand);
+    }
+    function.set(SECOND);
+
+    if sub {
+        subtract(function, FIRST, SECOND, false);
+    } else {
+        add(function, false);
+    }
+    function.get(RESULT);
+    function.set(rd);
+    function.set_flags(true);
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// MOV, CMP, ADD and SUB with an 8-bit immediate.
+fn thumb_immediate(function: &mut Function, instruction: u32) -> bool {
+    let rd = instruction >> 8 & 7;
+    let opcode = instruction >> 11 & 3;
+
+    function.get(rd);
+    function.set(FIRST);
+    function.i32_const(instruction & 0xff);
+    function.set(SECOND);
+
+    let arithmetic = match opcode {
+        0b00 => {
+            function.get(SECOND);
+            function.set(RESULT);
+            function.flag(29);
+            function.set(CARRY);
+            false
+        }
+        0b10 => add(function, false),
+        _ => subtract(function, FIRST, SECOND, false),
+    };
+    if opcode != 0b01 {
+        function.get(RESULT);
+        function.set(rd);
+    }
+    function.set_flags(arithmetic);
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// The ALU operations on two low registers. Shifts by a register and
+/// multiplies aren't translated.
+fn thumb_data_processing(function: &mut Function, instruction: u32) -> bool {
+    let rd = instruction & 7;
+    let rm = instruction >> 3 & 7;
+    let opcode = instruction >> 6 & 0xf;
+
+    if matches!(opcode, 0b0010 | 0b0011 | 0b0100 | 0b0111 | 0b1101) {
+        return false;
+    }
+
+    function.get(rd);
+    function.set(FIRST);
+    function.get(rm);
+    function.set(SECOND);
+
+    // Logical operations leave the carry alone
+    function.flag(29);
+    function.set(CARRY);
+
+    let arithmetic = match opcode {
+        0b0000 | 0b1000 => logical(function, I32_AND, false),
+        0b0001 => logical(function, I32_XOR, false),
+        0b1100 => logical(function, I32_OR, false),
+        0b1110 => logical(function, I32_AND, true),
+        0b1111 => {
+            function.i32_const(!0);
+            function.get(SECOND);
+            function.op(I32_XOR);
+            function.set(RESULT);
+            false
+        }
+        0b0101 => add(function, true),
+        0b1011 => add(function, false),
+        0b0110 => subtract(function, FIRST, SECOND, true),
+        0b1010 => subtract(function, FIRST, SECOND, false),
+        0b1001 => {
+            function.i32_const(0);
+            function.set(FIRST);
+            subtract(function, FIRST, SECOND, false)
+        }
+        _ => unreachable!(),
+    };
+
+    // TST, CMP and CMN only set the flags
+    if !matches!(opcode, 0b1000 | 0b1010 | 0b1011) {
+        function.get(RESULT);
+        function.set(rd);
+    }
+    function.set_flags(arithmetic);
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// ADD, CMP and MOV with high registers. Writing to r15 would branch, and
+/// isn't translated, along with BX.
+fn thumb_high_registers(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let rd = (instruction >> 4 & 8) | instruction & 7;
+    let rm = instruction >> 3 & 0xf;
+    let opcode = instruction >> 8 & 3;
+
+    if opcode == 0b11 || (opcode != 0b01 && rd == 15) {
+        return false;
+    }
+
+    function.thumb_register(rd, address);
+    function.set(FIRST);
+    function.thumb_register(rm, address);
+    function.set(SECOND);
+
+    match opcode {
+        0b00 => {
+            function.get(FIRST);
+            function.get(SECOND);
+            function.op(I32_ADD);
+            function.set(rd);
+        }
+        0b01 => {
+            subtract(function, FIRST, SECOND, false);
+            function.set_flags(true);
+        }
+        _ => {
+            function.get(SECOND);
+            function.set(rd);
+        }
+    }
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// LDR, LDRB and STR with a 5-bit immediate offset.
+fn thumb_load_store(function: &mut Function, instruction: u32) -> bool {
+    let rd = instruction & 7;
+    let rn = instruction >> 3 & 7;
+    let offset = instruction >> 6 & 0x1f;
+    let byte = instruction >> 12 & 1 == 1;
+    let load = instruction >> 11 & 1 == 1;
+
+    if byte && !load {
+        return false;
+    }
+
+    function.get(rn);
+    function.i32_const(if byte { offset } else { offset << 2 });
+    function.op(I32_ADD);
+    function.set(ADDRESS);
+
+    match (load, byte) {
+        (true, false) => {
+            function.read_rotated_word();
+            function.set(rd);
+        }
+        (true, true) => {
+            function.get(ADDRESS);
+            function.call(READ_BYTE);
+            function.set(rd);
+        }
+        (false, _) => {
+            function.get(ADDRESS);
+            function.get(rd);
+            function.write(false);
+        }
+    }
+
+    function.executed(cycles::THUMB);
+    true
+}
+
+/// B with a condition, which only leaves the block if the condition passes.
+fn thumb_conditional_branch(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let condition = instruction >> 8 & 0xf;
+    if condition >= 0xe {
+        return false;
+    }
+
+    let offset = ((instruction << 24) as i32 >> 23) as u32;
+    function.executed(cycles::THUMB);
+    function.condition(condition);
+    function.op(IF);
+    function.op(EMPTY);
+    function.leave((address + 4).wrapping_add(offset), true);
+    function.op(END);
+    true
+}
+
+/// B without a condition, and both halves of BL. The second half of BL jumps
+/// to wherever the first half left in r14.
+fn thumb_branch(function: &mut Function, address: u32, instruction: u32) -> bool {
+    let offset = instruction & 0x7ff;
+
+    match instruction >> 11 & 3 {
+        0b00 => {
+            let offset = ((offset << 21) as i32 >> 20) as u32;
+            function.executed(cycles::THUMB);
+            function.leave((address + 4).wrapping_add(offset), true);
+        }
+        0b10 => {
+            let offset = ((offset << 21) as i32 >> 9) as u32;
+            function.i32_const((address + 4).wrapping_add(offset));
+            function.set(14);
+            function.executed(cycles::THUMB);
+        }
+        0b11 => {
+            function.get(14);
+            function.i32_const(offset << 1);
+            function.op(I32_ADD);
+            function.set(ADDRESS);
+            function.i32_const((address + 2) | 1);
+            function.set(14);
+            function.executed(cycles::THUMB);
+            function.leave_to(ADDRESS, true);
+        }
+        // BLX is only on later architectures
+        _ => return false,
+    }
+    true
+}
+
+/// The code of the function that the module exports.
+#[derive(Default)]
+struct Function {
+    code: Vec<u8>,
+    /// Whether the instruction being translated has a store in it, which the
+    /// block might need to leave after.
+    stores: bool,
+}
+
+impl Function {
+    fn op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+    }
+
+    fn get(&mut self, local: u32) {
+        self.op(LOCAL_GET);
+        unsigned(&mut self.code, local);
+    }
+
+    fn set(&mut self, local: u32) {
+        self.op(LOCAL_SET);
+        unsigned(&mut self.code, local);
+    }
+
+    fn i32_const(&mut self, value: u32) {
+        self.op(I32_CONST);
+        signed(&mut self.code, value as i32 as i64);
+    }
+
+    fn load(&mut self, offset: u32) {
+        self.op(I32_LOAD);
+        unsigned(&mut self.code, 2);
+        unsigned(&mut self.code, offset);
+    }
+
+    fn store(&mut self, offset: u32) {
+        self.op(I32_STORE);
+        unsigned(&mut self.code, 2);
+        unsigned(&mut self.code, offset);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.op(CALL);
+        unsigned(&mut self.code, function);
+    }
+
+    /// Reads a register, which for r15 is the address of the instruction
+    /// plus 8.
+    fn register(&mut self, register: u32, address: u32) {
+        if register == 15 {
+            self.i32_const(address + 8);
+        } else {
+            self.get(register);
+        }
+    }
+
+    /// Reads a register in Thumb state, where r15 is only 4 ahead.
+    fn thumb_register(&mut self, register: u32, address: u32) {
+        if register == 15 {
+            self.i32_const(address + 4);
+        } else {
+            self.get(register);
+        }
+    }
+
+    /// Pushes a single bit of the CPSR.
+    fn flag(&mut self, bit: u32) {
+        self.get(CPSR);
+        self.i32_const(bit);
+        self.op(I32_SHR_U);
+        self.i32_const(1);
+        self.op(I32_AND);
+    }
+
+    /// Adds up the cycles that an instruction took, and lets the cartridge
+    /// prefetch during the ones that weren't spent on its own fetch, the same
+    /// as the interpreter does.
+    /// Reads the word at `ADDRESS` onto the stack. Unaligned words are rotated
+    /// so that the addressed byte is at the bottom.
+    fn read_rotated_word(&mut self) {
+        self.get(ADDRESS);
+        self.i32_const(!3);
+        self.op(I32_AND);
+        self.call(READ_WORD);
+        self.get(ADDRESS);
+        self.i32_const(3);
+        self.op(I32_AND);
+        self.i32_const(3);
+        self.op(I32_SHL);
+        self.op(I32_ROTR);
+    }
+
+    /// Writes the value on the stack to the address under it, and notes
+    /// whether the block needs to stop once the instruction has finished.
+    fn write(&mut self, byte: bool) {
+        self.call(if byte { WRITE_BYTE } else { WRITE_WORD });
+        self.set(STOP);
+        self.stores = true;
+    }
+
+    fn executed(&mut self, cycles: u32) {
+        self.get(CYCLES);
+        self.i32_const(cycles);
+        self.op(I32_ADD);
+        self.set(CYCLES);
+
+        if cycles > 1 {
+            self.i32_const(cycles - 1);
+            self.call(IDLE);
+        }
+    }
+
+    /// Sets N and Z from `RESULT` and C from `CARRY`. Arithmetic instructions
+    /// also set V from the top bit of `SECOND`, which they leave it in.
+    fn set_flags(&mut self, arithmetic: bool) {
+        self.get(CPSR);
+        self.i32_const(if arithmetic { 0x0fff_ffff } else { 0x1fff_ffff });
+        self.op(I32_AND);
+
+        self.get(RESULT);
+        self.i32_const(0x8000_0000);
+        self.op(I32_AND);
+        self.op(I32_OR);
+
+        self.get(RESULT);
+        self.op(I32_EQZ);
+        self.i32_const(30);
+        self.op(I32_SHL);
+        self.op(I32_OR);
+
+        self.get(CARRY);
+        self.i32_const(29);
+        self.op(I32_SHL);
+        self.op(I32_OR);
+
+        if arithmetic {
+            self.get(SECOND);
+            self.i32_const(31);
+            self.op(I32_SHR_U);
+            self.i32_const(28);
+            self.op(I32_SHL);
+            self.op(I32_OR);
+        }
+
+        self.set(CPSR);
+    }
+
+    /// Pushes whether the condition passes, for any condition apart from AL
+    /// and NV.
+    fn condition(&mut self, condition: u32) {
+        let (n, z, c, v) = (31, 30, 29, 28);
+
+        match condition {
+            0x0 | 0x1 => self.flag(z),
+            0x2 | 0x3 => self.flag(c),
+            0x4 | 0x5 => self.flag(n),
+            0x6 | 0x7 => self.flag(v),
+            // HI and LS: C set and Z clear
+            0x8 | 0x9 => {
+                self.flag(z);
+                self.op(I32_EQZ);
+                self.flag(c);
+                self.op(I32_AND);
+            }
+            // GE and LT: N equals V
+            0xa | 0xb => {
+                self.flag(n);
+                self.flag(v);
+                self.op(I32_XOR);
+                self.op(I32_EQZ);
+            }
+            // GT and LE: Z clear and N equals V
+            _ => {
+                self.flag(n);
+                self.flag(v);
+                self.op(I32_XOR);
+                self.flag(z);
+                self.op(I32_OR);
+                self.op(I32_EQZ);
+            }
+        }
+
+        // Odd conditions are the opposite of the even one before them
+        if condition & 1 == 1 {
+            self.op(I32_EQZ);
+        }
+    }
+
+    /// Copies everything back into the memory, and returns from the block
+    /// with `next` as the next instruction to run.
+    fn leave(&mut self, next: u32, branched: bool) {
+        self.i32_const(next);
+        self.set(ADDRESS);
+        self.leave_to(ADDRESS, branched);
+    }
+
+    /// The same as `leave`, but with the next instruction in a local.
+    fn leave_to(&mut self, next: u32, branched: bool) {
+        for register in 0..15 {
+            self.i32_const(0);
+            self.get(register);
+            self.store(REGISTERS_OFFSET + register * 4);
+        }
+        self.i32_const(0);
+        self.get(next);
+        self.store(REGISTERS_OFFSET + 15 * 4);
+        self.i32_const(0);
+        self.get(CPSR);
+        self.store(CPSR_OFFSET);
+        self.i32_const(0);
+        self.i32_const(branched as u32);
+        self.store(BRANCHED_OFFSET);
+
+        self.get(CYCLES);
+        self.op(RETURN);
+    }
+}
+
+/// Wraps the code of `run` up into a module with its imports and export.
+fn module(code: &[u8]) -> Vec<u8> {
+    let mut module = b"\0asm".to_vec();
+    module.extend(&[1, 0, 0, 0]);
+
+    // Types: run, the reads, the writes, and idle and fetch
+    section(
+        &mut module,
+        1,
+        &[
+            4, 0x60, 0, 1, I32, 0x60, 1, I32, 1, I32, 0x60, 2, I32, I32, 1, I32, 0x60, 1, I32, 0,
+        ],
+    );
+
+    let mut imports = vec![7];
+    import(&mut imports, "memory");
+    // Memory with at least one page, and no maximum
+    imports.extend(&[0x02, 0, 1]);
+    for (name, ty) in &[
+        ("read_byte", 1),
+        ("read_word", 1),
+        ("write_byte", 2),
+        ("write_word", 2),
+        ("idle", 3),
+        ("fetch", 3),
+    ] {
+        import(&mut imports, name);
+        imports.extend(&[0x00, *ty]);
+    }
+    section(&mut module, 2, &imports);
+
+    section(&mut module, 3, &[1, 0]);
+
+    let mut exports = vec![1];
+    name(&mut exports, "run");
+    exports.push(0x00);
+    unsigned(&mut exports, RUN);
+    section(&mut module, 7, &exports);
+
+    let mut body = vec![2];
+    unsigned(&mut body, I32_LOCALS);
+    body.push(I32);
+    body.extend(&[1, I64]);
+    body.extend(code);
+    let mut functions = vec![1];
+    unsigned(&mut functions, body.len() as u32);
+    functions.extend(body);
+    section(&mut module, 10, &functions);
+
+    module
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    unsigned(module, contents.len() as u32);
+    module.extend(contents);
+}
+
+fn import(imports: &mut Vec<u8>, field: &str) {
+    name(imports, "env");
+    name(imports, field);
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    unsigned(bytes, name.len() as u32);
+    bytes.extend(name.as_bytes());
+}
+
+/// Numbers in WebAssembly are written 7 bits at a time, with the top bit set
+/// on every byte apart from the last.
+fn unsigned(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Signed numbers stop once the rest of the bits match the sign bit of the
+/// last byte.
+fn signed(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// The functions that compiled blocks import to use the memory. They keep
+/// track of wait states in the same way as the interpreter.
+pub mod host {
+    use super::internal;
+    use crate::emulator::memory::IO_START;
+    use crate::emulator::Emulator;
+
+    pub fn read_byte(emulator: &mut Emulator, address: u32) -> u32 {
+        internal::read_byte(emulator, address) as u32
+    }
+
+    pub fn read_word(emulator: &mut Emulator, address: u32) -> u32 {
+        internal::read_word(emulator, address)
+    }
+
+    /// Returns 1 if the block should stop after the instruction that did the
+    /// write.
+    pub fn write_byte(emulator: &mut Emulator, address: u32, value: u32) -> u32 {
+        internal::write_byte(emulator, address, value as u8);
+        stops(address)
+    }
+
+    pub fn write_word(emulator: &mut Emulator, address: u32, value: u32) -> u32 {
+        internal::write_word(emulator, address, value);
+        stops(address)
+    }
+
+    /// Only writes to IO registers can have effects that the block wouldn't
+    /// notice by itself.
+    fn stops(address: u32) -> u32 {
+        (address as usize >> 24 == IO_START >> 24) as u32
+    }
+
+    pub fn idle(emulator: &mut Emulator, cycles: u32) {
+        emulator.memory.timing.idle(cycles);
+    }
+
+    pub fn fetch(emulator: &mut Emulator, address: u32) {
+        let width = emulator.fetch_width();
+        emulator.memory.timing.fetch(address, width);
+    }
+}
+
+/// Somewhere to run compiled blocks. In the browser, this is the browser's
+/// own WebAssembly engine.
+pub trait Runtime {
+    /// Instantiates a block with the functions in `host` as its imports.
+    /// Returns nothing if the runtime can't, in which case the interpreter
+    /// keeps running the block.
+    fn instantiate(&mut self, block: &CompiledBlock) -> Option<Rc<dyn Instance>>;
+}
+
+/// A compiled block that is ready to run.
+pub trait Instance {
+    /// Runs the block with a copy of `state` as its memory, and copies the
+    /// memory back afterwards. Returns the cycles that the block used.
+    fn run(&self, emulator: &mut Emulator, state: &mut [u8]) -> u32;
+}
+
+impl Emulator {
+    /// Copies the registers into the memory of a compiled block, before it
+    /// runs.
+    pub fn enter_compiled(&self, state: &mut [u8]) {
+        for register in 0..15 {
+            let value = self
+                .cpu
+                .get_register_value(RegisterNames::try_from(register).unwrap());
+            write(state, REGISTERS_OFFSET + register * 4, value);
+        }
+        write(state, CPSR_OFFSET, self.cpu.registers.cpsr);
+    }
+
+    /// Copies the registers back after a compiled block has run, and carries
+    /// on from wherever it stopped. The cycles that it returns still need to
+    /// be passed on to the scheduler.
+    pub fn leave_compiled(&mut self, state: &[u8]) {
+        for register in 0..15 {
+            let value = read(state, REGISTERS_OFFSET + register * 4);
+            self.cpu
+                .set_register_value(RegisterNames::try_from(register).unwrap(), value);
+        }
+        self.cpu.registers.cpsr = read(state, CPSR_OFFSET);
+
+        // A branch flushes the pipeline, but otherwise the block has already
+        // fetched the two instructions after the one it stopped at
+        let next = read(state, REGISTERS_OFFSET + 15 * 4);
+        if read(state, BRANCHED_OFFSET) != 0 {
+            self.cpu.set_register_value(RegisterNames::r15, next);
+        } else {
+            self.cpu.registers.r15 = next + 2 * self.cpu.instruction_size();
+            self.cpu.pipeline.flushed = false;
+        }
+    }
+}
+
+fn read(state: &[u8], offset: u32) -> u32 {
+    let offset = offset as usize;
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&state[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn write(state: &mut [u8], offset: u32, value: u32) {
+    let offset = offset as usize;
+    state[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::RegisterNames::*;
+    use crate::emulator::interrupt::{self, Interrupt, INTERRUPT_ENABLE, INTERRUPT_MASTER_ENABLE};
+    use crate::emulator::memory::IO_START;
+    use crate::emulator::TEST_CODE as CODE;
+    use std::cell::RefCell;
+    use wasmi::{Caller, Engine, Linker, Memory, MemoryType, Module, Store, TypedFunc};
+
+    /// Loads and stores use r12 as their base, pointing into the middle of
+    /// this.
+    const DATA: u32 = CODE + 0x1000;
+    const DATA_SIZE: u32 = 0x800;
+
+    /// Runs compiled blocks in an embedded WebAssembly engine, the same way
+    /// that the browser would.
+    pub(crate) struct Wasmi(Engine);
+
+    struct WasmiInstance {
+        /// Points at the emulator while the block is running.
+        store: RefCell<Store<*mut Emulator>>,
+        memory: Memory,
+        run: TypedFunc<(), u32>,
+    }
+
+    impl Wasmi {
+        pub(crate) fn new() -> Self {
+            Wasmi(Engine::default())
+        }
+    }
+
+    impl Runtime for Wasmi {
+        fn instantiate(&mut self, block: &CompiledBlock) -> Option<Rc<dyn Instance>> {
+            let module = Module::new(&self.0, &block.module[..]).ok()?;
+            let mut store = Store::new(&self.0, std::ptr::null_mut());
+            let memory = Memory::new(&mut store, MemoryType::new(1, None).ok()?).ok()?;
+
+            type Host<'a> = Caller<'a, *mut Emulator>;
+            let mut linker = Linker::new(&self.0);
+            linker.define("env", "memory", memory).ok()?;
+            linker
+                .func_wrap("env", "read_byte", |caller: Host, address| {
+                    host::read_byte(unsafe { &mut **caller.data() }, address)
+                })
+                .ok()?;
+            linker
+                .func_wrap("env", "read_word", |caller: Host, address| {
+                    host::read_word(unsafe { &mut **caller.data() }, address)
+                })
+                .ok()?;
+            linker
+                .func_wrap("env", "write_byte", |caller: Host, address, value| {
+                    host::write_byte(unsafe { &mut **caller.data() }, address, value)
+                })
+                .ok()?;
+            linker
+                .func_wrap("env", "write_word", |caller: Host, address, value| {
+                    host::write_word(unsafe { &mut **caller.data() }, address, value)
+                })
+                .ok()?;
+            linker
+                .func_wrap("env", "idle", |caller: Host, cycles| {
+                    host::idle(unsafe { &mut **caller.data() }, cycles)
+                })
+                .ok()?;
+            linker
+                .func_wrap("env", "fetch", |caller: Host, address| {
+                    host::fetch(unsafe { &mut **caller.data() }, address)
+                })
+                .ok()?;
+
+            let instance = linker
+                .instantiate(&mut store, &module)
+                .ok()?
+                .start(&mut store)
+                .ok()?;
+            let run = instance.get_typed_func::<(), u32>(&store, "run").ok()?;
+            Some(Rc::new(WasmiInstance {
+                store: RefCell::new(store),
+                memory,
+                run,
+            }))
+        }
+    }
+
+    impl Instance for WasmiInstance {
+        fn run(&self, emulator: &mut Emulator, state: &mut [u8]) -> u32 {
+            let mut store = self.store.borrow_mut();
+            *store.data_mut() = emulator;
+            self.memory.write(&mut *store, 0, state).unwrap();
+            let cycles = self.run.call(&mut *store, ()).unwrap();
+            self.memory.read(&*store, 0, state).unwrap();
+            *store.data_mut() = std::ptr::null_mut();
+            cycles
+        }
+    }
+
+    /// Runs a compiled block, and returns how long it took.
+    fn run_compiled(mut emulator: Emulator, block: &CompiledBlock) -> (Emulator, u64) {
+        let instance = Wasmi::new().instantiate(block).unwrap();
+        let cycles = emulator.run_compiled(&*instance);
+        let wait_states = emulator.memory.timing.take_cycles();
+        (emulator, (cycles + wait_states) as u64)
+    }
+
+    /// Steps through the same instructions with the interpreter, until one
+    /// of them branches or it runs off the end.
+    fn run_interpreted(mut emulator: Emulator, length: usize) -> (Emulator, u64) {
+        emulator.cache.enabled = false;
+        let start = emulator.scheduler.now();
+        let end = CODE + length as u32 * emulator.cpu.instruction_size();
+        for _ in 0..length {
+            emulator.step_instruction();
+            if emulator.cpu.pipeline.flushed || emulator.cpu.next_instruction_address() == end {
+                break;
+            }
+        }
+        let now = emulator.scheduler.now();
+        (emulator, now - start)
+    }
+
+    fn prepared(instructions: &[u32], registers: &[u32; 15], flags: u32) -> Emulator {
+        let mut emulator = Emulator::running(instructions);
+        for (register, value) in registers.iter().enumerate() {
+            emulator
+                .cpu
+                .set_register_value(RegisterNames::try_from(register as u32).unwrap(), *value);
+        }
+        emulator.cpu.registers.cpsr = emulator.cpu.registers.cpsr & 0x0fff_ffff | flags << 28;
+        for offset in (0..DATA_SIZE).step_by(4) {
+            emulator
+                .memory
+                .write_word(DATA + offset, offset.wrapping_mul(0x9e37_79b9));
+        }
+        emulator
+    }
+
+    /// The same as `prepared`, but in Thumb state.
+    fn prepared_thumb(instructions: &[u16], registers: &[u32; 15], flags: u32) -> Emulator {
+        let words: Vec<u32> = instructions
+            .chunks(2)
+            .map(|pair| pair[0] as u32 | (*pair.get(1).unwrap_or(&0) as u32) << 16)
+            .collect();
+        let mut emulator = prepared(&words, registers, flags);
+        emulator.cpu.set_thumb_bit(true);
+        emulator
+    }
+
+    /// Checks that the compiled block leaves everything the same as the
+    /// interpreter would.
+    fn assert_same(instructions: &[u32], registers: &[u32; 15], flags: u32) {
+        let block = compile(CODE, instructions).unwrap();
+        let prepare = || prepared(instructions, registers, flags);
+        assert_same_block(&block, prepare, &format!("{:08x?}", instructions));
+    }
+
+    fn assert_same_thumb(instructions: &[u16], registers: &[u32; 15], flags: u32) {
+        let block = compile_thumb(CODE, instructions).unwrap();
+        let prepare = || prepared_thumb(instructions, registers, flags);
+        assert_same_block(&block, prepare, &format!("{:04x?}", instructions));
+    }
+
+    fn assert_same_block(block: &CompiledBlock, prepare: impl Fn() -> Emulator, context: &str) {
+        let (mut compiled, compiled_cycles) = run_compiled(prepare(), block);
+        let (mut interpreted, interpreted_cycles) = run_interpreted(prepare(), block.length);
+
+        for register in 0..16 {
+            let register = RegisterNames::try_from(register).unwrap();
+            assert_eq!(
+                compiled.cpu.get_register_value(register),
+                interpreted.cpu.get_register_value(register),
+                "{:?} after {}",
+                register,
+                context
+            );
+        }
+        assert_eq!(
+            compiled.cpu.registers.cpsr, interpreted.cpu.registers.cpsr,
+            "CPSR after {}",
+            context
+        );
+        assert_eq!(
+            compiled.cpu.registers.r15, interpreted.cpu.registers.r15,
+            "r15 after {}",
+            context
+        );
+        assert_eq!(
+            compiled.cpu.pipeline.flushed, interpreted.cpu.pipeline.flushed,
+            "Pipeline after {}",
+            context
+        );
+        for offset in (0..DATA_SIZE).step_by(4) {
+            assert_eq!(
+                compiled.memory.read_word(DATA + offset),
+                interpreted.memory.read_word(DATA + offset),
+                "Memory at {:#x} after {}",
+                DATA + offset,
+                context
+            );
+        }
+        assert_eq!(
+            compiled_cycles, interpreted_cycles,
+            "Cycles for {}",
+            context
+        );
+    }
+
+    /// A tiny random number generator, so that the tests are the same every
+    /// time.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 32) as u32
+        }
+
+        fn below(&mut self, limit: u32) -> u32 {
+            self.next() % limit
+        }
+    }
+
+    /// A random instruction that the recompiler should be able to translate.
+    /// Only r0 to r10 are written, so that r11 and r12 can be used to keep
+    /// loads and stores inside `DATA`.
+    fn random_instruction(random: &mut Random) -> u32 {
+        let condition = random.below(15) << 28;
+        let rd = random.below(11) << 12;
+
+        match random.below(10) {
+            // Loads and stores relative to r12, with an offset that's either
+            // small or r11 shifted a little
+            0..=3 => {
+                let offset = if random.below(2) == 0 {
+                    random.below(0x80)
+                } else {
+                    1 << 25 | random.below(3) << 7 | random.below(3) << 5 | 11
+                };
+                let pre_indexed = random.below(2);
+                let write_back = pre_indexed & random.below(2);
+                let byte = random.below(2);
+                let load = random.below(2);
+                let rd = if byte == 0 && load == 0 && random.below(8) == 0 {
+                    15 << 12
+                } else {
+                    rd
+                };
+                condition
+                    | 1 << 26
+                    | pre_indexed << 24
+                    | random.below(2) << 23
+                    | byte << 22
+                    | write_back << 21
+                    | load << 20
+                    | 12 << 16
+                    | rd
+                    | offset
+            }
+            // Branches, with or without a link
+            4 => condition | 0b101 << 25 | random.below(2) << 24 | random.below(0x100),
+            // Data processing, with any operands
+            _ => {
+                let opcode = random.below(16);
+                let set_flags = if (0b1000..=0b1011).contains(&opcode) {
+                    1
+                } else {
+                    random.below(2)
+                };
+                let operand = if random.below(2) == 0 {
+                    1 << 25 | random.below(0x1000)
+                } else {
+                    random.below(0x100) << 4 & 0xfe0 | random.below(16)
+                };
+                condition | opcode << 21 | set_flags << 20 | random.below(16) << 16 | rd | operand
+            }
+        }
+    }
+
+    /// A random Thumb instruction that the recompiler should be able to
+    /// translate. r7 is kept pointing into `DATA` for loads and stores.
+    fn random_thumb_instruction(random: &mut Random) -> u16 {
+        // Any register apart from r7 and r15, in the low bits, and with the
+        // top bit for the high register instructions
+        let rd = |random: &mut Random, high: bool| loop {
+            let rd = random.below(if high { 15 } else { 8 });
+            if rd != 7 {
+                break rd;
+            }
+        };
+        let rm = random.below(8) << 3;
+
+        let instruction = match random.below(12) {
+            // Shifts, and ADD and SUB with three registers or an immediate
+            0 => random.below(3) << 11 | random.below(32) << 6 | rm | rd(random, false),
+            1 => {
+                0b00011 << 11 | random.below(4) << 9 | random.below(8) << 6 | rm | rd(random, false)
+            }
+            // MOV, CMP, ADD and SUB with an 8-bit immediate
+            2 => 0b001 << 13 | random.below(4) << 11 | rd(random, false) << 8 | random.below(0x100),
+            // Everything on the ALU apart from shifts by a register and MUL
+            3..=5 => {
+                let opcodes = [0, 1, 5, 6, 8, 9, 10, 11, 12, 14, 15];
+                let opcode = opcodes[random.below(opcodes.len() as u32) as usize];
+                0b010000 << 10 | opcode << 6 | rm | rd(random, false)
+            }
+            // ADD, CMP and MOV with high registers, reading anything
+            6 => {
+                let opcode = random.below(3);
+                let rd = if opcode == 1 {
+                    random.below(16)
+                } else {
+                    rd(random, true)
+                };
+                0b010001 << 10 | opcode << 8 | (rd & 8) << 4 | random.below(16) << 3 | rd & 7
+            }
+            // STR, LDR and LDRB relative to r7
+            7 | 8 => {
+                let opcode = [0b01100, 0b01101, 0b01111][random.below(3) as usize];
+                opcode << 11 | random.below(32) << 6 | 7 << 3 | rd(random, false)
+            }
+            // Conditional branches, branches, and either half of BL
+            9 => 0b1101 << 12 | random.below(14) << 8 | random.below(0x100),
+            10 => 0b11100 << 11 | random.below(0x800),
+            _ => (0b11110 | random.below(2)) << 11 | random.below(0x800),
+        };
+        instruction as u16
+    }
+
+    #[test]
+    fn matches_the_interpreter() {
+        let mut random = Random(0x1a7e_d0e5);
+        for _ in 0..500 {
+            let length = 1 + random.below(12) as usize;
+            let instructions: Vec<u32> = (0..length)
+                .map(|_| random_instruction(&mut random))
+                .collect();
+
+            let mut registers = [0; 15];
+            for value in registers.iter_mut() {
+                // Plenty of zeros and edge cases, to hit every flag
+                *value = match random.below(4) {
+                    0 => 0,
+                    1 => 0x8000_0000 ^ random.below(2),
+                    _ => random.next(),
+                };
+            }
+            registers[11] = random.below(16);
+            registers[12] = DATA + DATA_SIZE / 2;
+
+            assert_same(&instructions, &registers, random.below(16));
+        }
+    }
+
+    #[test]
+    fn matches_the_interpreter_in_thumb() {
+        let mut random = Random(0x7b_0b5e);
+        for _ in 0..500 {
+            let length = 1 + random.below(12) as usize;
+            let instructions: Vec<u16> = (0..length)
+                .map(|_| random_thumb_instruction(&mut random))
+                .collect();
+
+            let mut registers = [0; 15];
+            for value in registers.iter_mut() {
+                *value = match random.below(4) {
+                    0 => 0,
+                    1 => 0x8000_0000 ^ random.below(2),
+                    _ => random.next(),
+                };
+            }
+            registers[7] = DATA + DATA_SIZE / 2;
+
+            assert_same_thumb(&instructions, &registers, random.below(16));
+        }
+    }
+
+    #[test]
+    fn unaligned_thumb_loads_are_rotated() {
+        // ldr r0, [r7, #4]
+        let instructions = [0x6878];
+        let mut registers = [0; 15];
+        for offset in 0..4 {
+            registers[7] = DATA + offset;
+            assert_same_thumb(&instructions, &registers, 0);
+        }
+
+        let block = compile_thumb(CODE, &instructions).unwrap();
+        let (mut emulator, _) = run_compiled(prepared_thumb(&instructions, &registers, 0), &block);
+        let word = emulator.memory.read_word(DATA + 4);
+        assert_eq!(emulator.cpu.get_register_value(r0), word.rotate_right(24));
+    }
+
+    #[test]
+    fn loops_stop_at_the_branch() {
+        let instructions = [
+            0xe280_0001, // add r0, r0, #1
+            0xe350_000a, // cmp r0, #10
+            0x1aff_fffc, // bne 0x00
+            0xe5cc_0000, // strb r0, [r12]
+        ];
+        let mut registers = [0; 15];
+        registers[12] = DATA;
+
+        // The first time around, the branch is taken
+        assert_same(&instructions, &registers, 0);
+        let block = compile(CODE, &instructions).unwrap();
+        let (emulator, _) = run_compiled(prepared(&instructions, &registers, 0), &block);
+        assert_eq!(emulator.cpu.get_register_value(r0), 1);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE);
+
+        // And the last time, it falls through to the store
+        registers[0] = 9;
+        assert_same(&instructions, &registers, 0);
+        let (mut emulator, _) = run_compiled(prepared(&instructions, &registers, 0), &block);
+        assert_eq!(emulator.memory.read_byte(DATA), 10);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 16);
+    }
+
+    #[test]
+    fn unsupported_instructions() {
+        assert_eq!(compile(CODE, &[]).unwrap_err(), CompileError::Empty);
+
+        // mov r0, #1
+        // mul r0, r1, r2
+        assert_eq!(
+            compile(CODE, &[0xe3a0_0001, 0xe000_0291]).unwrap_err(),
+            CompileError::Unsupported {
+                address: CODE + 4,
+                instruction: 0xe000_0291
+            }
+        );
+
+        // mov pc, lr
+        assert!(compile(CODE, &[0xe1a0_f00e]).is_err());
+        // ldr pc, [r0]
+        assert!(compile(CODE, &[0xe590_f000]).is_err());
+        // add r0, r0, r1, lsl r2
+        assert!(compile(CODE, &[0xe080_0211]).is_err());
+        // mrs r0, cpsr
+        assert!(compile(CODE, &[0xe10f_0000]).is_err());
+
+        // lsl r0, r1 and mul r0, r1
+        assert!(compile_thumb(CODE, &[0x4088]).is_err());
+        assert!(compile_thumb(CODE, &[0x4348]).is_err());
+        // mov pc, r0 and bx r0
+        assert!(compile_thumb(CODE, &[0x4687]).is_err());
+        assert!(compile_thumb(CODE, &[0x4700]).is_err());
+        // strb r0, [r1] and push {r0}
+        assert!(compile_thumb(CODE, &[0x7008]).is_err());
+        assert!(compile_thumb(CODE, &[0xb401]).is_err());
+    }
+
+    #[test]
+    fn hot_blocks_are_compiled() {
+        // add r0, r0, #1
+        // b 0x00
+        let instructions = [0xe280_0001, 0xeaff_fffd];
+        let mut emulator = prepared(&instructions, &[0; 15], 0);
+        emulator.cache.runtime = Some(Box::new(Wasmi::new()));
+
+        // Looking doesn't count as starting it
+        for _ in 0..15 {
+            emulator.step_instruction();
+            emulator.step_instruction();
+            assert!(emulator.cache.hot_block(CODE).is_none());
+        }
+        emulator.step_instruction();
+        let block = emulator.cache.hot_block(CODE).unwrap();
+        assert_eq!(block.address, CODE);
+        assert_eq!(block.length, 2);
+
+        // From then on, each step runs the whole block
+        assert_eq!(emulator.cpu.get_register_value(r0), 16);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE);
+        for _ in 0..84 {
+            emulator.step_instruction();
+        }
+        assert_eq!(emulator.cpu.get_register_value(r0), 100);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE);
+
+        // And takes just as long as the interpreter would
+        let mut interpreted = prepared(&instructions, &[0; 15], 0);
+        interpreted.cache.enabled = false;
+        while interpreted.cpu.get_register_value(r0) < 100 || !interpreted.cpu.pipeline.flushed {
+            interpreted.step_instruction();
+        }
+        assert_eq!(emulator.scheduler.now(), interpreted.scheduler.now());
+
+        // Blocks with anything else in them stay in the interpreter
+        // mul r0, r1, r2
+        // b 0x100
+        emulator.memory.write_word(CODE + 0x100, 0xe000_0291);
+        emulator.memory.write_word(CODE + 0x104, 0xeaff_fffd);
+        emulator.cpu.set_register_value(r15, CODE + 0x100);
+        for _ in 0..40 {
+            emulator.step_instruction();
+        }
+        assert!(emulator.cache.hot_block(CODE + 0x100).is_none());
+    }
+
+    #[test]
+    fn io_writes_stop_the_block() {
+        // str r0, [r1]
+        // add r2, r2, #1
+        let instructions = [0xe581_0000, 0xe282_2001];
+        let block = compile(CODE, &instructions).unwrap();
+        let mut registers = [0; 15];
+        registers[1] = 0x0400_0010;
+
+        let (emulator, cycles) = run_compiled(prepared(&instructions, &registers, 0), &block);
+        assert_eq!(emulator.cpu.get_register_value(r2), 0);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE + 4);
+        let (_, interpreted_cycles) = run_interpreted(prepared(&instructions, &registers, 0), 1);
+        assert_eq!(cycles, interpreted_cycles);
+
+        // Anywhere else, the block carries on
+        registers[1] = DATA;
+        assert_same(&instructions, &registers, 0);
+        let (emulator, _) = run_compiled(prepared(&instructions, &registers, 0), &block);
+        assert_eq!(emulator.cpu.get_register_value(r2), 1);
+    }
+
+    #[test]
+    fn interrupts_are_taken_straight_after_io_writes() {
+        // str r0, [r1]
+        // add r2, r2, #1
+        // b 0x00
+        let instructions = [0xe581_0000, 0xe282_2001, 0xeaff_fffc];
+        let mut registers = [0; 15];
+        registers[1] = (IO_START + INTERRUPT_MASTER_ENABLE) as u32;
+        let mut emulator = prepared(&instructions, &registers, 0);
+        emulator.cache.runtime = Some(Box::new(Wasmi::new()));
+        emulator
+            .memory
+            .write_half_word((IO_START + INTERRUPT_ENABLE) as u32, 1);
+        interrupt::request(&mut emulator.memory, Interrupt::VBlank);
+
+        while emulator.cache.hot_block(CODE).is_none() {
+            emulator.step_instruction();
+        }
+        while emulator.cpu.next_instruction_address() != CODE {
+            emulator.step_instruction();
+        }
+
+        // Turning on IME in the middle of the block stops it, and the
+        // interrupt is taken before the next instruction
+        let count = emulator.cpu.get_register_value(r2);
+        emulator.cpu.set_register_value(r0, 1);
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.get_register_value(r2), count);
+        emulator.step_instruction();
+        assert_eq!(emulator.cpu.registers.cpsr & 0x1f, 0x12);
+        assert_eq!(emulator.cpu.get_register_value(r14), CODE + 8);
+    }
+
+    #[test]
+    fn hot_thumb_blocks_are_compiled() {
+        // add r0, #1
+        // b 0x00
+        let instructions = [0x3001, 0xe7fd];
+        let mut emulator = prepared_thumb(&instructions, &[0; 15], 0);
+        emulator.cache.runtime = Some(Box::new(Wasmi::new()));
+
+        for _ in 0..31 {
+            emulator.step_instruction();
+        }
+        let block = emulator.cache.hot_block(CODE | 1).unwrap();
+        assert_eq!(block.length, 2);
+
+        for _ in 0..84 {
+            emulator.step_instruction();
+        }
+        assert_eq!(emulator.cpu.get_register_value(r0), 100);
+        assert_eq!(emulator.cpu.next_instruction_address(), CODE);
+
+        let mut interpreted = prepared_thumb(&instructions, &[0; 15], 0);
+        interpreted.cache.enabled = false;
+        while interpreted.cpu.get_register_value(r0) < 100 || !interpreted.cpu.pipeline.flushed {
+            interpreted.step_instruction();
+        }
+        assert_eq!(emulator.scheduler.now(), interpreted.scheduler.now());
+    }
+
+    #[test]
+    fn nothing_is_compiled_without_a_runtime() {
+        // mov r0, #1
+        // b 0x00
+        let mut emulator = prepared(&[0xe3a0_0001, 0xeaff_fffd], &[0; 15], 0);
+        for _ in 0..40 {
+            emulator.step_instruction();
+        }
+        assert!(emulator.cache.hot_block(CODE).is_none());
+    }
+}
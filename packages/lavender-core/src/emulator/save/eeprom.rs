@@ -0,0 +1,221 @@
+/// EEPROM is read and written in blocks of 64 bits.
+pub const BLOCK_SIZE: usize = 8;
+/// The number of bits that are read back after a read request. The first four
+/// are junk, and the rest are the requested block.
+const READ_LENGTH: u32 = 4 + 64;
+
+/// EEPROM chips come in two sizes, which use a different number of bits to
+/// address blocks. There isn't any way to ask the chip which one it is, so we
+/// have to figure it out from how the game talks to it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EepromSize {
+    /// 512 bytes, with 6-bit addresses
+    Small,
+    /// 8KB, with 14-bit addresses (of which only the bottom 10 are used)
+    Large,
+}
+
+impl EepromSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            EepromSize::Small => 512,
+            EepromSize::Large => 8 * 1024,
+        }
+    }
+
+    pub fn address_bits(self) -> u32 {
+        match self {
+            EepromSize::Small => 6,
+            EepromSize::Large => 14,
+        }
+    }
+
+    /// Games always use DMA to send requests, and the number of bits in the
+    /// transfer tells us how wide the address is. Read requests are 2 bits
+    /// for the command, the address, and a stop bit. Write requests also have
+    /// 64 bits of data before the stop bit.
+    pub fn from_transfer_length(length: u32) -> Option<Self> {
+        match length {
+            9 | 73 => Some(EepromSize::Small),
+            17 | 81 => Some(EepromSize::Large),
+            _ => None,
+        }
+    }
+}
+
+pub struct Eeprom {
+    /// The size of the chip, if we know it yet.
+    pub size: Option<EepromSize>,
+    pub data: Vec<u8>,
+    /// The bits of the request that is currently being received, with the
+    /// earliest bit in the most significant position.
+    request: u128,
+    request_length: u32,
+    /// The block that is being read back, and how many bits are left.
+    read_block: u64,
+    read_remaining: u32,
+}
+
+impl Eeprom {
+    /// Creates an EEPROM of the given size. If the size is not known, it will
+    /// be detected the first time that a game sends a request.
+    pub fn new(size: Option<EepromSize>) -> Self {
+        Self {
+            size,
+            data: vec![0xff; size.unwrap_or(EepromSize::Large).bytes()],
+            request: 0,
+            request_length: 0,
+            read_block: 0,
+            read_remaining: 0,
+        }
+    }
+
+    /// Called when a DMA transfer to the EEPROM begins, with the number of
+    /// units being transferred.
+    pub fn detect_size(&mut self, transfer_length: u32) {
+        if self.size.is_some() {
+            return;
+        }
+
+        if let Some(size) = EepromSize::from_transfer_length(transfer_length) {
+            self.size = Some(size);
+            self.data.resize(size.bytes(), 0xff);
+        }
+    }
+
+    /// Reads the next bit of the current read request. When there isn't a
+    /// read in progress, the chip reports that it is ready for another request.
+    pub fn read_bit(&mut self) -> u16 {
+        if self.read_remaining == 0 {
+            return 1;
+        }
+
+        self.read_remaining -= 1;
+
+        if self.read_remaining >= 64 {
+            0
+        } else {
+            (self.read_block >> self.read_remaining & 1) as u16
+        }
+    }
+
+    /// Receives one bit of a request. Only bit 0 of the value is used.
+    pub fn write_bit(&mut self, value: u16) {
+        self.request = self.request << 1 | (value & 1) as u128;
+        self.request_length += 1;
+
+        if self.request_length < 2 {
+            return;
+        }
+
+        let address_bits = self.size.unwrap_or(EepromSize::Small).address_bits();
+        let command = self.request >> (self.request_length - 2) & 0b11;
+
+        match command {
+            // Read request
+            0b11 if self.request_length == 2 + address_bits + 1 => {
+                let address = self.block_address((self.request >> 1) as usize, address_bits);
+                self.read_block = self.data[address..address + BLOCK_SIZE]
+                    .iter()
+                    .fold(0, |block, byte| block << 8 | *byte as u64);
+                self.read_remaining = READ_LENGTH;
+                self.reset_request();
+            }
+            // Write request
+            0b10 if self.request_length == 2 + address_bits + 64 + 1 => {
+                let address = self.block_address((self.request >> 65) as usize, address_bits);
+                let block = (self.request >> 1) as u64;
+                for (index, byte) in block.to_be_bytes().iter().enumerate() {
+                    self.data[address + index] = *byte;
+                }
+                self.reset_request();
+            }
+            0b11 | 0b10 => (),
+            // Not a valid request, so start over
+            _ => self.reset_request(),
+        }
+    }
+
+    /// Converts the address from a request into an offset into `data`.
+    fn block_address(&self, request_address: usize, address_bits: u32) -> usize {
+        let address = request_address & ((1 << address_bits) - 1);
+        address * BLOCK_SIZE % self.data.len()
+    }
+
+    fn reset_request(&mut self) {
+        self.request = 0;
+        self.request_length = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(eeprom: &mut Eeprom, value: u128, length: u32) {
+        for position in (0..length).rev() {
+            eeprom.write_bit((value >> position & 1) as u16);
+        }
+    }
+
+    fn read(eeprom: &mut Eeprom) -> u64 {
+        let mut block = 0;
+        for index in 0..READ_LENGTH {
+            let bit = eeprom.read_bit() as u64;
+            if index < 4 {
+                assert_eq!(bit, 0);
+            }
+            block = block << 1 | bit;
+        }
+        block
+    }
+
+    #[test]
+    fn detect_size() {
+        let mut eeprom = Eeprom::new(None);
+        eeprom.detect_size(68);
+        assert_eq!(eeprom.size, None);
+
+        eeprom.detect_size(81);
+        assert_eq!(eeprom.size, Some(EepromSize::Large));
+        assert_eq!(eeprom.data.len(), 8 * 1024);
+
+        let mut eeprom = Eeprom::new(None);
+        eeprom.detect_size(9);
+        assert_eq!(eeprom.size, Some(EepromSize::Small));
+        assert_eq!(eeprom.data.len(), 512);
+    }
+
+    #[test]
+    fn write_then_read_small() {
+        let mut eeprom = Eeprom::new(Some(EepromSize::Small));
+
+        // 10, address 0b000011, data, stop bit
+        let data = 0x0123_4567_89ab_cdef_u128;
+        send(&mut eeprom, 0b10 << 71 | 0b000011 << 65 | data << 1, 73);
+        assert_eq!(
+            &eeprom.data[24..32],
+            &[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
+        );
+
+        // Ready after the write
+        assert_eq!(eeprom.read_bit(), 1);
+
+        // 11, address 0b000011, stop bit
+        send(&mut eeprom, 0b11 << 7 | 0b000011 << 1, 9);
+        assert_eq!(read(&mut eeprom), 0x0123_4567_89ab_cdef);
+        assert_eq!(eeprom.read_bit(), 1);
+    }
+
+    #[test]
+    fn write_then_read_large() {
+        let mut eeprom = Eeprom::new(Some(EepromSize::Large));
+
+        let data = 0xfeed_face_dead_beef_u128;
+        send(&mut eeprom, 0b10 << 79 | 0x3ff << 65 | data << 1, 81);
+        assert_eq!(eeprom.data[0x3ff * 8], 0xfe);
+
+        send(&mut eeprom, 0b11 << 15 | 0x3ff << 1, 17);
+        assert_eq!(read(&mut eeprom), 0xfeed_face_dead_beef);
+    }
+}
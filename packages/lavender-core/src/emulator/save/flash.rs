@@ -0,0 +1,312 @@
+/// Flash chips are accessed 64KB at a time. Larger chips have to switch
+/// between banks to reach the rest of their memory.
+pub const BANK_SIZE: usize = 64 * 1024;
+/// The smallest area of memory that can be erased at once.
+pub const SECTOR_SIZE: usize = 4 * 1024;
+/// Atmel chips don't support byte programming, and instead write an entire
+/// page at a time.
+pub const ATMEL_PAGE_SIZE: usize = 128;
+
+/// The addresses that command sequences are written to.
+const COMMAND_ADDRESS_1: usize = 0x5555;
+const COMMAND_ADDRESS_2: usize = 0x2aaa;
+
+/// All of the flash chips that have been found in cartridges. Games will read
+/// back the manufacturer and device IDs to figure out how to talk to them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlashChip {
+    /// Panasonic MN63F805MNP (64KB)
+    Panasonic,
+    /// Atmel AT29LV512 (64KB)
+    Atmel,
+    /// Macronix MX29L512 (64KB)
+    Macronix64K,
+    /// Macronix MX29L010 (128KB)
+    Macronix128K,
+    /// Sanyo LE26FV10N1TS (128KB)
+    Sanyo,
+}
+
+impl FlashChip {
+    pub fn manufacturer_id(self) -> u8 {
+        match self {
+            FlashChip::Panasonic => 0x32,
+            FlashChip::Atmel => 0x1f,
+            FlashChip::Macronix64K | FlashChip::Macronix128K => 0xc2,
+            FlashChip::Sanyo => 0x62,
+        }
+    }
+
+    pub fn device_id(self) -> u8 {
+        match self {
+            FlashChip::Panasonic => 0x1b,
+            FlashChip::Atmel => 0x3d,
+            FlashChip::Macronix64K => 0x1c,
+            FlashChip::Macronix128K => 0x09,
+            FlashChip::Sanyo => 0x13,
+        }
+    }
+
+    /// The total amount of memory on the chip, in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            FlashChip::Panasonic | FlashChip::Atmel | FlashChip::Macronix64K => BANK_SIZE,
+            FlashChip::Macronix128K | FlashChip::Sanyo => 2 * BANK_SIZE,
+        }
+    }
+}
+
+/// How far along we are in receiving the unlock sequence which comes before
+/// every command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CommandSequence {
+    /// Waiting for 0xaa to be written to 0x5555
+    Ready,
+    /// Waiting for 0x55 to be written to 0x2aaa
+    Unlocking,
+    /// Waiting for the command itself
+    Unlocked,
+}
+
+/// Commands which affect the next write, rather than taking effect immediately.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PendingOperation {
+    /// The next write will program a single byte.
+    Program,
+    /// The next writes will program a page, starting at the given offset.
+    /// Only used by Atmel chips.
+    ProgramPage { start: usize, written: usize },
+    /// The next write to 0x0000 selects the bank.
+    SwitchBank,
+}
+
+pub struct Flash {
+    pub chip: FlashChip,
+    /// The contents of the entire chip. Erased memory reads as 0xff.
+    pub data: Vec<u8>,
+    /// The 64KB bank that is currently mapped into the save region.
+    bank: usize,
+    sequence: CommandSequence,
+    /// While enabled, reading the first two bytes returns the chip IDs.
+    id_mode: bool,
+    /// Set by the 0x80 command, and required before either erase command.
+    erase_enabled: bool,
+    pending: Option<PendingOperation>,
+}
+
+impl Flash {
+    pub fn new(chip: FlashChip) -> Self {
+        Self {
+            chip,
+            data: vec![0xff; chip.size()],
+            bank: 0,
+            sequence: CommandSequence::Ready,
+            id_mode: false,
+            erase_enabled: false,
+            pending: None,
+        }
+    }
+
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        let offset = offset % BANK_SIZE;
+
+        if self.id_mode {
+            match offset {
+                0 => return self.chip.manufacturer_id(),
+                1 => return self.chip.device_id(),
+                _ => (),
+            }
+        }
+
+        self.data[self.bank * BANK_SIZE + offset]
+    }
+
+    pub fn write_byte(&mut self, offset: usize, value: u8) {
+        let offset = offset % BANK_SIZE;
+
+        if let Some(operation) = self.pending.take() {
+            self.perform(operation, offset, value);
+            return;
+        }
+
+        self.sequence = match (self.sequence, offset, value) {
+            (CommandSequence::Ready, COMMAND_ADDRESS_1, 0xaa) => CommandSequence::Unlocking,
+            (CommandSequence::Unlocking, COMMAND_ADDRESS_2, 0x55) => CommandSequence::Unlocked,
+            (CommandSequence::Unlocked, COMMAND_ADDRESS_1, command) => {
+                self.command(command);
+                CommandSequence::Ready
+            }
+            (CommandSequence::Unlocked, _, 0x30) if self.erase_enabled => {
+                self.erase_sector(offset);
+                CommandSequence::Ready
+            }
+            // Some games leave ID mode without sending the unlock sequence first.
+            (CommandSequence::Ready, _, 0xf0) => {
+                self.id_mode = false;
+                CommandSequence::Ready
+            }
+            _ => CommandSequence::Ready,
+        };
+    }
+
+    fn command(&mut self, command: u8) {
+        match command {
+            // Enter ID mode
+            0x90 => self.id_mode = true,
+            // Exit ID mode
+            0xf0 => self.id_mode = false,
+            // Prepare to erase
+            0x80 => self.erase_enabled = true,
+            // Erase the entire chip
+            0x10 if self.erase_enabled => {
+                for byte in self.data.iter_mut() {
+                    *byte = 0xff;
+                }
+                self.erase_enabled = false;
+            }
+            // Prepare to write
+            0xa0 => {
+                self.pending = Some(match self.chip {
+                    FlashChip::Atmel => PendingOperation::ProgramPage {
+                        start: 0,
+                        written: 0,
+                    },
+                    _ => PendingOperation::Program,
+                })
+            }
+            // Prepare to switch banks
+            0xb0 if self.chip.size() > BANK_SIZE => {
+                self.pending = Some(PendingOperation::SwitchBank)
+            }
+            _ => (),
+        }
+    }
+
+    fn perform(&mut self, operation: PendingOperation, offset: usize, value: u8) {
+        match operation {
+            PendingOperation::Program => {
+                self.data[self.bank * BANK_SIZE + offset] = value;
+            }
+            PendingOperation::ProgramPage { start, written } => {
+                // The first write of a page determines which page is being written.
+                let start = if written == 0 {
+                    offset & !(ATMEL_PAGE_SIZE - 1)
+                } else {
+                    start
+                };
+
+                if offset & !(ATMEL_PAGE_SIZE - 1) == start {
+                    self.data[self.bank * BANK_SIZE + offset] = value;
+                }
+
+                if written + 1 < ATMEL_PAGE_SIZE {
+                    self.pending = Some(PendingOperation::ProgramPage {
+                        start,
+                        written: written + 1,
+                    });
+                }
+            }
+            PendingOperation::SwitchBank => {
+                if offset == 0 {
+                    self.bank = value as usize & 1;
+                }
+            }
+        }
+    }
+
+    fn erase_sector(&mut self, offset: usize) {
+        let start = self.bank * BANK_SIZE + (offset & !(SECTOR_SIZE - 1));
+
+        for byte in self.data[start..start + SECTOR_SIZE].iter_mut() {
+            *byte = 0xff;
+        }
+        self.erase_enabled = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_command(flash: &mut Flash, command: u8) {
+        flash.write_byte(COMMAND_ADDRESS_1, 0xaa);
+        flash.write_byte(COMMAND_ADDRESS_2, 0x55);
+        flash.write_byte(COMMAND_ADDRESS_1, command);
+    }
+
+    #[test]
+    fn id_mode() {
+        let mut flash = Flash::new(FlashChip::Macronix128K);
+
+        send_command(&mut flash, 0x90);
+        assert_eq!(flash.read_byte(0), 0xc2);
+        assert_eq!(flash.read_byte(1), 0x09);
+
+        send_command(&mut flash, 0xf0);
+        assert_eq!(flash.read_byte(0), 0xff);
+        assert_eq!(flash.read_byte(1), 0xff);
+    }
+
+    #[test]
+    fn program_and_erase() {
+        let mut flash = Flash::new(FlashChip::Panasonic);
+
+        // A write without the command sequence should be ignored
+        flash.write_byte(0x1234, 0x42);
+        assert_eq!(flash.read_byte(0x1234), 0xff);
+
+        send_command(&mut flash, 0xa0);
+        flash.write_byte(0x1234, 0x42);
+        assert_eq!(flash.read_byte(0x1234), 0x42);
+
+        send_command(&mut flash, 0xa0);
+        flash.write_byte(0x2000, 0x24);
+
+        // Erase the sector containing 0x1234
+        send_command(&mut flash, 0x80);
+        flash.write_byte(COMMAND_ADDRESS_1, 0xaa);
+        flash.write_byte(COMMAND_ADDRESS_2, 0x55);
+        flash.write_byte(0x1000, 0x30);
+        assert_eq!(flash.read_byte(0x1234), 0xff);
+        assert_eq!(flash.read_byte(0x2000), 0x24);
+
+        // Erase everything
+        send_command(&mut flash, 0x80);
+        send_command(&mut flash, 0x10);
+        assert_eq!(flash.read_byte(0x2000), 0xff);
+    }
+
+    #[test]
+    fn bank_switching() {
+        let mut flash = Flash::new(FlashChip::Sanyo);
+
+        send_command(&mut flash, 0xb0);
+        flash.write_byte(0, 1);
+        send_command(&mut flash, 0xa0);
+        flash.write_byte(0x10, 0x99);
+
+        assert_eq!(flash.data[BANK_SIZE + 0x10], 0x99);
+        assert_eq!(flash.data[0x10], 0xff);
+
+        send_command(&mut flash, 0xb0);
+        flash.write_byte(0, 0);
+        assert_eq!(flash.read_byte(0x10), 0xff);
+    }
+
+    #[test]
+    fn atmel_page_program() {
+        let mut flash = Flash::new(FlashChip::Atmel);
+
+        send_command(&mut flash, 0xa0);
+        for index in 0..ATMEL_PAGE_SIZE {
+            flash.write_byte(0x180 + index, index as u8);
+        }
+
+        assert_eq!(flash.read_byte(0x180), 0);
+        assert_eq!(flash.read_byte(0x1ff), 0x7f);
+
+        // The page is finished, so this shouldn't be written
+        flash.write_byte(0x200, 0x12);
+        assert_eq!(flash.read_byte(0x200), 0xff);
+    }
+}
@@ -0,0 +1,228 @@
+//! Cartridges keep their save data in one of a few different kinds of chips,
+//! and each of them is talked to in its own special way. Everything in this
+//! module is accessed through the save region of memory, starting at
+//! 0x0e00_0000.
+
+/// Serial EEPROM, which is accessed one bit at a time near the top of the ROM
+/// address space.
+pub mod eeprom;
+/// Flash memory, which is controlled by writing command sequences to it.
+pub mod flash;
+
+pub use eeprom::{Eeprom, EepromSize};
+pub use flash::{Flash, FlashChip};
+
+/// Standard battery backed SRAM is 32KB, and is mirrored across the entire
+/// save region.
+pub const SRAM_SIZE: usize = 32 * 1024;
+
+/// All of the kinds of save memory that we know how to emulate.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SaveType {
+    /// Battery backed static RAM, which can be read and written directly.
+    Sram,
+    /// 64KB of flash memory, which fits in the save region all at once.
+    Flash64K,
+    /// 128KB of flash memory, split into two banks of 64KB.
+    Flash128K,
+    /// EEPROM of an unknown size, which will be detected from the first
+    /// request that the game sends.
+    Eeprom,
+    /// 512 bytes of EEPROM.
+    Eeprom512,
+    /// 8KB of EEPROM.
+    Eeprom8K,
+}
+
+impl SaveType {
+    /// Games are built with Nintendo's save libraries, which leave their
+    /// version string in the ROM. Looking for them is the most reliable way we
+    /// have of guessing which kind of save memory a cartridge contains.
+    const LIBRARY_STRINGS: [(&'static [u8], SaveType); 6] = [
+        (b"EEPROM_V", SaveType::Eeprom),
+        (b"SRAM_F_V", SaveType::Sram),
+        (b"SRAM_V", SaveType::Sram),
+        (b"FLASH1M_V", SaveType::Flash128K),
+        (b"FLASH512_V", SaveType::Flash64K),
+        (b"FLASH_V", SaveType::Flash64K),
+    ];
+
+    /// Scans a ROM for the library strings that identify its save type.
+    /// Returns `None` if the game doesn't appear to save at all.
+    pub fn detect(rom: &[u8]) -> Option<SaveType> {
+        Self::LIBRARY_STRINGS
+            .iter()
+            .find(|(string, _)| contains_library_string(rom, string))
+            .map(|(_, save_type)| *save_type)
+    }
+
+    /// Parses the names used by the frontend to override the detected type.
+    pub fn from_name(name: &str) -> Option<SaveType> {
+        match name.to_lowercase().as_str() {
+            "sram" => Some(SaveType::Sram),
+            "flash64k" | "flash512" => Some(SaveType::Flash64K),
+            "flash128k" | "flash1m" => Some(SaveType::Flash128K),
+            "eeprom" => Some(SaveType::Eeprom),
+            "eeprom512" => Some(SaveType::Eeprom512),
+            "eeprom8k" => Some(SaveType::Eeprom8K),
+            _ => None,
+        }
+    }
+}
+
+/// Checks whether a ROM was built with a library that left the given version
+/// string behind. The strings are always word aligned, so there's no need to
+/// check every byte.
+pub fn contains_library_string(rom: &[u8], string: &[u8]) -> bool {
+    if string.len() > rom.len() {
+        return false;
+    }
+
+    (0..=rom.len() - string.len())
+        .step_by(4)
+        .any(|index| rom[index..].starts_with(string))
+}
+
+/// The save memory of the currently inserted cartridge.
+pub enum Save {
+    Sram(Vec<u8>),
+    Flash(Flash),
+    Eeprom(Eeprom),
+}
+
+impl Save {
+    pub fn new(save_type: SaveType) -> Self {
+        match save_type {
+            SaveType::Sram => Save::Sram(vec![0xff; SRAM_SIZE]),
+            SaveType::Flash64K => Save::Flash(Flash::new(FlashChip::Panasonic)),
+            SaveType::Flash128K => Save::Flash(Flash::new(FlashChip::Sanyo)),
+            SaveType::Eeprom => Save::Eeprom(Eeprom::new(None)),
+            SaveType::Eeprom512 => Save::Eeprom(Eeprom::new(Some(EepromSize::Small))),
+            SaveType::Eeprom8K => Save::Eeprom(Eeprom::new(Some(EepromSize::Large))),
+        }
+    }
+
+    pub fn save_type(&self) -> SaveType {
+        match self {
+            Save::Sram(_) => SaveType::Sram,
+            Save::Flash(flash) if flash.chip.size() > flash::BANK_SIZE => SaveType::Flash128K,
+            Save::Flash(_) => SaveType::Flash64K,
+            Save::Eeprom(eeprom) => match eeprom.size {
+                None => SaveType::Eeprom,
+                Some(EepromSize::Small) => SaveType::Eeprom512,
+                Some(EepromSize::Large) => SaveType::Eeprom8K,
+            },
+        }
+    }
+
+    /// Reads a byte from the save memory. The offset is relative to the
+    /// beginning of the save region.
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        match self {
+            Save::Sram(data) => data[offset % data.len()],
+            Save::Flash(flash) => flash.read_byte(offset),
+            // Nothing is connected to the save region on EEPROM cartridges.
+            Save::Eeprom(_) => 0xff,
+        }
+    }
+
+    /// Writes a byte to the save memory. The offset is relative to the
+    /// beginning of the save region.
+    pub fn write_byte(&mut self, offset: usize, value: u8) {
+        match self {
+            Save::Sram(data) => {
+                let length = data.len();
+                data[offset % length] = value;
+            }
+            Save::Flash(flash) => flash.write_byte(offset, value),
+            Save::Eeprom(_) => (),
+        }
+    }
+
+    /// The raw contents of the save memory, in the same layout as a standard
+    /// `.sav` file.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Save::Sram(data) => data,
+            Save::Flash(flash) => &flash.data,
+            Save::Eeprom(eeprom) => &eeprom.data,
+        }
+    }
+
+    /// Replaces the contents of the save memory with those of a `.sav` file.
+    /// Files which are too short are padded with erased bytes, and anything
+    /// past the size of the chip is ignored.
+    pub fn load(&mut self, save: &[u8]) {
+        let data = match self {
+            Save::Sram(data) => data,
+            Save::Flash(flash) => &mut flash.data,
+            Save::Eeprom(eeprom) => {
+                // A save file is the only way to know the size before the game
+                // sends a request.
+                if eeprom.size.is_none() {
+                    eeprom.size = match save.len() {
+                        512 => Some(EepromSize::Small),
+                        8192 => Some(EepromSize::Large),
+                        _ => None,
+                    };
+                    eeprom
+                        .data
+                        .resize(eeprom.size.unwrap_or(EepromSize::Large).bytes(), 0xff);
+                }
+                &mut eeprom.data
+            }
+        };
+
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = save.get(index).copied().unwrap_or(0xff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sram_is_mirrored() {
+        let mut save = Save::new(SaveType::Sram);
+
+        save.write_byte(0x10, 0xab);
+        assert_eq!(save.read_byte(0x10), 0xab);
+        assert_eq!(save.read_byte(SRAM_SIZE + 0x10), 0xab);
+    }
+
+    #[test]
+    fn detect_save_type() {
+        let mut rom = vec![0; 0x400];
+        assert_eq!(SaveType::detect(&rom), None);
+
+        rom[0x200..0x20c].copy_from_slice(b"FLASH1M_V103");
+        assert_eq!(SaveType::detect(&rom), Some(SaveType::Flash128K));
+
+        let mut rom = vec![0; 0x400];
+        rom[0x104..0x10e].copy_from_slice(b"EEPROM_V12");
+        assert_eq!(SaveType::detect(&rom), Some(SaveType::Eeprom));
+
+        // Strings that aren't word aligned are ignored
+        let mut rom = vec![0; 0x400];
+        rom[0x101..0x109].copy_from_slice(b"SRAM_V11");
+        assert_eq!(SaveType::detect(&rom), None);
+
+        // Right at the end of the ROM
+        let mut rom = vec![0; 0x400];
+        rom[0x3f8..0x400].copy_from_slice(b"SRAM_V11");
+        assert_eq!(SaveType::detect(&rom), Some(SaveType::Sram));
+        assert!(!contains_library_string(b"SRAM", b"SRAM_V"));
+    }
+
+    #[test]
+    fn load_and_export() {
+        let mut save = Save::new(SaveType::Flash64K);
+
+        save.load(&[1, 2, 3]);
+        assert_eq!(save.data().len(), 64 * 1024);
+        assert_eq!(&save.data()[0..4], &[1, 2, 3, 0xff]);
+        assert_eq!(save.save_type(), SaveType::Flash64K);
+    }
+}
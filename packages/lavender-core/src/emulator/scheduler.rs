@@ -0,0 +1,175 @@
+use super::{dma, frontend, serial, timer, video, Emulator};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Something that the hardware does at a particular time, rather than in
+/// response to the CPU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    /// The display has finished drawing the visible part of a line.
+    HBlank,
+    /// The display has finished a whole line, and moves on to the next one.
+    HBlankEnd,
+    /// One of the four timers has overflowed.
+    TimerOverflow(usize),
+    /// A DMA channel has finished starting up, and begins its transfer.
+    DmaStart(usize),
+    /// The sound hardware is ready for the next sample.
+    AudioSample,
+    /// The serial port has finished sending or receiving.
+    SerialComplete,
+}
+
+/// A change to the schedule that was asked for by a write to an IO register.
+/// Memory can't reach the scheduler, so these are collected there and passed
+/// on to the scheduler once the instruction has finished.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Schedules an event to happen after the given number of cycles.
+    Schedule(Event, u64),
+    /// Removes every pending occurrence of an event.
+    Cancel(Event),
+}
+
+/// Keeps track of the time, in CPU cycles since the emulator started, and of
+/// the events that are waiting to happen. The CPU moves time forward as it
+/// runs instructions, and everything else only needs to do any work when one
+/// of its events comes up.
+#[derive(Default)]
+pub struct Scheduler {
+    now: u64,
+    /// Ordered by time, and then by the order they were scheduled in, so that
+    /// events at the same time always run in a predictable order.
+    events: BinaryHeap<Reverse<(u64, u64, Event)>>,
+    scheduled: u64,
+}
+
+impl Scheduler {
+    /// The current time, in cycles.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Schedules an event to happen after the given number of cycles.
+    pub fn schedule(&mut self, event: Event, delay: u64) {
+        self.schedule_at(event, self.now + delay);
+    }
+
+    /// Schedules an event to happen at a specific time. Events that should
+    /// happen regularly can use the time that the previous one was due to
+    /// schedule the next, so that they don't drift.
+    pub fn schedule_at(&mut self, event: Event, time: u64) {
+        self.events.push(Reverse((time, self.scheduled, event)));
+        self.scheduled += 1;
+    }
+
+    /// Removes every pending occurrence of an event.
+    pub fn cancel(&mut self, event: Event) {
+        let events = std::mem::take(&mut self.events);
+        self.events = events
+            .into_iter()
+            .filter(|Reverse((_, _, pending))| *pending != event)
+            .collect();
+    }
+
+    /// The time of the next event, if anything is scheduled.
+    pub fn next_event_time(&self) -> Option<u64> {
+        self.events.peek().map(|Reverse((time, _, _))| *time)
+    }
+
+    /// Moves time forward by the given number of cycles.
+    pub fn advance(&mut self, cycles: u32) {
+        self.now += cycles as u64;
+    }
+
+    /// Jumps straight to the time of the next event. Used when the CPU is
+    /// halted, and there's nothing to do until something happens.
+    pub fn skip_to_next_event(&mut self) {
+        if let Some(time) = self.next_event_time() {
+            self.now = self.now.max(time);
+        }
+    }
+
+    /// Removes the next event that is due, and returns it along with the time
+    /// that it was due at.
+    pub fn pop_due(&mut self) -> Option<(Event, u64)> {
+        match self.events.peek() {
+            Some(Reverse((time, _, _))) if *time <= self.now => {
+                let Reverse((time, _, event)) = self.events.pop()?;
+                Some((event, time))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Passes on everything that IO registers have asked for since the last time.
+/// This needs to happen before time moves on past the write that asked.
+pub fn schedule_requests(emulator: &mut Emulator) {
+    let now = emulator.scheduler.now();
+    schedule_requests_from(emulator, now);
+}
+
+fn schedule_requests_from(emulator: &mut Emulator, time: u64) {
+    for request in emulator.memory.requests.drain(..) {
+        match request {
+            Request::Schedule(event, delay) => emulator.scheduler.schedule_at(event, time + delay),
+            Request::Cancel(event) => emulator.scheduler.cancel(event),
+        }
+    }
+}
+
+/// Runs every event that is due.
+pub fn run_due_events(emulator: &mut Emulator) {
+    while let Some((event, time)) = emulator.scheduler.pop_due() {
+        emulator.memory.now = time;
+        match event {
+            Event::HBlank => video::hblank(emulator, time),
+            Event::HBlankEnd => video::hblank_end(emulator, time),
+            Event::TimerOverflow(timer) => timer::overflow(emulator, timer, time),
+            Event::DmaStart(channel) => dma::transfer(&mut emulator.memory, channel),
+            Event::AudioSample => frontend::sample(emulator, time),
+            Event::SerialComplete => serial::complete(&mut emulator.memory),
+        }
+
+        // Anything the event wrote to is timed from when it happened
+        schedule_requests_from(emulator, time);
+    }
+
+    emulator.memory.now = emulator.scheduler.now();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_in_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(Event::AudioSample, 20);
+        scheduler.schedule(Event::DmaStart(1), 10);
+        scheduler.schedule(Event::DmaStart(0), 10);
+
+        scheduler.advance(9);
+        assert_eq!(scheduler.pop_due(), None);
+
+        // Events at the same time come out in the order they were scheduled
+        scheduler.advance(16);
+        assert_eq!(scheduler.pop_due(), Some((Event::DmaStart(1), 10)));
+        assert_eq!(scheduler.pop_due(), Some((Event::DmaStart(0), 10)));
+        assert_eq!(scheduler.pop_due(), Some((Event::AudioSample, 20)));
+        assert_eq!(scheduler.pop_due(), None);
+    }
+
+    #[test]
+    fn cancel_and_skip() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(Event::SerialComplete, 100);
+        scheduler.schedule(Event::TimerOverflow(2), 500);
+        scheduler.cancel(Event::SerialComplete);
+
+        scheduler.skip_to_next_event();
+        assert_eq!(scheduler.now(), 500);
+        assert_eq!(scheduler.pop_due(), Some((Event::TimerOverflow(2), 500)));
+    }
+}
@@ -0,0 +1,95 @@
+use super::interrupt::{self, Interrupt};
+use super::memory::Memory;
+use super::scheduler::{Event, Request};
+
+/// The serial control register, relative to the beginning of IO memory.
+pub const SERIAL_CONTROL: usize = 0x128;
+
+/// Bits of the low byte of SIOCNT. Setting the start bit begins a transfer,
+/// and it stays set until the transfer is complete.
+const INTERNAL_CLOCK: u8 = 1 << 0;
+const FAST_CLOCK: u8 = 1 << 1;
+const START: u8 = 1 << 7;
+/// Bits of the high byte. Bit 12 of SIOCNT sends 32 bits instead of 8, and
+/// bit 14 enables an interrupt when a transfer completes.
+const WIDE_TRANSFER: u8 = 1 << 4;
+const COMPLETE_INTERRUPT: u8 = 1 << 6;
+
+/// Called whenever either byte of SIOCNT is written, with the value that
+/// byte had before the write. Starting a transfer schedules its completion.
+///
+/// There's never anything connected to the other end of the cable, so
+/// transfers clocked by the other side never finish, and every mode is timed
+/// as if it were normal mode.
+pub fn control_written(memory: &mut Memory, offset: usize, previous: u8) {
+    let starting =
+        offset == SERIAL_CONTROL && memory.io[offset] & START != 0 && previous & START == 0;
+
+    // Half word writes set the start bit before the width, so a transfer that
+    // was started by this instruction is timed again after the high byte
+    let pending = memory
+        .requests
+        .iter()
+        .position(|request| matches!(request, Request::Schedule(Event::SerialComplete, _)));
+    if let Some(index) = pending {
+        memory.requests.remove(index);
+    } else if !starting {
+        return;
+    }
+
+    let control = memory.io[SERIAL_CONTROL];
+    if control & START == 0 || control & INTERNAL_CLOCK == 0 {
+        return;
+    }
+
+    // The internal clock runs at either 256KHz or 2MHz
+    let bits = if memory.io[SERIAL_CONTROL + 1] & WIDE_TRANSFER != 0 {
+        32
+    } else {
+        8
+    };
+    let cycles_per_bit = if control & FAST_CLOCK != 0 { 8 } else { 64 };
+    memory.requests.push(Request::Schedule(
+        Event::SerialComplete,
+        bits * cycles_per_bit,
+    ));
+}
+
+/// Finishes the transfer that is in progress.
+pub fn complete(memory: &mut Memory) {
+    memory.io[SERIAL_CONTROL] &= !START;
+    if memory.io[SERIAL_CONTROL + 1] & COMPLETE_INTERRUPT != 0 {
+        interrupt::request(memory, Interrupt::Serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::interrupt::INTERRUPT_REQUEST_FLAGS;
+    use crate::emulator::memory::IO_START;
+
+    const ADDRESS: u32 = (IO_START + SERIAL_CONTROL) as u32;
+
+    #[test]
+    fn transfers_take_time() {
+        let mut memory = Memory::init();
+
+        // 32 bits at 2MHz, with an interrupt
+        memory.write_half_word(ADDRESS, 0x5083);
+        assert_eq!(
+            memory.requests,
+            [Request::Schedule(Event::SerialComplete, 32 * 8)]
+        );
+        assert_eq!(memory.read_half_word(ADDRESS) & 0x80, 0x80);
+
+        complete(&mut memory);
+        assert_eq!(memory.read_half_word(ADDRESS) & 0x80, 0);
+        assert_eq!(memory.io[INTERRUPT_REQUEST_FLAGS] & 1 << 7, 1 << 7);
+
+        // Nothing ever clocks an external transfer
+        memory.requests.clear();
+        memory.write_half_word(ADDRESS, 0x0080);
+        assert!(memory.requests.is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+/// The value the sensor reports on each axis while the console is level.
+pub const TILT_CENTER: i32 = 0x3a0;
+
+/// Offsets of the tilt sensor's registers from the start of the save region.
+const START_SAMPLE: usize = 0x8000;
+const FINISH_SAMPLE: usize = 0x8100;
+const X_LOW: usize = 0x8200;
+const X_HIGH: usize = 0x8300;
+const Y_LOW: usize = 0x8400;
+const Y_HIGH: usize = 0x8500;
+
+/// Bit 7 of the high byte of the X axis is set once a sample is ready.
+const SAMPLE_READY: u8 = 0x80;
+
+/// The two axis accelerometer from Yoshi Topsy-Turvy and Koro Koro Puzzle.
+/// Unlike the other cartridge hardware it isn't connected to the GPIO port,
+/// and instead has registers in the save region. These games save to EEPROM,
+/// so there's nothing else there to get in the way.
+#[derive(Default)]
+pub struct TiltSensor {
+    /// How far the console is tilted on each axis, relative to being level.
+    /// The sensor reports 12-bit values, so the useful range is about ±0x3a0.
+    pub x: i16,
+    pub y: i16,
+    /// Set by the first half of the sampling sequence.
+    sampling: bool,
+    sample_x: u16,
+    sample_y: u16,
+}
+
+impl TiltSensor {
+    /// Checks if an offset into the save region belongs to the sensor.
+    pub fn handles(offset: usize) -> bool {
+        (START_SAMPLE..Y_HIGH + 0x100).contains(&offset)
+    }
+
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        match offset & !0xff {
+            X_LOW => self.sample_x as u8,
+            X_HIGH => (self.sample_x >> 8) as u8 & 0xf | SAMPLE_READY,
+            Y_LOW => self.sample_y as u8,
+            Y_HIGH => (self.sample_y >> 8) as u8 & 0xf,
+            _ => 0xff,
+        }
+    }
+
+    /// Games take a sample by writing 0x55 and then 0xaa to the two control
+    /// registers.
+    pub fn write_byte(&mut self, offset: usize, value: u8) {
+        match (offset & !0xff, value) {
+            (START_SAMPLE, 0x55) => self.sampling = true,
+            (FINISH_SAMPLE, 0xaa) if self.sampling => {
+                self.sampling = false;
+                self.sample_x = axis(self.x);
+                self.sample_y = axis(self.y);
+            }
+            _ => self.sampling = false,
+        }
+    }
+}
+
+fn axis(value: i16) -> u16 {
+    (TILT_CENTER + value as i32).clamp(0, 0xfff) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_sample() {
+        let mut tilt = TiltSensor {
+            x: -0x20,
+            y: 0x100,
+            ..TiltSensor::default()
+        };
+
+        tilt.write_byte(START_SAMPLE, 0x55);
+        tilt.write_byte(FINISH_SAMPLE, 0xaa);
+
+        assert_eq!(tilt.read_byte(X_LOW), 0x80);
+        assert_eq!(tilt.read_byte(X_HIGH), 0x83);
+        assert_eq!(tilt.read_byte(Y_LOW), 0xa0);
+        assert_eq!(tilt.read_byte(Y_HIGH), 0x04);
+
+        // Without the first write, nothing changes
+        tilt.x = 0;
+        tilt.write_byte(FINISH_SAMPLE, 0xaa);
+        assert_eq!(tilt.read_byte(X_LOW), 0x80);
+    }
+}
@@ -0,0 +1,297 @@
+use super::interrupt::{self, Interrupt};
+use super::memory::{Memory, IO_START};
+use super::scheduler::{Event, Request};
+use super::Emulator;
+
+/// Offsets of the registers for each of the four timers, relative to the
+/// beginning of IO memory. Each timer has a counter followed by a control
+/// register. Writes to the counter set the value it's reloaded with instead.
+pub const TIMER_REGISTERS: [usize; 4] = [0x100, 0x104, 0x108, 0x10c];
+/// The offset of the control register within each timer's registers.
+pub const TIMER_CONTROL: usize = 0x02;
+
+const TIMER_INTERRUPTS: [Interrupt; 4] = [
+    Interrupt::Timer0,
+    Interrupt::Timer1,
+    Interrupt::Timer2,
+    Interrupt::Timer3,
+];
+
+/// Bits of the control register. The bottom two pick how many cycles each
+/// tick of the counter takes.
+const PRESCALERS: [u64; 4] = [1, 64, 256, 1024];
+const COUNT_UP: u8 = 1 << 2;
+const OVERFLOW_INTERRUPT: u8 = 1 << 6;
+const ENABLE: u8 = 1 << 7;
+
+/// Where a timer that is running on its own started counting up from, and
+/// when. This is enough to work out its counter whenever it's read.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Start {
+    pub time: u64,
+    pub counter: u16,
+    /// How many cycles each tick of the counter takes.
+    pub prescaler: u64,
+}
+
+/// Called whenever a byte of a timer's registers is written, with the value
+/// that byte had before the write. Starting a timer reloads its counter and
+/// schedules its first overflow, and stopping it cancels the overflow and
+/// leaves the counter where it got to.
+pub fn register_written(memory: &mut Memory, offset: usize, previous: u8) {
+    let timer = match TIMER_REGISTERS
+        .iter()
+        .position(|base| (*base..*base + 4).contains(&offset))
+    {
+        Some(timer) => timer,
+        None => return,
+    };
+
+    match offset - TIMER_REGISTERS[timer] {
+        0 | 1 => {
+            let shift = (offset - TIMER_REGISTERS[timer]) * 8;
+            let value = memory.io[offset] as u16;
+            memory.timer_reloads[timer] =
+                memory.timer_reloads[timer] & !(0xff << shift) | value << shift;
+            memory.io[offset] = previous;
+        }
+        TIMER_CONTROL => {
+            let control = memory.io[offset];
+            if control & ENABLE != 0 && previous & ENABLE == 0 {
+                let reload = memory.timer_reloads[timer];
+                write_counter(memory, timer, reload);
+                start(memory, timer, memory.now, reload, control);
+                if !counts_up(timer, control) {
+                    let delay = period(reload, control);
+                    memory
+                        .requests
+                        .push(Request::Schedule(Event::TimerOverflow(timer), delay));
+                }
+            } else if control & ENABLE == 0 && previous & ENABLE != 0 {
+                if !counts_up(timer, previous) {
+                    let counter = running_counter(memory, timer);
+                    write_counter(memory, timer, counter);
+                }
+                memory
+                    .requests
+                    .push(Request::Cancel(Event::TimerOverflow(timer)));
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Reloads a timer that has overflowed, and schedules its next overflow. The
+/// timer after it counts up by one if it's cascading from this one.
+pub fn overflow(emulator: &mut Emulator, timer: usize, time: u64) {
+    let memory = &mut emulator.memory;
+    let control = memory.io[TIMER_REGISTERS[timer] + TIMER_CONTROL];
+    let reload = memory.timer_reloads[timer];
+    write_counter(memory, timer, reload);
+    start(memory, timer, time, reload, control);
+
+    if control & OVERFLOW_INTERRUPT != 0 {
+        interrupt::request(memory, TIMER_INTERRUPTS[timer]);
+    }
+    if !counts_up(timer, control) {
+        emulator
+            .scheduler
+            .schedule_at(Event::TimerOverflow(timer), time + period(reload, control));
+    }
+
+    let next = timer + 1;
+    if next < TIMER_REGISTERS.len() {
+        let control = emulator.memory.io[TIMER_REGISTERS[next] + TIMER_CONTROL];
+        if control & ENABLE != 0 && counts_up(next, control) {
+            let counter = read_counter(&emulator.memory, next).wrapping_add(1);
+            write_counter(&mut emulator.memory, next, counter);
+            if counter == 0 {
+                overflow(emulator, next, time);
+            }
+        }
+    }
+}
+
+/// Brings a timer's counter up to date before it's read, if the timer is
+/// running on its own. The counter is only written when the timer reloads or
+/// counts up, so otherwise it's worked out from how long the timer has been
+/// running for.
+pub fn update_counter(memory: &mut Memory, offset: usize) {
+    let timer = match TIMER_REGISTERS
+        .iter()
+        .position(|base| (*base..*base + 2).contains(&offset))
+    {
+        Some(timer) => timer,
+        None => return,
+    };
+
+    let control = memory.io[TIMER_REGISTERS[timer] + TIMER_CONTROL];
+    if control & ENABLE != 0 && !counts_up(timer, control) {
+        let counter = running_counter(memory, timer);
+        write_counter(memory, timer, counter);
+    }
+}
+
+fn start(memory: &mut Memory, timer: usize, time: u64, counter: u16, control: u8) {
+    memory.timer_starts[timer] = Start {
+        time,
+        counter,
+        prescaler: PRESCALERS[(control & 0b11) as usize],
+    };
+}
+
+/// The counter of a timer that is running on its own, as of the start of the
+/// current instruction.
+fn running_counter(memory: &Memory, timer: usize) -> u16 {
+    let start = memory.timer_starts[timer];
+    let ticks = (memory.now - start.time) / start.prescaler;
+    start.counter.wrapping_add(ticks as u16)
+}
+
+/// Timers in count up mode only tick when the one before them overflows. The
+/// first timer has nothing before it, so it always runs on its own.
+fn counts_up(timer: usize, control: u8) -> bool {
+    timer != 0 && control & COUNT_UP != 0
+}
+
+/// How many cycles it takes a timer to count from its reload value up to the
+/// overflow.
+fn period(reload: u16, control: u8) -> u64 {
+    (0x1_0000 - reload as u64) * PRESCALERS[(control & 0b11) as usize]
+}
+
+fn read_counter(memory: &Memory, timer: usize) -> u16 {
+    let offset = TIMER_REGISTERS[timer];
+    u16::from_le_bytes([memory.io[offset], memory.io[offset + 1]])
+}
+
+fn write_counter(memory: &mut Memory, timer: usize, value: u16) {
+    let offset = TIMER_REGISTERS[timer];
+    memory.io[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The address of a timer register, for use in tests and debugging tools.
+pub fn register_address(timer: usize, offset: usize) -> u32 {
+    (IO_START + TIMER_REGISTERS[timer] + offset) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::interrupt::INTERRUPT_REQUEST_FLAGS;
+    use crate::emulator::scheduler::{run_due_events, schedule_requests};
+
+    fn run_for(emulator: &mut Emulator, cycles: u64) {
+        schedule_requests(emulator);
+        emulator.scheduler.advance(cycles as u32);
+        run_due_events(emulator);
+    }
+
+    fn requested(emulator: &Emulator, interrupt: Interrupt) -> bool {
+        let flags = u16::from_le_bytes([
+            emulator.memory.io[INTERRUPT_REQUEST_FLAGS],
+            emulator.memory.io[INTERRUPT_REQUEST_FLAGS + 1],
+        ]);
+        flags & 1 << interrupt as u16 != 0
+    }
+
+    #[test]
+    fn overflows_and_reloads() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+        memory.write_half_word(register_address(1, 0), 0xff00);
+        // Enabled, every 64 cycles, with an interrupt
+        memory.write_half_word(register_address(1, TIMER_CONTROL), 0xc1);
+        assert_eq!(memory.read_half_word(register_address(1, 0)), 0xff00);
+
+        run_for(&mut emulator, 0x100 * 64 - 1);
+        assert!(!requested(&emulator, Interrupt::Timer1));
+
+        run_for(&mut emulator, 1);
+        assert!(requested(&emulator, Interrupt::Timer1));
+
+        // And again, on its own
+        emulator.memory.io[INTERRUPT_REQUEST_FLAGS] = 0;
+        run_for(&mut emulator, 0x100 * 64);
+        assert!(requested(&emulator, Interrupt::Timer1));
+
+        // Until it's stopped
+        emulator.memory.io[INTERRUPT_REQUEST_FLAGS] = 0;
+        emulator
+            .memory
+            .write_half_word(register_address(1, TIMER_CONTROL), 0x41);
+        run_for(&mut emulator, 0x100 * 64);
+        assert!(!requested(&emulator, Interrupt::Timer1));
+    }
+
+    #[test]
+    fn counts_while_running() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+        memory.write_half_word(register_address(2, 0), 0x1000);
+        // Enabled, every 256 cycles
+        memory.write_half_word(register_address(2, TIMER_CONTROL), 0x82);
+
+        run_for(&mut emulator, 256 * 3 + 10);
+        let memory = &mut emulator.memory;
+        assert_eq!(memory.read_half_word(register_address(2, 0)), 0x1003);
+        assert_eq!(memory.read_word(register_address(2, 0)), 0x0082_1003);
+
+        // A new reload value doesn't change the count until the next reload
+        memory.write_half_word(register_address(2, 0), 0x2000);
+        run_for(&mut emulator, 256);
+        let memory = &mut emulator.memory;
+        assert_eq!(memory.read_byte(register_address(2, 0)), 0x04);
+
+        // Stopping the timer leaves the counter where it got to
+        memory.write_half_word(register_address(2, TIMER_CONTROL), 0x02);
+        run_for(&mut emulator, 256 * 4);
+        assert_eq!(
+            emulator.memory.read_half_word(register_address(2, 0)),
+            0x1004
+        );
+    }
+
+    #[test]
+    fn reloads_only_take_effect_on_start() {
+        let mut memory = Memory::init();
+        memory.write_half_word(register_address(0, 0), 0x1234);
+        assert_eq!(memory.read_half_word(register_address(0, 0)), 0);
+
+        memory.write_half_word(register_address(0, TIMER_CONTROL), 0x80);
+        assert_eq!(memory.read_half_word(register_address(0, 0)), 0x1234);
+        assert_eq!(
+            memory.requests,
+            [Request::Schedule(
+                Event::TimerOverflow(0),
+                0x1_0000 - 0x1234
+            )]
+        );
+    }
+
+    #[test]
+    fn cascade() {
+        let mut emulator = Emulator::new();
+        let memory = &mut emulator.memory;
+        memory.write_half_word(register_address(0, 0), 0xfff0);
+        memory.write_half_word(register_address(1, 0), 0xfffe);
+        // Timer 1 counts up with an interrupt, and then timer 0 starts
+        memory.write_half_word(register_address(1, TIMER_CONTROL), 0xc4);
+        memory.write_half_word(register_address(0, TIMER_CONTROL), 0x80);
+
+        run_for(&mut emulator, 0x10);
+        assert_eq!(
+            emulator.memory.read_half_word(register_address(1, 0)),
+            0xffff
+        );
+        assert!(!requested(&emulator, Interrupt::Timer1));
+
+        run_for(&mut emulator, 0x10);
+        assert_eq!(
+            emulator.memory.read_half_word(register_address(1, 0)),
+            0xfffe
+        );
+        assert!(requested(&emulator, Interrupt::Timer1));
+        assert!(!requested(&emulator, Interrupt::Timer0));
+    }
+}
@@ -0,0 +1,312 @@
+/// WAITCNT, relative to the beginning of IO memory. It sets how long the
+/// cartridge takes to respond, and turns the prefetch buffer on and off.
+pub const WAIT_CONTROL: usize = 0x204;
+
+/// Bit 14 of WAITCNT enables the prefetch buffer.
+const PREFETCH_ENABLE: u16 = 1 << 14;
+/// The prefetch buffer holds up to eight half words.
+const PREFETCH_CAPACITY: u32 = 8;
+
+/// Wait states for non-sequential accesses to the cartridge, picked by two bits
+/// of WAITCNT. SRAM uses the same table.
+const NON_SEQUENTIAL_WAIT: [u32; 4] = [4, 3, 2, 8];
+/// Wait states for sequential accesses to each of the three ROM mirrors,
+/// picked by one bit of WAITCNT each.
+const SEQUENTIAL_WAIT: [[u32; 2]; 3] = [[2, 1], [4, 1], [8, 1]];
+
+/// How much data is moved in a single access.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Width {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+impl Width {
+    fn size(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::HalfWord => 2,
+            Width::Word => 4,
+        }
+    }
+}
+
+/// The cartridge has a small buffer that it fills with the half words after
+/// the last instruction that was fetched from ROM, whenever the CPU isn't
+/// using the cartridge. If the CPU fetches the next instruction and it's
+/// already in the buffer, it only takes a single cycle.
+#[derive(Default)]
+struct Prefetch {
+    /// Cleared when the CPU uses the cartridge for something else, until the
+    /// next instruction is fetched from ROM.
+    active: bool,
+    /// The address of the first half word in the buffer, which is the next one
+    /// that the CPU should fetch.
+    head: u32,
+    /// How many half words have finished loading.
+    count: u32,
+    /// How many cycles have been spent loading the half word after them.
+    progress: u32,
+}
+
+/// Keeps track of the extra cycles that memory accesses take. Most of the
+/// memory inside the GBA can be accessed in a single cycle, but the cartridge
+/// and the external work RAM take longer, and 32-bit accesses to memory on a
+/// 16-bit bus take two accesses. These extra cycles are called wait states.
+#[derive(Default)]
+pub struct Timing {
+    /// A copy of WAITCNT.
+    control: u16,
+    /// Wait states since they were last taken.
+    cycles: u32,
+    /// Accesses to the address straight after the previous one are
+    /// sequential, which is faster on the cartridge. Instruction fetches and
+    /// data accesses are tracked separately.
+    next_fetch: Option<u32>,
+    next_data: Option<u32>,
+    prefetch: Prefetch,
+}
+
+impl Timing {
+    pub fn set_control(&mut self, value: u16) {
+        self.control = value;
+        if !self.prefetch_enabled() {
+            self.prefetch = Prefetch::default();
+        }
+    }
+
+    fn prefetch_enabled(&self) -> bool {
+        self.control & PREFETCH_ENABLE != 0
+    }
+
+    /// Returns the wait states that have built up since the last call, and
+    /// starts tracking the next instruction.
+    pub fn take_cycles(&mut self) -> u32 {
+        self.next_data = None;
+        std::mem::take(&mut self.cycles)
+    }
+
+    /// The CPU is busy with something that doesn't use memory, so the prefetch
+    /// buffer gets a chance to load more of the ROM.
+    pub fn idle(&mut self, cycles: u32) {
+        if !self.prefetch.active {
+            return;
+        }
+
+        let per_half_word = self.wait_states(self.prefetch_address(), Width::HalfWord, true) + 1;
+        let prefetch = &mut self.prefetch;
+        prefetch.progress += cycles;
+        while prefetch.progress >= per_half_word && prefetch.count < PREFETCH_CAPACITY {
+            prefetch.progress -= per_half_word;
+            prefetch.count += 1;
+        }
+        if prefetch.count == PREFETCH_CAPACITY {
+            prefetch.progress = 0;
+        }
+    }
+
+    /// The address of the half word that the prefetch buffer is loading.
+    fn prefetch_address(&self) -> u32 {
+        self.prefetch.head + self.prefetch.count * 2
+    }
+
+    /// The CPU has fetched an instruction.
+    pub fn fetch(&mut self, address: u32, width: Width) {
+        let sequential = self.next_fetch == Some(address);
+        self.next_fetch = Some(address + width.size());
+
+        if !is_game_pak_address(address) {
+            let wait = self.wait_states(address, width, sequential);
+            self.cycles += wait;
+            self.idle(wait + 1);
+            return;
+        }
+
+        if !self.prefetch_enabled() {
+            self.cycles += self.wait_states(address, width, sequential);
+            return;
+        }
+
+        let half_words = width.size() / 2;
+        if self.prefetch.active && self.prefetch.head == address {
+            // Already in the buffer, or on its way
+            for _ in 0..half_words {
+                if self.prefetch.count > 0 {
+                    // Reading from the buffer doesn't need the cartridge, so
+                    // it can keep loading at the same time
+                    self.prefetch.count -= 1;
+                    self.prefetch.head += 2;
+                    self.idle(1);
+                } else {
+                    let per_half_word = self.wait_states(address, Width::HalfWord, true) + 1;
+                    self.cycles += per_half_word - self.prefetch.progress - 1;
+                    self.prefetch.progress = 0;
+                    self.prefetch.head += 2;
+                }
+            }
+        } else {
+            // The buffer had the wrong instructions in it, so start again
+            // from here
+            self.cycles += self.wait_states(address, width, sequential);
+            self.prefetch = Prefetch {
+                active: true,
+                head: address + width.size(),
+                count: 0,
+                progress: 0,
+            };
+        }
+    }
+
+    /// The CPU has read or written data.
+    pub fn data_access(&mut self, address: u32, width: Width) {
+        let sequential = self.next_data == Some(address);
+        self.next_data = Some(address + width.size());
+
+        let wait = self.wait_states(address, width, sequential);
+        self.cycles += wait;
+
+        if is_game_pak_address(address) {
+            // The cartridge can't load instructions while it's busy with
+            // this, so the buffer is thrown away and the next fetch starts
+            // from scratch
+            self.prefetch = Prefetch::default();
+            self.next_fetch = None;
+        } else {
+            self.idle(wait + 1);
+        }
+    }
+
+    /// The number of extra cycles that an access takes, on top of the one
+    /// cycle that every access takes.
+    pub fn wait_states(&self, address: u32, width: Width, sequential: bool) -> u32 {
+        let control = self.control as u32;
+
+        match address >> 24 {
+            // External work RAM has two wait states, and a 16-bit bus
+            0x02 if width == Width::Word => 5,
+            0x02 => 2,
+            // The palette and VRAM are on a 16-bit bus
+            0x05 | 0x06 if width == Width::Word => 1,
+            region @ 0x08..=0x0d => {
+                let state = (region - 0x08) as usize / 2;
+                let non_sequential = NON_SEQUENTIAL_WAIT[(control >> (2 + 3 * state) & 3) as usize];
+                let sequential_wait =
+                    SEQUENTIAL_WAIT[state][(control >> (4 + 3 * state) & 1) as usize];
+
+                // The cartridge counts addresses in 128KB blocks, so
+                // crossing into a new one is always non-sequential
+                let first = if sequential && address & 0x1_ffff != 0 {
+                    sequential_wait
+                } else {
+                    non_sequential
+                };
+
+                // The cartridge only has a 16-bit bus, so a word takes a
+                // second, sequential access
+                if width == Width::Word {
+                    first + sequential_wait + 1
+                } else {
+                    first
+                }
+            }
+            // SRAM only has an 8-bit bus, but only bytes can be read from it
+            0x0e | 0x0f => NON_SEQUENTIAL_WAIT[(control & 3) as usize],
+            _ => 0,
+        }
+    }
+}
+
+/// Everything from the start of the ROM up to the end of the save memory is
+/// connected to the cartridge.
+fn is_game_pak_address(address: u32) -> bool {
+    (0x08..=0x0f).contains(&(address >> 24))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: u32 = 0x0800_0000;
+
+    #[test]
+    fn wait_states() {
+        let mut timing = Timing::default();
+        assert_eq!(timing.wait_states(0x0300_0000, Width::Word, false), 0);
+        assert_eq!(timing.wait_states(0x0200_0000, Width::Word, false), 5);
+        assert_eq!(timing.wait_states(ROM, Width::HalfWord, false), 4);
+        assert_eq!(timing.wait_states(ROM + 2, Width::HalfWord, true), 2);
+        assert_eq!(timing.wait_states(ROM + 4, Width::Word, true), 5);
+
+        // The settings most games use: 3/1 for the first ROM mirror
+        timing.set_control(0x4317);
+        assert_eq!(timing.wait_states(ROM, Width::Word, false), 5);
+        assert_eq!(timing.wait_states(ROM + 4, Width::Word, true), 3);
+        assert_eq!(timing.wait_states(ROM + 0x2_0000, Width::HalfWord, true), 3);
+        assert_eq!(timing.wait_states(0x0e00_0000, Width::Byte, false), 8);
+    }
+
+    #[test]
+    fn sequential_fetches_without_prefetch() {
+        let mut timing = Timing::default();
+        timing.fetch(ROM, Width::HalfWord);
+        timing.fetch(ROM + 2, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 4 + 2);
+
+        // Jumping somewhere else is non-sequential again
+        timing.fetch(ROM + 0x100, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 4);
+    }
+
+    #[test]
+    fn prefetch_fills_while_idle() {
+        let mut timing = Timing::default();
+        timing.set_control(PREFETCH_ENABLE);
+
+        timing.fetch(ROM, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 4);
+
+        // Each half word takes three cycles to load, so after three cycles
+        // there's one ready. Reading it gives the next one a cycle to load, so
+        // fetching that only waits for one more.
+        timing.idle(3);
+        timing.fetch(ROM + 2, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 0);
+        timing.fetch(ROM + 4, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 1);
+
+        // The buffer stops once it's full, but keeps loading while it's read
+        // from, so it takes a while to run out
+        timing.idle(100);
+        for offset in 0..12 {
+            timing.fetch(ROM + 6 + offset * 2, Width::HalfWord);
+        }
+        assert_eq!(timing.take_cycles(), 0);
+        timing.fetch(ROM + 30, Width::HalfWord);
+        assert_eq!(timing.take_cycles(), 2);
+    }
+
+    #[test]
+    fn prefetch_is_flushed() {
+        let mut timing = Timing::default();
+        timing.set_control(PREFETCH_ENABLE);
+        timing.fetch(ROM, Width::Word);
+        timing.idle(100);
+        timing.take_cycles();
+
+        // A branch throws away what was loaded
+        timing.fetch(ROM + 0x40, Width::Word);
+        assert_eq!(timing.take_cycles(), 4 + 2 + 1);
+
+        // So does reading data from the cartridge, even straight after
+        timing.idle(100);
+        timing.data_access(ROM + 0x1000, Width::HalfWord);
+        timing.fetch(ROM + 0x44, Width::Word);
+        assert_eq!(timing.take_cycles(), 4 + 4 + 2 + 1);
+
+        // But data from anywhere else gives the buffer time to fill
+        timing.data_access(0x0200_0000, Width::Word);
+        timing.fetch(ROM + 0x48, Width::Word);
+        assert_eq!(timing.take_cycles(), 5);
+    }
+}
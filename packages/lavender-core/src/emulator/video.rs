@@ -0,0 +1,120 @@
+use super::dma::{self, StartTiming};
+use super::frontend;
+use super::interrupt::{self, Interrupt};
+use super::scheduler::{Event, Scheduler};
+use super::Emulator;
+
+/// The display draws 240 pixels at four cycles each, and then waits in
+/// HBlank for the rest of the line.
+pub const HDRAW_CYCLES: u64 = 960;
+pub const HBLANK_CYCLES: u64 = 272;
+pub const LINE_CYCLES: u64 = HDRAW_CYCLES + HBLANK_CYCLES;
+
+/// There are 160 visible lines, followed by 68 lines of VBlank.
+pub const VISIBLE_LINES: u8 = 160;
+pub const TOTAL_LINES: u8 = 228;
+pub const FRAME_CYCLES: u64 = LINE_CYCLES * TOTAL_LINES as u64;
+
+/// IO registers that keep track of where the display is.
+pub const DISPLAY_STATUS: usize = 0x004;
+pub const VERTICAL_COUNT: usize = 0x006;
+
+/// Bits of DISPSTAT. The bottom three are flags, and the next three enable
+/// the interrupt for each of them. The top byte is the line that the
+/// VCounter flag is looking for.
+const VBLANK_FLAG: u8 = 1 << 0;
+const HBLANK_FLAG: u8 = 1 << 1;
+const VCOUNTER_FLAG: u8 = 1 << 2;
+const VBLANK_INTERRUPT: u8 = 1 << 3;
+const HBLANK_INTERRUPT: u8 = 1 << 4;
+const VCOUNTER_INTERRUPT: u8 = 1 << 5;
+
+/// Starts the display at the beginning of the first line.
+pub fn start(scheduler: &mut Scheduler) {
+    scheduler.schedule(Event::HBlank, HDRAW_CYCLES);
+}
+
+pub fn hblank(emulator: &mut Emulator, time: u64) {
+    let memory = &mut emulator.memory;
+    memory.io[DISPLAY_STATUS] |= HBLANK_FLAG;
+
+    if memory.io[DISPLAY_STATUS] & HBLANK_INTERRUPT != 0 {
+        interrupt::request(memory, Interrupt::HBlank);
+    }
+    if memory.io[VERTICAL_COUNT] < VISIBLE_LINES {
+        dma::trigger(memory, StartTiming::HBlank);
+    }
+
+    emulator
+        .scheduler
+        .schedule_at(Event::HBlankEnd, time + HBLANK_CYCLES);
+}
+
+pub fn hblank_end(emulator: &mut Emulator, time: u64) {
+    let memory = &mut emulator.memory;
+    let line = (memory.io[VERTICAL_COUNT] + 1) % TOTAL_LINES;
+    memory.io[VERTICAL_COUNT] = line;
+    memory.io[DISPLAY_STATUS] &= !HBLANK_FLAG;
+
+    if line == VISIBLE_LINES {
+        memory.io[DISPLAY_STATUS] |= VBLANK_FLAG;
+        if memory.io[DISPLAY_STATUS] & VBLANK_INTERRUPT != 0 {
+            interrupt::request(memory, Interrupt::VBlank);
+        }
+        dma::trigger(memory, StartTiming::VBlank);
+    } else if line == TOTAL_LINES - 1 {
+        // The flag is cleared a line early
+        memory.io[DISPLAY_STATUS] &= !VBLANK_FLAG;
+    }
+
+    if line == memory.io[DISPLAY_STATUS + 1] {
+        memory.io[DISPLAY_STATUS] |= VCOUNTER_FLAG;
+        if memory.io[DISPLAY_STATUS] & VCOUNTER_INTERRUPT != 0 {
+            interrupt::request(memory, Interrupt::VCounter);
+        }
+    } else {
+        memory.io[DISPLAY_STATUS] &= !VCOUNTER_FLAG;
+    }
+
+    if line == VISIBLE_LINES {
+        frontend::vblank(emulator);
+    }
+
+    emulator
+        .scheduler
+        .schedule_at(Event::HBlank, time + HDRAW_CYCLES);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::interrupt::INTERRUPT_REQUEST_FLAGS;
+    use crate::emulator::scheduler::run_due_events;
+
+    #[test]
+    fn vblank_once_per_frame() {
+        let mut emulator = Emulator::new();
+        emulator.memory.io[DISPLAY_STATUS] = VBLANK_INTERRUPT;
+        emulator.memory.io[DISPLAY_STATUS + 1] = 100;
+
+        emulator
+            .scheduler
+            .advance((LINE_CYCLES * VISIBLE_LINES as u64) as u32);
+        run_due_events(&mut emulator);
+
+        let memory = &emulator.memory;
+        assert_eq!(memory.io[VERTICAL_COUNT], VISIBLE_LINES);
+        assert_eq!(memory.io[DISPLAY_STATUS] & 0b111, VBLANK_FLAG);
+        assert_eq!(memory.io[INTERRUPT_REQUEST_FLAGS], 1);
+
+        // Back to the top, having passed line 100 along the way
+        emulator
+            .scheduler
+            .advance((FRAME_CYCLES - LINE_CYCLES * VISIBLE_LINES as u64 + 1000) as u32);
+        run_due_events(&mut emulator);
+
+        let memory = &emulator.memory;
+        assert_eq!(memory.io[VERTICAL_COUNT], 0);
+        assert_eq!(memory.io[DISPLAY_STATUS] & 0b111, HBLANK_FLAG);
+    }
+}
@@ -0,0 +1,483 @@
+//! Bindings that let the browser drive the emulator. Everything in here is
+//! only built with the `wasm` feature, so that native tools don't need to pull
+//! in wasm-bindgen just to run the emulator.
+
+use crate::emulator::frontend::{AudioSink, Frame, Frontend, InputSource, Sample, VideoSink};
+use crate::emulator::recompiler::{host, CompiledBlock, Instance, Runtime};
+use crate::emulator::{self, Emulator};
+use js_sys::{Function, Object, Reflect, Uint8Array, WebAssembly};
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+#[wasm_bindgen]
+extern "C" {
+    type Date;
+
+    #[wasm_bindgen(constructor)]
+    fn new() -> Date;
+    #[wasm_bindgen(method, js_name = getTime)]
+    fn get_time(this: &Date) -> f64;
+    #[wasm_bindgen(method, js_name = getTimezoneOffset)]
+    fn get_timezone_offset(this: &Date) -> f64;
+}
+
+/// The standard library can't tell the time inside of the browser, so the
+/// cartridge clock asks JavaScript instead. Unlike the system clock, this one
+/// knows about the user's timezone.
+struct BrowserClock;
+
+impl emulator::gpio::Clock for BrowserClock {
+    fn now(&self) -> i64 {
+        let date = Date::new();
+        let minutes_from_utc = date.get_timezone_offset() as i64;
+        (date.get_time() / 1000.0) as i64 - minutes_from_utc * 60
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+}
+
+/// Forwards diagnostics from the emulator to the browser console.
+struct ConsoleLogger;
+
+impl ::log::Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &::log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &::log::Record) {
+        log(&format!("[{}] {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+/// The details from the header of the inserted cartridge.
+#[wasm_bindgen]
+pub struct CartridgeInfo {
+    title: String,
+    game_code: String,
+    maker_code: String,
+    pub unit_code: u8,
+    pub version: u8,
+}
+
+#[wasm_bindgen]
+impl CartridgeInfo {
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn game_code(&self) -> String {
+        self.game_code.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn maker_code(&self) -> String {
+        self.maker_code.clone()
+    }
+}
+
+/// Holds on to about a second of sound, in case nothing is taking it.
+const MAX_BUFFERED_SAMPLES: usize = 32768;
+
+/// The browser draws each frame straight out of the emulator's memory once
+/// `step_frames` returns, so there's nothing to hand it. Sound waits here
+/// until JavaScript collects it, and buttons are set from JavaScript whenever
+/// they change.
+#[derive(Clone, Default)]
+struct Browser {
+    buttons: Rc<Cell<u16>>,
+    samples: Rc<RefCell<Vec<Sample>>>,
+}
+
+impl VideoSink for Browser {
+    fn frame(&mut self, _frame: &Frame) {}
+}
+
+impl AudioSink for Browser {
+    fn samples(&mut self, samples: &[Sample]) {
+        let mut buffer = self.samples.borrow_mut();
+        buffer.extend_from_slice(samples);
+
+        let excess = buffer.len().saturating_sub(MAX_BUFFERED_SAMPLES);
+        buffer.drain(..excess);
+    }
+}
+
+impl InputSource for Browser {
+    fn buttons(&mut self) -> u16 {
+        self.buttons.get()
+    }
+}
+
+/// Runs recompiled blocks with the browser's own WebAssembly engine. Every
+/// block shares the same memory and imports, and the imports find the
+/// emulator through a pointer that is only set while a block is running.
+struct BrowserRuntime {
+    emulator: Rc<Cell<*mut Emulator>>,
+    memory: WebAssembly::Memory,
+    imports: Object,
+    /// The imported functions only work for as long as these are around.
+    reads: Vec<Closure<dyn FnMut(u32) -> u32>>,
+    writes: Vec<Closure<dyn FnMut(u32, u32) -> u32>>,
+    timing: Vec<Closure<dyn FnMut(u32)>>,
+}
+
+impl BrowserRuntime {
+    fn new() -> Result<Self, JsValue> {
+        let descriptor = Object::new();
+        Reflect::set(&descriptor, &"initial".into(), &1.into())?;
+        let memory = WebAssembly::Memory::new(&descriptor)?;
+        let emulator: Rc<Cell<*mut Emulator>> = Rc::new(Cell::new(std::ptr::null_mut()));
+
+        // The pointer is only ever followed from inside `BrowserInstance::run`
+        let read = |function: fn(&mut Emulator, u32) -> u32| {
+            let emulator = Rc::clone(&emulator);
+            Closure::wrap(Box::new(move |address| {
+                function(unsafe { &mut *emulator.get() }, address)
+            }) as Box<dyn FnMut(u32) -> u32>)
+        };
+        let write = |function: fn(&mut Emulator, u32, u32) -> u32| {
+            let emulator = Rc::clone(&emulator);
+            Closure::wrap(Box::new(move |address, value| {
+                function(unsafe { &mut *emulator.get() }, address, value)
+            }) as Box<dyn FnMut(u32, u32) -> u32>)
+        };
+        let timing = |function: fn(&mut Emulator, u32)| {
+            let emulator = Rc::clone(&emulator);
+            Closure::wrap(
+                Box::new(move |value| function(unsafe { &mut *emulator.get() }, value))
+                    as Box<dyn FnMut(u32)>,
+            )
+        };
+        let reads = vec![read(host::read_byte), read(host::read_word)];
+        let writes = vec![write(host::write_byte), write(host::write_word)];
+        let timing = vec![timing(host::idle), timing(host::fetch)];
+
+        let env = Object::new();
+        Reflect::set(&env, &"memory".into(), &memory)?;
+        for (name, closure) in ["read_byte", "read_word"].iter().zip(&reads) {
+            Reflect::set(&env, &(*name).into(), closure.as_ref())?;
+        }
+        for (name, closure) in ["write_byte", "write_word"].iter().zip(&writes) {
+            Reflect::set(&env, &(*name).into(), closure.as_ref())?;
+        }
+        for (name, closure) in ["idle", "fetch"].iter().zip(&timing) {
+            Reflect::set(&env, &(*name).into(), closure.as_ref())?;
+        }
+        let imports = Object::new();
+        Reflect::set(&imports, &"env".into(), &env)?;
+
+        Ok(Self {
+            emulator,
+            memory,
+            imports,
+            reads,
+            writes,
+            timing,
+        })
+    }
+}
+
+impl Runtime for BrowserRuntime {
+    fn instantiate(&mut self, block: &CompiledBlock) -> Option<Rc<dyn Instance>> {
+        // Browsers only compile small modules synchronously on the main
+        // thread, so the bigger blocks might be turned down
+        let module = WebAssembly::Module::new(&Uint8Array::from(&block.module[..])).ok()?;
+        let instance = WebAssembly::Instance::new(&module, &self.imports).ok()?;
+        let run = Reflect::get(&instance.exports(), &"run".into())
+            .ok()?
+            .dyn_into::<Function>()
+            .ok()?;
+
+        Some(Rc::new(BrowserInstance {
+            emulator: Rc::clone(&self.emulator),
+            memory: self.memory.clone(),
+            run,
+        }))
+    }
+}
+
+struct BrowserInstance {
+    emulator: Rc<Cell<*mut Emulator>>,
+    memory: WebAssembly::Memory,
+    run: Function,
+}
+
+impl Instance for BrowserInstance {
+    fn run(&self, emulator: &mut Emulator, state: &mut [u8]) -> u32 {
+        let view = Uint8Array::new_with_byte_offset_and_length(
+            &self.memory.buffer(),
+            0,
+            state.len() as u32,
+        );
+        view.copy_from(state);
+
+        self.emulator.set(emulator);
+        let cycles = self.run.call0(&JsValue::UNDEFINED);
+        self.emulator.set(std::ptr::null_mut());
+
+        view.copy_to(state);
+        cycles
+            .ok()
+            .and_then(|cycles| cycles.as_f64())
+            .unwrap_or_default() as u32
+    }
+}
+
+/// A single emulated Game Boy Advance. Any number of them can run side by
+/// side, each with its own cartridge.
+#[wasm_bindgen]
+pub struct Lavender {
+    emulator: Emulator,
+    browser: Browser,
+}
+
+impl Default for Lavender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Lavender {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        // Debug diagnostics are noisy, so they're only shown in debug builds
+        if ::log::set_logger(&LOGGER).is_ok() {
+            ::log::set_max_level(if cfg!(debug_assertions) {
+                ::log::LevelFilter::Debug
+            } else {
+                ::log::LevelFilter::Warn
+            });
+        }
+
+        let mut emulator = Emulator::new();
+        let browser = Browser::default();
+        emulator.frontend = Frontend::new(
+            Box::new(browser.clone()),
+            Box::new(browser.clone()),
+            Box::new(browser.clone()),
+        );
+        emulator.cache.runtime = BrowserRuntime::new()
+            .ok()
+            .map(|runtime| Box::new(runtime) as Box<dyn Runtime>);
+
+        Self { emulator, browser }
+    }
+
+    /// Starts the emulation of the provided ROM. Throws if the ROM is rejected.
+    pub fn init_emulation(&mut self, rom: &[u8]) -> Result<(), JsValue> {
+        self.emulator.memory.gpio.clock = Box::new(BrowserClock);
+        self.emulator
+            .load_rom(&rom)
+            .map_err(|error| JsValue::from_str(&error.to_string()))?;
+
+        // Without a real BIOS there's no startup animation to run
+        if !self.emulator.bios.loaded {
+            self.emulator.direct_boot();
+        }
+
+        self.emulator.test();
+        Ok(())
+    }
+
+    /// Returns the header of the inserted cartridge, or `undefined` if there
+    /// isn't one yet.
+    pub fn get_cartridge_info(&self) -> Option<CartridgeInfo> {
+        self.emulator.header.as_ref().map(|header| CartridgeInfo {
+            title: header.title.clone(),
+            game_code: header.game_code.clone(),
+            maker_code: header.maker_code.clone(),
+            unit_code: header.unit_code,
+            version: header.version,
+        })
+    }
+
+    /// Returns a pointer to the beginning of the IO memory section.
+    pub fn get_io_address(&mut self) -> *mut u8 {
+        &mut self.emulator.memory.io[0] as *mut u8
+    }
+
+    /// Returns a pointer to the beginning of the palette memory section.
+    pub fn get_palette_address(&self) -> *const u8 {
+        &self.emulator.memory.palette[0] as *const u8
+    }
+
+    /// Returns a pointer to the beginning of the VRAM memory section.
+    pub fn get_vram_address(&self) -> *const u8 {
+        &self.emulator.memory.vram[0] as *const u8
+    }
+
+    /// Returns a pointer to the beginning of the object attribute memory section.
+    pub fn get_object_address(&self) -> *const u8 {
+        &self.emulator.memory.object[0] as *const u8
+    }
+
+    /// Called from JavaScript when it is time to produce the next frame.
+    pub fn step_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.emulator.step_frame();
+        }
+    }
+
+    /// Step forward by one instruction
+    pub fn step_instruction(&mut self) {
+        self.emulator.step_instruction();
+    }
+
+    /// Get the values of the current register bank
+    pub fn read_registers(&self) -> Vec<u32> {
+        use crate::emulator::cpu::RegisterNames::*;
+
+        vec![
+            self.emulator.cpu.get_register_value(r0),
+            self.emulator.cpu.get_register_value(r1),
+            self.emulator.cpu.get_register_value(r2),
+            self.emulator.cpu.get_register_value(r3),
+            self.emulator.cpu.get_register_value(r4),
+            self.emulator.cpu.get_register_value(r5),
+            self.emulator.cpu.get_register_value(r6),
+            self.emulator.cpu.get_register_value(r7),
+            self.emulator.cpu.get_register_value(r8),
+            self.emulator.cpu.get_register_value(r9),
+            self.emulator.cpu.get_register_value(r10),
+            self.emulator.cpu.get_register_value(r11),
+            self.emulator.cpu.get_register_value(r12),
+            self.emulator.cpu.get_register_value(r13),
+            self.emulator.cpu.get_register_value(r14),
+            self.emulator.cpu.get_register_value(r15),
+        ]
+    }
+
+    /// Get the status of the cpsr register.
+    pub fn read_cpsr(&self) -> u32 {
+        use crate::emulator::cpu::RegisterNames::cpsr;
+
+        self.emulator.cpu.get_register_value(cpsr)
+    }
+
+    /// Allows us to inspect parts of memory the way that the emulator sees them.
+    // todo: Needs to be robustified for Thumb instructions.
+    pub fn read_next_instruction(&self) -> u32 {
+        let address = self.emulator.cpu.next_instruction_address();
+
+        // Look at the memory directly, because the BIOS might be protected from
+        // reads until the instruction is actually fetched
+        match self
+            .emulator
+            .memory
+            .get_mapped_segment_and_real_offset(address)
+        {
+            Some((segment, offset)) if offset + 4 <= segment.len() => {
+                u32::from_le_bytes(segment[offset..offset + 4].try_into().unwrap())
+            }
+            _ => 0,
+        }
+    }
+
+    /// Overrides the kind of save memory that was detected for the ROM. Accepts
+    /// `sram`, `flash64k`, `flash128k`, `eeprom`, `eeprom512` or `eeprom8k`, and any
+    /// other value goes back to automatic detection.
+    pub fn set_save_type(&mut self, name: &str) {
+        use crate::emulator::save::SaveType;
+
+        self.emulator
+            .set_save_type_override(SaveType::from_name(name));
+    }
+
+    /// Replaces the built-in BIOS with a dump of the real one, which then boots
+    /// the game through the startup animation. Throws if the image isn't a known
+    /// BIOS, in which case the built-in BIOS keeps being used.
+    pub fn load_bios(&mut self, bios: &[u8]) -> Result<(), JsValue> {
+        self.emulator
+            .load_bios(bios)
+            .map_err(|error| JsValue::from_str(&error.to_string()))
+    }
+
+    /// Skips the startup animation of a loaded BIOS, and jumps straight into the
+    /// game.
+    pub fn direct_boot(&mut self) {
+        self.emulator.direct_boot();
+    }
+
+    /// Chooses whether BIOS calls are emulated directly, rather than by running
+    /// the BIOS itself.
+    pub fn set_hle_bios(&mut self, enabled: bool) {
+        self.emulator.bios.hle = enabled;
+    }
+
+    /// Sets how much light the solar sensor on Boktai cartridges sees, from 0 for
+    /// complete darkness up to 255 for direct sunlight.
+    pub fn set_solar_level(&mut self, level: u8) {
+        if let Some(solar) = &mut self.emulator.memory.gpio.solar {
+            solar.level = level;
+        }
+    }
+
+    /// Sets how quickly the console is being rotated, for cartridges with a gyro
+    /// sensor. Positive values are clockwise.
+    pub fn set_gyro_rate(&mut self, rate: i16) {
+        if let Some(gyro) = &mut self.emulator.memory.gpio.gyro {
+            gyro.rate = rate;
+        }
+    }
+
+    /// Sets how far the console is tilted on each axis, for cartridges with a
+    /// tilt sensor.
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        if let Some(tilt) = &mut self.emulator.memory.tilt {
+            tilt.x = x;
+            tilt.y = y;
+        }
+    }
+
+    /// Whether the cartridge's rumble motor is currently running.
+    pub fn is_rumbling(&self) -> bool {
+        matches!(&self.emulator.memory.gpio.rumble, Some(rumble) if rumble.active)
+    }
+
+    /// Returns the contents of the cartridge save memory, in the same format as a
+    /// `.sav` file.
+    pub fn export_save(&self) -> Vec<u8> {
+        self.emulator.memory.save.data().to_vec()
+    }
+
+    /// Replaces the contents of the cartridge save memory with those of a `.sav` file.
+    pub fn import_save(&mut self, save: &[u8]) {
+        self.emulator.memory.save.load(save);
+    }
+
+    /// Sets which buttons are held down, with one bit for each in the same
+    /// order as KEYINPUT. The game sees them from the next frame.
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.browser.buttons.set(buttons);
+    }
+
+    /// Takes all of the sound produced since the last call, as interleaved
+    /// left and right samples.
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.browser
+            .samples
+            .borrow_mut()
+            .drain(..)
+            .flat_map(|sample| [sample.left, sample.right])
+            .collect()
+    }
+}
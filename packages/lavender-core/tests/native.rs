@@ -0,0 +1,70 @@
+//! Runs the emulator as an ordinary Rust library, the way a native frontend
+//! or tool would, without going through any of the browser bindings.
+
+use lavender::emulator::cartridge::{header_complement, HEADER_SIZE};
+use lavender::emulator::cpu::RegisterNames;
+use lavender::emulator::frontend::{Headless, BUTTON_A};
+use lavender::emulator::Emulator;
+
+/// A cartridge that stores its number in r0, and then spins forever.
+fn rom(number: u8) -> Vec<u8> {
+    let mut rom = vec![0; HEADER_SIZE + 8];
+    // b 0x080000c0
+    rom[0..4].copy_from_slice(&0xea00_002e_u32.to_le_bytes());
+    rom[0xa0..0xa8].copy_from_slice(b"LAVENDER");
+    rom[0xb2] = 0x96;
+    rom[0xbd] = header_complement(&rom);
+
+    // mov r0, #number
+    // b .
+    let code = &mut rom[HEADER_SIZE..];
+    code[0..4].copy_from_slice(&(0xe3a0_0000 | number as u32).to_le_bytes());
+    code[4..8].copy_from_slice(&0xeaff_fffe_u32.to_le_bytes());
+    rom
+}
+
+fn booted(number: u8) -> Emulator {
+    let mut emulator = Emulator::new();
+    emulator.load_rom(&rom(number)).unwrap();
+    emulator.direct_boot();
+    emulator
+}
+
+#[test]
+fn run_a_cartridge() {
+    let mut emulator = booted(42);
+    assert_eq!(emulator.header.as_ref().unwrap().title, "LAVENDER");
+
+    emulator.step_frame();
+    assert_eq!(emulator.cpu.get_register_value(RegisterNames::r0), 42);
+}
+
+#[test]
+fn instances_are_independent() {
+    let mut first = booted(1);
+    let mut second = booted(2);
+
+    first.step_frame();
+    assert_eq!(first.cpu.get_register_value(RegisterNames::r0), 1);
+    assert_eq!(second.cpu.get_register_value(RegisterNames::r0), 0);
+
+    second.step_frame();
+    assert_eq!(first.cpu.get_register_value(RegisterNames::r0), 1);
+    assert_eq!(second.cpu.get_register_value(RegisterNames::r0), 2);
+}
+
+#[test]
+fn headless_frontend() {
+    let mut emulator = booted(0);
+    let headless = Headless::default();
+    emulator.frontend = headless.frontend();
+    headless.press(BUTTON_A);
+
+    emulator.step_frame();
+    emulator.step_frame();
+
+    let recording = headless.recording();
+    assert_eq!(recording.frames, 2);
+    assert!(!recording.samples.is_empty());
+    assert_eq!(emulator.memory.read_half_word(0x0400_0130), 0x3fe);
+}